@@ -14,11 +14,29 @@ pub async fn main() -> anyhow::Result<()> {
 #[clap(about = clap::crate_description!())]
 #[clap(version = clap::crate_version!())]
 #[clap(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub struct Daemon {}
+pub struct Daemon {
+    /// subcommand (default: run)
+    #[clap(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Cmd {
+    /// Run the daemon
+    Run,
+    DeadLetter(s3d::cli::dead_letter_cmd::DeadLetterCmd),
+}
 
 impl Daemon {
     pub async fn run(self) -> anyhow::Result<()> {
         log::debug!("{:?}", self);
+        match self.cmd {
+            None | Some(Cmd::Run) => Daemon::serve().await,
+            Some(Cmd::DeadLetter(cmd)) => cmd.run().await,
+        }
+    }
+
+    async fn serve() -> anyhow::Result<()> {
         #[cfg(feature = "fuse")]
         {
             s3d::fuse::Fuse::start_fuse_mount().await?;
@@ -0,0 +1,126 @@
+//! Byte ranges of the http `Range` header.
+//!
+//! S3 supports a single range per request (`bytes=first-last`, `bytes=first-`
+//! or `bytes=-suffix_length`). A header that does not parse is ignored and the whole
+//! object is returned, while a range that starts after the end of the object
+//! is answered with InvalidRange (416).
+
+use crate::s3::errors::S3Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// bytes=first-last (inclusive)
+    FromTo(u64, u64),
+    /// bytes=first-
+    From(u64),
+    /// bytes=-suffix_length
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// parse returns None for headers that should be ignored.
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        match (first.is_empty(), last.is_empty()) {
+            (true, false) => Some(ByteRange::Suffix(last.parse().ok()?)),
+            (false, true) => Some(ByteRange::From(first.parse().ok()?)),
+            (false, false) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                Some(ByteRange::FromTo(first, last))
+            }
+            (true, true) => None,
+        }
+    }
+
+    /// resolve returns the first and last (inclusive) offsets of the range
+    /// in an object of the given size.
+    pub fn resolve(&self, size: u64) -> Result<(u64, u64), S3Error> {
+        let resolved = match *self {
+            ByteRange::FromTo(first, last) => (first, last.min(size.saturating_sub(1))),
+            ByteRange::From(first) => (first, size.saturating_sub(1)),
+            ByteRange::Suffix(len) => (size.saturating_sub(len), size.saturating_sub(1)),
+        };
+        let empty = matches!(*self, ByteRange::Suffix(0));
+        if size == 0 || resolved.0 >= size || empty {
+            return Err(S3Error::new(
+                "InvalidRange",
+                "The requested range is not satisfiable",
+            ));
+        }
+        Ok(resolved)
+    }
+}
+
+/// content_range formats the `Content-Range` header of a partial response.
+pub fn content_range(first: u64, last: u64, size: u64) -> String {
+    format!("bytes {}-{}/{}", first, last, size)
+}
+
+/// parse_content_range returns the first and last offsets and the object size
+/// of a `Content-Range` header of a partial response.
+pub fn parse_content_range(header: &str) -> Option<(u64, u64, u64)> {
+    let (range, size) = header.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, size.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(ByteRange::parse("bytes=0-9"), Some(ByteRange::FromTo(0, 9)));
+        assert_eq!(
+            ByteRange::parse(" bytes= 5 - 5 "),
+            Some(ByteRange::FromTo(5, 5))
+        );
+        assert_eq!(ByteRange::parse("bytes=10-"), Some(ByteRange::From(10)));
+        assert_eq!(ByteRange::parse("bytes=-0"), Some(ByteRange::Suffix(0)));
+        assert_eq!(ByteRange::parse("bytes=-20"), Some(ByteRange::Suffix(20)));
+        assert_eq!(ByteRange::parse("bytes=9-0"), None);
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+        assert_eq!(ByteRange::parse("bytes=-"), None);
+        assert_eq!(ByteRange::parse("bytes=a-1"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+    }
+
+    #[test]
+    fn resolve() {
+        let resolve = |range: ByteRange, size| range.resolve(size).map_err(|err| err.code);
+        assert_eq!(resolve(ByteRange::FromTo(0, 9), 100), Ok((0, 9)));
+        assert_eq!(resolve(ByteRange::FromTo(90, 200), 100), Ok((90, 99)));
+        assert_eq!(
+            resolve(ByteRange::FromTo(100, 200), 100),
+            Err("InvalidRange")
+        );
+        assert_eq!(resolve(ByteRange::From(99), 100), Ok((99, 99)));
+        assert_eq!(resolve(ByteRange::From(100), 100), Err("InvalidRange"));
+        assert_eq!(resolve(ByteRange::Suffix(10), 100), Ok((90, 99)));
+        assert_eq!(resolve(ByteRange::Suffix(200), 100), Ok((0, 99)));
+        assert_eq!(resolve(ByteRange::Suffix(0), 100), Err("InvalidRange"));
+        assert_eq!(resolve(ByteRange::From(0), 0), Err("InvalidRange"));
+        assert_eq!(resolve(ByteRange::Suffix(10), 0), Err("InvalidRange"));
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(content_range(0, 9, 100), "bytes 0-9/100");
+        assert_eq!(parse_content_range("bytes 0-9/100"), Some((0, 9, 100)));
+        assert_eq!(
+            parse_content_range(&content_range(5, 5, 6)),
+            Some((5, 5, 6))
+        );
+        assert_eq!(parse_content_range("bytes 0-9/*"), None);
+        assert_eq!(parse_content_range("bytes */100"), None);
+        assert_eq!(parse_content_range("0-9/100"), None);
+    }
+}
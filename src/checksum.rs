@@ -0,0 +1,271 @@
+//! Checksums of object data.
+//!
+//! The data of every write is hashed while it streams to disk - MD5 for the ETag,
+//! plus any of the flexible checksum algorithms (CRC32, CRC32C, SHA1, SHA256)
+//! that the client sent a value for or asked for with `x-amz-sdk-checksum-algorithm`.
+
+use crate::s3::errors::S3Error;
+use md5::Md5;
+use s3d_smithy_codegen_server_s3::input::{PutObjectInput, UploadPartInput};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const CRC32: &str = "CRC32";
+pub const CRC32C: &str = "CRC32C";
+pub const SHA1: &str = "SHA1";
+pub const SHA256: &str = "SHA256";
+
+#[derive(Default)]
+pub struct ObjectHasher {
+    md5: Md5,
+    crc32: Option<crc32fast::Hasher>,
+    crc32c: Option<u32>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+    expected_md5: Option<String>,
+    expected: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Checksums {
+    pub md5: Vec<u8>,
+    /// Base64 encoded checksums by algorithm name
+    pub checksums: BTreeMap<String, String>,
+}
+
+impl ObjectHasher {
+    pub fn new(algorithms: &[&str]) -> ObjectHasher {
+        let mut hasher = ObjectHasher::default();
+        for algo in algorithms {
+            match *algo {
+                CRC32 => hasher.crc32 = Some(crc32fast::Hasher::new()),
+                CRC32C => hasher.crc32c = Some(0),
+                SHA1 => hasher.sha1 = Some(Sha1::new()),
+                SHA256 => hasher.sha256 = Some(Sha256::new()),
+                _ => warn!("ObjectHasher: unknown checksum algorithm {:?}", algo),
+            }
+        }
+        hasher
+    }
+
+    /// from_put_object_input prepares to verify the Content-MD5 and x-amz-checksum-* headers of the request.
+    pub fn from_put_object_input(i: &PutObjectInput) -> ObjectHasher {
+        ObjectHasher::expecting(
+            i.content_md5(),
+            [
+                (CRC32, i.checksum_crc32()),
+                (CRC32C, i.checksum_crc32_c()),
+                (SHA1, i.checksum_sha1()),
+                (SHA256, i.checksum_sha256()),
+            ],
+            i.checksum_algorithm().map(|a| a.as_str()),
+        )
+    }
+
+    /// from_upload_part_input is the same as `from_put_object_input` for a part.
+    pub fn from_upload_part_input(i: &UploadPartInput) -> ObjectHasher {
+        ObjectHasher::expecting(
+            i.content_md5(),
+            [
+                (CRC32, i.checksum_crc32()),
+                (CRC32C, i.checksum_crc32_c()),
+                (SHA1, i.checksum_sha1()),
+                (SHA256, i.checksum_sha256()),
+            ],
+            i.checksum_algorithm().map(|a| a.as_str()),
+        )
+    }
+
+    /// expecting prepares a hasher for the algorithms with expected values,
+    /// plus the requested algorithm whose value the client wants in the response.
+    pub fn expecting(
+        content_md5: Option<&str>,
+        checksums: [(&str, Option<&str>); 4],
+        requested: Option<&str>,
+    ) -> ObjectHasher {
+        let mut expected = BTreeMap::new();
+        for (algo, value) in checksums {
+            if let Some(value) = value {
+                expected.insert(algo.to_string(), value.to_string());
+            }
+        }
+        let requested = requested.map(|a| a.to_uppercase());
+        let mut algorithms: Vec<&str> = expected.keys().map(String::as_str).collect();
+        if let Some(algo) = requested.as_deref() {
+            algorithms.push(algo);
+        }
+        let mut hasher = ObjectHasher::new(&algorithms);
+        hasher.expected_md5 = content_md5.map(String::from);
+        hasher.expected = expected;
+        hasher
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        self.md5.update(buf);
+        if let Some(h) = self.crc32.as_mut() {
+            h.update(buf);
+        }
+        if let Some(crc) = self.crc32c.as_mut() {
+            *crc = crc32c::crc32c_append(*crc, buf);
+        }
+        if let Some(h) = self.sha1.as_mut() {
+            h.update(buf);
+        }
+        if let Some(h) = self.sha256.as_mut() {
+            h.update(buf);
+        }
+    }
+
+    /// finish returns the checksums of the data, or a BadDigest error
+    /// if the data does not match the expected values from the request.
+    pub fn finish(self) -> Result<Checksums, S3Error> {
+        let mut checksums = BTreeMap::new();
+        if let Some(h) = self.crc32 {
+            checksums.insert(CRC32.to_string(), base64::encode(h.finalize().to_be_bytes()));
+        }
+        if let Some(crc) = self.crc32c {
+            checksums.insert(CRC32C.to_string(), base64::encode(crc.to_be_bytes()));
+        }
+        if let Some(h) = self.sha1 {
+            checksums.insert(SHA1.to_string(), base64::encode(h.finalize()));
+        }
+        if let Some(h) = self.sha256 {
+            checksums.insert(SHA256.to_string(), base64::encode(h.finalize()));
+        }
+        let res = Checksums {
+            md5: self.md5.finalize().to_vec(),
+            checksums,
+        };
+        if let Some(expected_md5) = self.expected_md5 {
+            if expected_md5 != res.content_md5() {
+                return Err(S3Error::new(
+                    "BadDigest",
+                    "The Content-MD5 you specified did not match what we received.",
+                ));
+            }
+        }
+        for (algo, expected) in self.expected.iter() {
+            if res.checksums.get(algo) != Some(expected) {
+                return Err(S3Error::new(
+                    "BadDigest",
+                    format!(
+                        "The {} you specified did not match the calculated checksum.",
+                        algo
+                    ),
+                ));
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl Checksums {
+    /// content_md5 returns the base64 MD5 as used in the Content-MD5 header.
+    pub fn content_md5(&self) -> String {
+        base64::encode(&self.md5)
+    }
+
+    /// e_tag returns the quoted hex MD5 as used for the ETag of non-multipart objects.
+    pub fn e_tag(&self) -> String {
+        format!("\"{}\"", to_hex(&self.md5))
+    }
+}
+
+/// multipart_e_tag returns the ETag of a multipart object from the ETags of its parts,
+/// which is the MD5 of the concatenated part MD5s, followed by the number of parts.
+/// Returns None if any of the part ETags is not a quoted hex MD5.
+pub fn multipart_e_tag<S: AsRef<str>>(part_e_tags: &[S]) -> Option<String> {
+    let mut hasher = Md5::new();
+    for e_tag in part_e_tags {
+        hasher.update(from_hex(e_tag.as_ref().trim_matches('"'))?);
+    }
+    Some(format!(
+        "\"{}-{}\"",
+        to_hex(&hasher.finalize()),
+        part_e_tags.len()
+    ))
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"hello world";
+
+    #[test]
+    fn checksums() {
+        let mut hasher = ObjectHasher::new(&[CRC32, CRC32C, SHA1, SHA256]);
+        hasher.update(&DATA[..5]);
+        hasher.update(&DATA[5..]);
+        let res = hasher.finish().unwrap();
+        assert_eq!(res.e_tag(), "\"5eb63bbbe01eeed093cb22bb8f5acdc3\"");
+        assert_eq!(res.content_md5(), "XrY7u+Ae7tCTyyK7j1rNww==");
+        assert_eq!(res.checksums[CRC32], "DUoRhQ==");
+        assert_eq!(res.checksums[CRC32C], "yZRlqg==");
+        assert_eq!(res.checksums[SHA1], "Kq5sNclPz7QV2+lfQIuc6R7oRu0=");
+        assert_eq!(
+            res.checksums[SHA256],
+            "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+        );
+    }
+
+    #[test]
+    fn expected_checksums() {
+        let hash = |content_md5, crc32, requested| {
+            let mut hasher = ObjectHasher::expecting(
+                content_md5,
+                [(CRC32, crc32), (CRC32C, None), (SHA1, None), (SHA256, None)],
+                requested,
+            );
+            hasher.update(DATA);
+            hasher.finish()
+        };
+        let res = hash(
+            Some("XrY7u+Ae7tCTyyK7j1rNww=="),
+            Some("DUoRhQ=="),
+            Some("sha1"),
+        )
+        .unwrap();
+        assert_eq!(res.checksums.keys().collect::<Vec<_>>(), vec![CRC32, SHA1]);
+        let err = hash(Some("1B2M2Y8AsgTpgAmY7PhCfg=="), None, None).unwrap_err();
+        assert_eq!(err.code, "BadDigest");
+        let err = hash(None, Some("AAAAAA=="), None).unwrap_err();
+        assert_eq!(err.code, "BadDigest");
+    }
+
+    #[test]
+    fn multipart_e_tags() {
+        assert_eq!(
+            multipart_e_tag(&[
+                "\"0cc175b9c0f1b6a831c399e269772661\"",
+                "\"92eb5ffee6ae2fec3ad71c777531578f\"",
+            ]),
+            Some("\"96e024ba2074fe77e8e965ba43a704be-2\"".to_string())
+        );
+        assert_eq!(multipart_e_tag(&["\"not-md5\""]), None);
+        assert_eq!(multipart_e_tag(&["\"abc\""]), None);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(from_hex("007fFF"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::config;
+use crate::utils::parse_bucket_and_key;
+use crate::write_queue::dead_letter::DeadLetters;
+use crate::write_queue::lock_queue_dir;
+
+/// Inspect, retry or discard write queue dead letters
+#[derive(clap::Parser, Debug, Clone)]
+#[clap(aliases = &["dl"])]
+pub struct DeadLetterCmd {
+    /// subcommand
+    #[clap(subcommand)]
+    cmd: DeadLetterSubCmd,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum DeadLetterSubCmd {
+    /// List dead letters with their last error
+    List,
+    /// Move a dead letter back to the write queue (`bucket/key`)
+    Retry {
+        #[clap(name = "bucket/key")]
+        bucket_and_key: String,
+    },
+    /// Remove a dead letter for good (`bucket/key`)
+    Discard {
+        #[clap(name = "bucket/key")]
+        bucket_and_key: String,
+    },
+}
+
+impl DeadLetterCmd {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let dead_letters = DeadLetters::new(&config::S3D_WRITE_QUEUE_DIR);
+        match &self.cmd {
+            DeadLetterSubCmd::List => {
+                for it in dead_letters.list().await? {
+                    println!(
+                        "{:>12} {:>3} {}/{} {}",
+                        it.size,
+                        it.state.attempts,
+                        it.bucket,
+                        it.key,
+                        it.state.last_error.as_deref().unwrap_or("")
+                    );
+                }
+            }
+            DeadLetterSubCmd::Retry { bucket_and_key } => {
+                let (bucket, key) = parse_bucket_and_key(bucket_and_key)?;
+                let _lock = lock_for_change()?;
+                dead_letters.retry(&bucket, &key).await?;
+                info!("Retry {}/{}", bucket, key);
+            }
+            DeadLetterSubCmd::Discard { bucket_and_key } => {
+                let (bucket, key) = parse_bucket_and_key(bucket_and_key)?;
+                let _lock = lock_for_change()?;
+                dead_letters.discard(&bucket, &key).await?;
+                info!("Discarded {}/{}", bucket, key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// lock_for_change locks the write queue dir for a change of dead letters,
+/// which is not tracked by a running daemon, so it must be stopped first.
+fn lock_for_change() -> anyhow::Result<std::fs::File> {
+    lock_queue_dir(&config::S3D_WRITE_QUEUE_DIR)
+        .map_err(|err| anyhow::anyhow!("{} - stop s3d to change dead letters", err))
+}
@@ -1,5 +1,6 @@
 pub mod api_cmd;
 pub mod completion_cmd;
+pub mod dead_letter_cmd;
 pub mod get_cmd;
 pub mod list_cmd;
 pub mod put_cmd;
@@ -1,3 +1,6 @@
+use crate::filter::Filter;
+use crate::s3::policy::Policy;
+
 macro_rules! env_config {
     ($env:ident optional) => {
         lazy_static::lazy_static! {
@@ -19,6 +22,7 @@ macro_rules! env_config {
 env_config!(HOME required);
 env_config!(S3D_LOCAL_DIR default ".s3d");
 env_config!(S3D_ENDPOINT default "http://localhost:33333");
+env_config!(S3D_POLICY optional);
 
 env_config!(S3_ENDPOINT optional);
 env_config!(S3_ACCESS_KEY optional);
@@ -27,23 +31,156 @@ env_config!(S3_SECRET_KEY optional);
 env_config!(S3D_WRITE_QUEUE default "false");
 env_config!(S3D_WRITE_QUEUE_DIR default format!("{}/write_queue", *S3D_LOCAL_DIR));
 env_config!(S3D_WRITE_QUEUE_FILTER optional);
-env_config!(S3D_WRITE_QUEUE_MAX_SIZE optional);
-env_config!(S3D_WRITE_QUEUE_MAX_FILES optional);
-env_config!(S3D_WRITE_QUEUE_MAX_AGE optional);
+env_config!(S3D_WRITE_QUEUE_MAX_SIZE default "1073741824");
+env_config!(S3D_WRITE_QUEUE_MAX_FILES default "100");
+env_config!(S3D_WRITE_QUEUE_MAX_AGE default "3600");
+env_config!(S3D_WRITE_QUEUE_WORKERS default "4");
 
 env_config!(S3D_READ_CACHE default "false");
 env_config!(S3D_READ_CACHE_DIR default format!("{}/read_cache", *S3D_LOCAL_DIR));
 env_config!(S3D_READ_CACHE_FILTER optional);
-env_config!(S3D_READ_CACHE_MAX_SIZE optional);
-env_config!(S3D_READ_CACHE_MAX_FILES optional);
-env_config!(S3D_READ_CACHE_MAX_AGE optional);
+env_config!(S3D_READ_CACHE_MAX_SIZE default "1073741824");
+env_config!(S3D_READ_CACHE_MAX_FILES default "100");
+env_config!(S3D_READ_CACHE_MAX_AGE default "3600");
+env_config!(S3D_READ_CACHE_TTL default "60");
+env_config!(S3D_READ_CACHE_PIN optional);
+env_config!(S3D_READ_CACHE_PREFETCH optional);
+env_config!(S3D_READ_CACHE_PREFETCH_INTERVAL default "3600");
+
+env_config!(S3D_MD_CACHE default "false");
+env_config!(S3D_MD_CACHE_TTL default "10");
+env_config!(S3D_MD_CACHE_MAX_ENTRIES default "1000");
 
 env_config!(S3D_SYNC_FOLDER default "false");
 env_config!(S3D_SYNC_FOLDER_DIR default format!("{}/sync_folder", *S3D_LOCAL_DIR));
 env_config!(S3D_SYNC_FOLDER_FILTER optional);
-env_config!(S3D_SYNC_FOLDER_MAX_SIZE optional);
-env_config!(S3D_SYNC_FOLDER_MAX_FILES optional);
-env_config!(S3D_SYNC_FOLDER_MAX_AGE optional);
+env_config!(S3D_SYNC_FOLDER_MAX_SIZE default "1073741824");
+env_config!(S3D_SYNC_FOLDER_MAX_FILES default "100");
+env_config!(S3D_SYNC_FOLDER_MAX_AGE default "3600");
 
 env_config!(S3D_FUSE_MOUNT default "false");
 env_config!(S3D_FUSE_MOUNT_DIR default format!("{}/fuse_mount", *S3D_LOCAL_DIR));
+
+/// Limits of a local store (write queue, read cache, sync folder).
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum total size in bytes
+    pub max_size: u64,
+    /// Maximum number of files
+    pub max_files: u64,
+    /// Maximum age of files
+    pub max_age: std::time::Duration,
+}
+
+impl Limits {
+    pub fn parse(max_size: &str, max_files: &str, max_age: &str) -> anyhow::Result<Limits> {
+        Ok(Limits {
+            max_size: parse_value("max size", max_size)?,
+            max_files: parse_value("max files", max_files)?,
+            max_age: parse_secs("max age", max_age)?,
+        })
+    }
+
+    pub fn write_queue() -> anyhow::Result<Limits> {
+        Limits::parse(
+            &S3D_WRITE_QUEUE_MAX_SIZE,
+            &S3D_WRITE_QUEUE_MAX_FILES,
+            &S3D_WRITE_QUEUE_MAX_AGE,
+        )
+    }
+
+    pub fn read_cache() -> anyhow::Result<Limits> {
+        Limits::parse(
+            &S3D_READ_CACHE_MAX_SIZE,
+            &S3D_READ_CACHE_MAX_FILES,
+            &S3D_READ_CACHE_MAX_AGE,
+        )
+    }
+}
+
+/// parse_value parses a config value, and fails with its name and value.
+pub fn parse_value<T>(name: &str, val: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    val.trim()
+        .parse::<T>()
+        .map_err(|err| anyhow::anyhow!("Invalid {} {:?}: {}", name, val, err))
+}
+
+/// parse_secs parses a config value of a duration in seconds.
+pub fn parse_secs(name: &str, val: &str) -> anyhow::Result<std::time::Duration> {
+    parse_value(name, val).map(std::time::Duration::from_secs)
+}
+
+/// write_queue_workers returns the number of entries the write queue pushes concurrently.
+pub fn write_queue_workers() -> anyhow::Result<usize> {
+    parse_value("write queue workers", &S3D_WRITE_QUEUE_WORKERS)
+}
+
+/// read_cache_ttl returns how long cached objects are served before they are revalidated.
+pub fn read_cache_ttl() -> anyhow::Result<std::time::Duration> {
+    parse_secs("read cache ttl", &S3D_READ_CACHE_TTL)
+}
+
+/// read_cache_prefetch_interval returns how often the prefetch targets are fetched.
+pub fn read_cache_prefetch_interval() -> anyhow::Result<std::time::Duration> {
+    parse_secs(
+        "read cache prefetch interval",
+        &S3D_READ_CACHE_PREFETCH_INTERVAL,
+    )
+}
+
+/// md_cache_ttl returns how long listing pages and buckets are served from the metadata cache.
+pub fn md_cache_ttl() -> anyhow::Result<std::time::Duration> {
+    parse_secs("metadata cache ttl", &S3D_MD_CACHE_TTL)
+}
+
+/// md_cache_max_entries returns the number of entries the metadata cache holds.
+pub fn md_cache_max_entries() -> anyhow::Result<usize> {
+    parse_value("metadata cache max entries", &S3D_MD_CACHE_MAX_ENTRIES)
+}
+
+/// write_queue_filter returns the filter of the objects that are queued.
+pub fn write_queue_filter() -> anyhow::Result<Option<Filter>> {
+    Filter::from_config("S3D_WRITE_QUEUE_FILTER", S3D_WRITE_QUEUE_FILTER.as_deref())
+}
+
+/// read_cache_filter returns the filter of the objects that are cached.
+pub fn read_cache_filter() -> anyhow::Result<Option<Filter>> {
+    Filter::from_config("S3D_READ_CACHE_FILTER", S3D_READ_CACHE_FILTER.as_deref())
+}
+
+/// read_cache_pin returns the filter of the cached objects that are pinned.
+pub fn read_cache_pin() -> anyhow::Result<Option<Filter>> {
+    Filter::from_config("S3D_READ_CACHE_PIN", S3D_READ_CACHE_PIN.as_deref())
+}
+
+/// sync_folder_filter returns the filter of the objects that are synced.
+pub fn sync_folder_filter() -> anyhow::Result<Option<Filter>> {
+    Filter::from_config("S3D_SYNC_FOLDER_FILTER", S3D_SYNC_FOLDER_FILTER.as_deref())
+}
+
+/// policy returns the policy that authorizes requests, if a policy file is set.
+pub fn policy() -> anyhow::Result<Option<Policy>> {
+    let mut policy = S3D_POLICY
+        .as_deref()
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(|path| Policy::load(std::path::Path::new(path)))
+        .transpose()?;
+    if let Some(policy) = policy.as_mut() {
+        policy.domain = endpoint_domain();
+    }
+    Ok(policy)
+}
+
+/// endpoint_domain returns the domain name of `S3D_ENDPOINT`, under which requests
+/// to `bucket.<domain>` are virtual-hosted-style. Addresses have no domain.
+pub fn endpoint_domain() -> Option<String> {
+    match url::Url::parse(&S3D_ENDPOINT).ok()?.host()? {
+        url::Host::Domain(domain) => Some(domain.to_ascii_lowercase()),
+        _ => None,
+    }
+}
@@ -0,0 +1,116 @@
+//! Connectivity to the remote storage.
+//!
+//! The daemon keeps running when the remote is unreachable (offline mode) -
+//! reads are served from the write queue and from the read cache (including stale
+//! items, which cannot be revalidated), and writes are accepted into the write queue.
+//! Ops that require the remote fail fast with ServiceUnavailable instead of waiting
+//! for the remote to time out.
+//!
+//! The remote is marked offline when a call fails to connect or times out,
+//! and online again when a call gets any response from it. While offline,
+//! calls to the remote are not made, and the remote is probed periodically
+//! to detect when it comes back, which also wakes up the write queue.
+
+use crate::s3::errors::S3Error;
+use aws_smithy_http::result::SdkError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Connectivity {
+    pub s3_client: &'static aws_sdk_s3::Client,
+    online: AtomicBool,
+    /// recovered is notified when the remote is reachable again.
+    pub recovered: Notify,
+}
+
+impl Connectivity {
+    pub fn new(s3_client: &'static aws_sdk_s3::Client) -> Connectivity {
+        Connectivity {
+            s3_client,
+            online: AtomicBool::new(true),
+            recovered: Notify::new(),
+        }
+    }
+
+    pub fn start(&'static self) {
+        tokio::spawn(self.prober());
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    /// check updates the state from the result of a remote call.
+    /// Service errors are responses of the remote, so they mean it is reachable.
+    pub fn check<T, E>(&self, r: &Result<T, SdkError<E>>) {
+        match r {
+            Err(SdkError::DispatchFailure(_)) | Err(SdkError::TimeoutError(_)) => {
+                self.set_online(false)
+            }
+            Err(SdkError::ConstructionFailure(_)) => {}
+            _ => self.set_online(true),
+        }
+    }
+
+    fn set_online(&self, online: bool) {
+        if self.online.swap(online, Ordering::SeqCst) == online {
+            return;
+        }
+        if online {
+            info!("Remote is reachable, leaving offline mode");
+            self.recovered.notify_waiters();
+        } else {
+            warn!("Remote is unreachable, entering offline mode");
+        }
+    }
+
+    /// prober probes the remote while it is offline. Any response (even an error
+    /// like AccessDenied for the probe itself) means that the remote is reachable.
+    async fn prober(&'static self) {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            if self.is_online() {
+                continue;
+            }
+            debug!("Probing remote ...");
+            let r = self.s3_client.list_buckets().send().await;
+            self.check(&r);
+        }
+    }
+}
+
+/// remote_unavailable is the error of ops that require the remote while offline.
+pub fn remote_unavailable() -> S3Error {
+    S3Error::new(
+        "ServiceUnavailable",
+        "The remote storage is unreachable (offline mode)",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn online_by_results() {
+        let s3_client = crate::utils::staticify(aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .region(aws_sdk_s3::Region::new("s3d"))
+                .build(),
+        ));
+        let connectivity = Connectivity::new(s3_client);
+        type R = Result<(), SdkError<std::io::Error>>;
+        assert!(connectivity.is_online());
+        connectivity.check(&R::Err(SdkError::TimeoutError("timeout".into())));
+        assert!(!connectivity.is_online());
+        // errors of building the request say nothing about the remote
+        connectivity.check(&R::Err(SdkError::ConstructionFailure("bad".into())));
+        assert!(!connectivity.is_online());
+        connectivity.check(&R::Ok(()));
+        assert!(connectivity.is_online());
+        assert_eq!(remote_unavailable().code, "ServiceUnavailable");
+    }
+}
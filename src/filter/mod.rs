@@ -0,0 +1,652 @@
+//! Object filters.
+//!
+//! Filters select the objects that the write queue pushes, the read cache keeps and
+//! the sync folder syncs (`S3D_WRITE_QUEUE_FILTER`, `S3D_READ_CACHE_FILTER` and
+//! `S3D_SYNC_FOLDER_FILTER`). A filter is a comma separated list of rules, and an
+//! object is selected if any of the rules matches it:
+//!
+//! ```text
+//! filter    := rule (',' rule)*
+//! rule      := bucket condition* ('/' key condition*)?
+//! condition := '[' source ':' name (op value)? ']'
+//! source    := 'tag' | 'hdr' | 'md' | 'wasm'
+//! op        := '=' | '!=' | '<' | '<=' | '>' | '>='
+//! value     := 'quoted' | "quoted" | bare
+//! ```
+//!
+//! The bucket and key are patterns where `*` matches any characters (so `prefix*`
+//! matches a key prefix), and a rule without a key matches every key of the bucket.
+//! All the conditions of a rule must hold, wherever they are written in the rule:
+//! - `[tag:key]` holds if the object has the tag.
+//! - `=` and `!=` match the value as a pattern, and `!=` holds if there is no value.
+//! - `<`, `<=`, `>` and `>=` compare numbers (e.g. `[hdr:content-length<100]`),
+//!   or strings when the values are not numbers, and do not hold if there is no value.
+//!
+//! Header and metadata names are case insensitive, and tag keys are case sensitive.
+//!
+//! `[wasm:path/to/module.wasm]` holds if a Wasm plugin includes the object (see `wasm`),
+//! for rules that the syntax cannot express.
+
+pub mod wasm;
+
+use crate::filter::wasm::WasmFilter;
+use crate::object_md::ObjectMd;
+use aws_smithy_types::date_time::Format;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub bucket: String,
+    pub key: String,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub source: Source,
+    pub name: String,
+    pub op: Op,
+    pub value: String,
+    /// The module of a wasm condition, see `load_plugins`
+    pub plugin: Option<Arc<WasmFilter>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Tag,
+    Header,
+    Metadata,
+    Wasm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Exists,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// FilterError is a syntax error with its position in the filter.
+#[derive(Debug, Clone)]
+pub struct FilterError {
+    pub input: String,
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let col = self.input[..self.pos].chars().count();
+        write!(
+            f,
+            "{} at column {}\n  {}\n  {}^",
+            self.message,
+            col + 1,
+            self.input,
+            " ".repeat(col)
+        )
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Filter, FilterError> {
+        Parser { input, pos: 0 }.parse_filter()
+    }
+
+    /// from_config parses the filter of a config variable, which selects all objects if empty,
+    /// and loads its plugins.
+    pub fn from_config(name: &str, value: Option<&str>) -> anyhow::Result<Option<Filter>> {
+        let value = match value.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut filter =
+            Filter::parse(value).map_err(|err| anyhow::anyhow!("Invalid {}: {}", name, err))?;
+        filter
+            .load_plugins()
+            .map_err(|err| anyhow::anyhow!("Invalid {}: {}", name, err))?;
+        Ok(Some(filter))
+    }
+
+    /// load_plugins loads the modules of the wasm conditions, once per path.
+    pub fn load_plugins(&mut self) -> anyhow::Result<()> {
+        let mut loaded = HashMap::<String, Arc<WasmFilter>>::new();
+        for cond in self
+            .rules
+            .iter_mut()
+            .flat_map(|rule| rule.conditions.iter_mut())
+        {
+            if cond.source != Source::Wasm {
+                continue;
+            }
+            let plugin = match loaded.get(&cond.name) {
+                Some(plugin) => plugin.clone(),
+                None => {
+                    let plugin = Arc::new(WasmFilter::load(Path::new(&cond.name))?);
+                    loaded.insert(cond.name.clone(), plugin.clone());
+                    plugin
+                }
+            };
+            cond.plugin = Some(plugin);
+        }
+        Ok(())
+    }
+
+    /// matches evaluates the filter on an object. It is async since wasm plugins
+    /// run on the blocking thread pool.
+    pub async fn matches(&self, md: &ObjectMd) -> bool {
+        for rule in &self.rules {
+            if rule.matches(md).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// matches_key evaluates only the bucket and key patterns of the rules, for when
+    /// the object is not known yet. Objects that do not match it never match the filter.
+    pub fn matches_key(&self, bucket: &str, key: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches_key(bucket, key))
+    }
+
+    /// matches_untagged evaluates the filter without the conditions that depend on
+    /// the tags of the object (including wasm plugins, which get the tags too), which
+    /// tells if the object could match the filter by changing its tags.
+    pub fn matches_untagged(&self, md: &ObjectMd) -> bool {
+        self.rules.iter().any(|rule| rule.matches_untagged(md))
+    }
+
+    /// uses_tags tells if evaluating the filter needs the tags of the object,
+    /// which are passed to wasm plugins too.
+    pub fn uses_tags(&self) -> bool {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.conditions.iter())
+            .any(|cond| cond.source == Source::Tag || cond.source == Source::Wasm)
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = FilterError;
+    fn from_str(s: &str) -> Result<Filter, FilterError> {
+        Filter::parse(s)
+    }
+}
+
+impl Rule {
+    pub async fn matches(&self, md: &ObjectMd) -> bool {
+        if !self.matches_key(&md.bucket, &md.key) {
+            return false;
+        }
+        for cond in &self.conditions {
+            if !cond.holds(md).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn matches_key(&self, bucket: &str, key: &str) -> bool {
+        glob_match(&self.bucket, bucket) && glob_match(&self.key, key)
+    }
+
+    pub fn matches_untagged(&self, md: &ObjectMd) -> bool {
+        self.matches_key(&md.bucket, &md.key)
+            && self
+                .conditions
+                .iter()
+                .filter(|c| c.source != Source::Tag && c.source != Source::Wasm)
+                .all(|c| c.holds_static(md))
+    }
+}
+
+impl Condition {
+    pub async fn holds(&self, md: &ObjectMd) -> bool {
+        if self.source != Source::Wasm {
+            return self.holds_static(md);
+        }
+        match &self.plugin {
+            Some(plugin) => plugin.matches(md).await,
+            None => {
+                warn!("Wasm filter {:?} is not loaded", self.name);
+                false
+            }
+        }
+    }
+
+    /// holds_static evaluates the conditions that do not run a wasm plugin,
+    /// which never hold.
+    pub fn holds_static(&self, md: &ObjectMd) -> bool {
+        let value = match self.source {
+            Source::Wasm => return false,
+            Source::Tag => md.tags.get(&self.name).cloned(),
+            Source::Header => header_value(md, &self.name),
+            Source::Metadata => md
+                .metadata
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(&self.name))
+                .map(|(_, v)| v.clone()),
+        };
+        match (self.op, value) {
+            (Op::Exists, value) => value.is_some(),
+            (Op::Ne, None) => true,
+            (_, None) => false,
+            (Op::Eq, Some(v)) => glob_match(&self.value, &v),
+            (Op::Ne, Some(v)) => !glob_match(&self.value, &v),
+            (op, Some(v)) => {
+                let ord = match (v.trim().parse::<f64>(), self.value.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.partial_cmp(&b),
+                    _ => Some(v.as_str().cmp(self.value.as_str())),
+                };
+                match ord {
+                    Some(ord) => match op {
+                        Op::Lt => ord.is_lt(),
+                        Op::Le => ord.is_le(),
+                        Op::Gt => ord.is_gt(),
+                        _ => ord.is_ge(),
+                    },
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// header_value returns the value of a header of the object by its lowercase name.
+pub fn header_value(md: &ObjectMd, name: &str) -> Option<String> {
+    match name.to_ascii_lowercase().as_str() {
+        "content-length" => Some(md.content_length.to_string()),
+        "content-type" => md.content_type.clone(),
+        "content-encoding" => md.content_encoding.clone(),
+        "content-disposition" => md.content_disposition.clone(),
+        "content-language" => md.content_language.clone(),
+        "cache-control" => md.cache_control.clone(),
+        "etag" => md.e_tag.clone(),
+        "last-modified" => md.last_modified_time().fmt(Format::HttpDate).ok(),
+        "x-amz-storage-class" => md.storage_class.clone(),
+        _ => None,
+    }
+}
+
+/// glob_match matches a pattern where `*` matches any characters.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    let mut backtrack = None;
+    while si < s.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            backtrack = Some((pi, si));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == s[si] {
+            pi += 1;
+            si += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            si = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_filter(&mut self) -> Result<Filter, FilterError> {
+        let mut rules = vec![];
+        loop {
+            self.skip_spaces();
+            rules.push(self.parse_rule()?);
+            self.skip_spaces();
+            match self.peek() {
+                None => break,
+                Some(',') => self.pos += 1,
+                Some(c) => return Err(self.error(format!("Unexpected {:?}, expected ','", c))),
+            }
+        }
+        Ok(Filter { rules })
+    }
+
+    fn parse_rule(&mut self) -> Result<Rule, FilterError> {
+        let bucket = self.take_until(&['[', '/', ',', ' ']);
+        if bucket.is_empty() {
+            return Err(self.error("Expected a bucket name".to_string()));
+        }
+        let mut conditions = self.parse_conditions()?;
+        let key = if self.peek() == Some('/') {
+            self.pos += 1;
+            let key = self.take_until(&['[', ',', ' ']);
+            if key.is_empty() {
+                return Err(self.error("Expected a key pattern".to_string()));
+            }
+            conditions.extend(self.parse_conditions()?);
+            key
+        } else {
+            "*".to_string()
+        };
+        Ok(Rule {
+            bucket,
+            key,
+            conditions,
+        })
+    }
+
+    fn parse_conditions(&mut self) -> Result<Vec<Condition>, FilterError> {
+        let mut conditions = vec![];
+        while self.peek() == Some('[') {
+            self.pos += 1;
+            conditions.push(self.parse_condition()?);
+        }
+        Ok(conditions)
+    }
+
+    fn parse_condition(&mut self) -> Result<Condition, FilterError> {
+        let source_pos = self.pos;
+        let source = match self.take_until(&[':', ']', ',']).as_str() {
+            "tag" => Source::Tag,
+            "hdr" => Source::Header,
+            "md" => Source::Metadata,
+            "wasm" => Source::Wasm,
+            other => {
+                self.pos = source_pos;
+                return Err(self.error(format!(
+                    "Unknown condition {:?}, expected tag, hdr, md or wasm",
+                    other
+                )));
+            }
+        };
+        self.expect(':')?;
+        let name = self.take_until(&['=', '!', '<', '>', ']', ',']);
+        if name.is_empty() {
+            return Err(self.error("Expected a name".to_string()));
+        }
+        let op = match (self.peek(), self.input[self.pos..].chars().nth(1)) {
+            (Some(']'), _) => Op::Exists,
+            (Some('!'), Some('=')) => Op::Ne,
+            (Some('<'), Some('=')) => Op::Le,
+            (Some('>'), Some('=')) => Op::Ge,
+            (Some('='), _) => Op::Eq,
+            (Some('<'), _) => Op::Lt,
+            (Some('>'), _) => Op::Gt,
+            _ => return Err(self.error("Expected an operator or ']'".to_string())),
+        };
+        if source == Source::Wasm && op != Op::Exists {
+            return Err(self.error("Expected ']', wasm conditions have no value".to_string()));
+        }
+        self.pos += match op {
+            Op::Exists => 0,
+            Op::Ne | Op::Le | Op::Ge => 2,
+            _ => 1,
+        };
+        let value = match (op, self.peek()) {
+            (Op::Exists, _) => String::new(),
+            (_, Some(quote)) if quote == '\'' || quote == '"' => {
+                let start = self.pos;
+                self.pos += 1;
+                let value = self.take_until(&[quote]);
+                if self.peek() != Some(quote) {
+                    self.pos = start;
+                    return Err(self.error("Unterminated quoted value".to_string()));
+                }
+                self.pos += 1;
+                value
+            }
+            _ => self.take_until(&[']', ',']).trim().to_string(),
+        };
+        self.expect(']')?;
+        Ok(Condition {
+            source,
+            name: name.trim().to_string(),
+            op,
+            value,
+            plugin: None,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn take_until(&mut self, stops: &[char]) -> String {
+        let rest = &self.input[self.pos..];
+        let len = rest.find(|c| stops.contains(&c)).unwrap_or(rest.len());
+        self.pos += len;
+        rest[..len].to_string()
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), FilterError> {
+        if self.peek() != Some(c) {
+            return Err(self.error(format!("Expected {:?}", c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn error(&self, message: String) -> FilterError {
+        FilterError {
+            input: self.input.to_string(),
+            pos: self.pos,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(bucket: &str, key: &str) -> ObjectMd {
+        ObjectMd {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..ObjectMd::default()
+        }
+    }
+
+    fn cond(source: Source, name: &str, op: Op, value: &str) -> Condition {
+        Condition {
+            source,
+            name: name.to_string(),
+            op,
+            value: value.to_string(),
+            plugin: None,
+        }
+    }
+
+    #[test]
+    fn glob() {
+        for (pattern, s) in [
+            ("", ""),
+            ("*", ""),
+            ("*", "anything"),
+            ("abc", "abc"),
+            ("a*", "abc"),
+            ("*c", "abc"),
+            ("a*c", "abbbc"),
+            ("a*b*c", "axxbyyc"),
+            ("*a*a*", "banana"),
+            ("**", "x"),
+        ] {
+            assert!(glob_match(pattern, s), "{:?} {:?}", pattern, s);
+        }
+        for (pattern, s) in [
+            ("", "a"),
+            ("abc", "ab"),
+            ("abc", "abcd"),
+            ("a*c", "abcb"),
+            ("a*b*c", "acb"),
+            ("ABC", "abc"),
+        ] {
+            assert!(!glob_match(pattern, s), "{:?} {:?}", pattern, s);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let filter = Filter::parse(
+            "photos, logs/2022/*[tag:keep], data[md:owner=\"a, b\"]/x*[hdr:content-length>=100]",
+        )
+        .unwrap();
+        assert_eq!(
+            filter.rules,
+            vec![
+                Rule {
+                    bucket: "photos".to_string(),
+                    key: "*".to_string(),
+                    conditions: vec![],
+                },
+                Rule {
+                    bucket: "logs".to_string(),
+                    key: "2022/*".to_string(),
+                    conditions: vec![cond(Source::Tag, "keep", Op::Exists, "")],
+                },
+                Rule {
+                    bucket: "data".to_string(),
+                    key: "x*".to_string(),
+                    conditions: vec![
+                        cond(Source::Metadata, "owner", Op::Eq, "a, b"),
+                        cond(Source::Header, "content-length", Op::Ge, "100"),
+                    ],
+                },
+            ]
+        );
+        let ops = Filter::parse("b[tag:a!=1][tag:b<2][tag:c<=3][tag:d>4][tag:e= 5 ]").unwrap();
+        assert_eq!(
+            ops.rules[0].conditions,
+            vec![
+                cond(Source::Tag, "a", Op::Ne, "1"),
+                cond(Source::Tag, "b", Op::Lt, "2"),
+                cond(Source::Tag, "c", Op::Le, "3"),
+                cond(Source::Tag, "d", Op::Gt, "4"),
+                cond(Source::Tag, "e", Op::Eq, "5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (input, pos, message) in [
+            ("", 0, "Expected a bucket name"),
+            ("a,", 2, "Expected a bucket name"),
+            ("a/", 2, "Expected a key pattern"),
+            ("a b", 2, "Unexpected 'b', expected ','"),
+            (
+                "a[foo:x]",
+                2,
+                "Unknown condition \"foo\", expected tag, hdr, md or wasm",
+            ),
+            ("a[tag]", 5, "Expected ':'"),
+            ("a[tag:]", 6, "Expected a name"),
+            ("a[tag:x", 7, "Expected an operator or ']'"),
+            ("a[tag:x='y]", 8, "Unterminated quoted value"),
+            ("a[tag:x=y", 9, "Expected ']'"),
+            (
+                "a[wasm:f.wasm=1]",
+                13,
+                "Expected ']', wasm conditions have no value",
+            ),
+        ] {
+            let err = Filter::parse(input).unwrap_err();
+            assert_eq!(
+                (err.pos, err.message.as_str()),
+                (pos, message),
+                "{:?}",
+                input
+            );
+        }
+        let err = Filter::parse("ü[tag]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected ':' at column 6\n  ü[tag]\n       ^"
+        );
+    }
+
+    #[tokio::test]
+    async fn matches() {
+        let filter =
+            Filter::parse("photos/*.jpg, logs[tag:keep=true], data[hdr:content-length<100]")
+                .unwrap();
+        let mut md = object("photos", "a/b.jpg");
+        assert!(filter.matches(&md).await);
+        md.key = "a/b.png".to_string();
+        assert!(!filter.matches(&md).await);
+
+        let mut md = object("logs", "app.log");
+        assert!(!filter.matches(&md).await);
+        assert!(filter.matches_key("logs", "app.log"));
+        assert!(!filter.matches_key("other", "app.log"));
+        md.tags.insert("keep".to_string(), "true".to_string());
+        assert!(filter.matches(&md).await);
+
+        let mut md = object("data", "k");
+        md.content_length = 99;
+        assert!(filter.matches(&md).await);
+        md.content_length = 100;
+        assert!(!filter.matches(&md).await);
+    }
+
+    #[tokio::test]
+    async fn conditions() {
+        let mut md = object("b", "k");
+        md.content_type = Some("image/png".to_string());
+        md.metadata.insert("Owner".to_string(), "alice".to_string());
+        md.tags.insert("tier".to_string(), "b".to_string());
+        for (filter, expected) in [
+            ("b[hdr:Content-Type=image/*]", true),
+            ("b[hdr:content-type!=image/*]", false),
+            ("b[hdr:content-encoding!=gzip]", true),
+            ("b[hdr:content-encoding]", false),
+            ("b[md:owner=alice]", true),
+            ("b[md:OWNER]", true),
+            ("b[tag:Tier]", false),
+            ("b[tag:tier>a]", true),
+            ("b[tag:tier<a]", false),
+            ("b[hdr:content-length>=0][tag:tier]", true),
+            ("b[hdr:content-length>0]", false),
+            ("b[hdr:content-length<1e3]", true),
+            ("b[md:missing<1]", false),
+        ] {
+            let filter = Filter::parse(filter).unwrap();
+            assert_eq!(filter.matches(&md).await, expected, "{:?}", filter);
+        }
+    }
+
+    #[tokio::test]
+    async fn untagged() {
+        let filter = Filter::parse("b[tag:push=true][md:owner=alice], b/raw/*[tag:push]").unwrap();
+        assert!(filter.uses_tags());
+        assert!(!Filter::parse("b[md:owner]").unwrap().uses_tags());
+        let mut md = object("b", "k");
+        md.metadata.insert("owner".to_string(), "alice".to_string());
+        assert!(!filter.matches(&md).await);
+        assert!(filter.matches_untagged(&md));
+        md.metadata.insert("owner".to_string(), "bob".to_string());
+        assert!(!filter.matches_untagged(&md));
+        md.key = "raw/k".to_string();
+        assert!(filter.matches_untagged(&md));
+    }
+}
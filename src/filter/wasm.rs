@@ -0,0 +1,304 @@
+//! Wasm filter plugins.
+//!
+//! A `[wasm:path/to/module.wasm]` condition of a filter passes the object to a
+//! user supplied WebAssembly module, which decides if the object is included.
+//! The module is compiled and linked once when the filter is loaded, and every
+//! evaluation runs in a new instance, so modules cannot keep state between objects.
+//! Evaluations run on the blocking thread pool, with a fuel budget that bounds the
+//! instructions of a call (`FUEL_PER_CALL`) and a cap on the linear memory
+//! (`MAX_MEMORY`), so a module that loops or grows without bounds fails the call
+//! instead of stalling the server.
+//!
+//! Modules run in the embedded [wasmi](https://crates.io/crates/wasmi) interpreter
+//! without any imports, and export:
+//!
+//! ```text
+//! (memory (export "memory") 1)
+//! (func (export "alloc") (param $len i32) (result i32))
+//! (func (export "filter") (param $ptr i32) (param $len i32) (result i32))
+//! ```
+//!
+//! The object is written as JSON to the memory returned by `alloc`, and `filter`
+//! returns 1 to include the object and 0 to exclude it:
+//!
+//! ```text
+//! {"bucket":"b","key":"k","size":1,"headers":{..},"metadata":{..},"tags":{..}}
+//! ```
+//!
+//! Any other result, a trap, or running out of fuel or memory, is logged and excludes
+//! the object.
+
+use crate::filter::header_value;
+use crate::object_md::ObjectMd;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use wasmi::{
+    Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+/// The fuel of a call to a module, which is roughly the number of instructions it
+/// can run, for the `alloc` and `filter` calls together.
+pub const FUEL_PER_CALL: u64 = 10_000_000;
+
+/// The maximum size of the linear memory of an instance, in bytes.
+pub const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Headers that are passed to the modules, when the object has them.
+pub const HEADERS: &[&str] = &[
+    "content-type",
+    "content-encoding",
+    "content-disposition",
+    "content-language",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "x-amz-storage-class",
+];
+
+pub struct WasmFilter {
+    pub path: PathBuf,
+    engine: Engine,
+    module: Module,
+    linker: Linker<WasmState>,
+}
+
+struct WasmState {
+    limits: StoreLimits,
+}
+
+struct WasmInstance {
+    store: Store<WasmState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    filter: TypedFunc<(i32, i32), i32>,
+}
+
+#[derive(Serialize)]
+struct WasmFilterInput<'a> {
+    bucket: &'a str,
+    key: &'a str,
+    size: i64,
+    headers: BTreeMap<&'static str, String>,
+    metadata: &'a BTreeMap<String, String>,
+    tags: &'a BTreeMap<String, String>,
+}
+
+impl WasmFilter {
+    /// load compiles a module, and instantiates it once to check its exports.
+    pub fn load(path: &Path) -> anyhow::Result<WasmFilter> {
+        let wasm = std::fs::read(path)
+            .map_err(|err| anyhow::anyhow!("Read wasm filter {:?}: {}", path, err))?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &wasm[..])
+            .map_err(|err| anyhow::anyhow!("Compile wasm filter {:?}: {}", path, err))?;
+        let linker = <Linker<WasmState>>::new(&engine);
+        let wasm_filter = WasmFilter {
+            path: path.to_path_buf(),
+            engine,
+            module,
+            linker,
+        };
+        wasm_filter
+            .instantiate()
+            .map_err(|err| anyhow::anyhow!("Wasm filter {:?}: {}", path, err))?;
+        info!("Wasm filter loaded: {:?}", path);
+        Ok(wasm_filter)
+    }
+
+    /// matches runs the module on an object on the blocking thread pool,
+    /// and excludes it on errors.
+    pub async fn matches(self: &Arc<Self>, md: &ObjectMd) -> bool {
+        let input = match serde_json::to_vec(&WasmFilterInput {
+            bucket: &md.bucket,
+            key: &md.key,
+            size: md.content_length,
+            headers: HEADERS
+                .iter()
+                .filter_map(|name| Some((*name, header_value(md, name)?)))
+                .collect(),
+            metadata: &md.metadata,
+            tags: &md.tags,
+        }) {
+            Ok(input) => input,
+            Err(err) => {
+                warn!("Wasm filter {:?}: input {}", self.path, err);
+                return false;
+            }
+        };
+        let plugin = self.clone();
+        let res = tokio::task::spawn_blocking(move || plugin.call(&input))
+            .await
+            .unwrap_or_else(|err| Err(anyhow::anyhow!("call {}", err)));
+        match res {
+            Ok(1) => true,
+            Ok(0) => false,
+            Ok(res) => {
+                warn!("Wasm filter {:?}: invalid result {}", self.path, res);
+                false
+            }
+            Err(err) => {
+                warn!("Wasm filter {:?}: {}", self.path, err);
+                false
+            }
+        }
+    }
+
+    fn call(&self, input: &[u8]) -> anyhow::Result<i32> {
+        let mut i = self.instantiate()?;
+        let len = i32::try_from(input.len())?;
+        let ptr = i
+            .alloc
+            .call(&mut i.store, len)
+            .map_err(|err| anyhow::anyhow!("alloc: {}", err))?;
+        i.memory
+            .write(&mut i.store, ptr as u32 as usize, input)
+            .map_err(|err| anyhow::anyhow!("write input: {}", err))?;
+        i.filter
+            .call(&mut i.store, (ptr, len))
+            .map_err(|err| anyhow::anyhow!("filter: {}", err))
+    }
+
+    /// instantiate creates a new instance of the module with the limits of a call,
+    /// and finds its exports.
+    fn instantiate(&self) -> anyhow::Result<WasmInstance> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, WasmState { limits });
+        store.limiter(|state| &mut state.limits);
+        store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|err| anyhow::anyhow!("fuel: {}", err))?;
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .map_err(|err| anyhow::anyhow!("instantiate: {}", err))?
+            .start(&mut store)
+            .map_err(|err| anyhow::anyhow!("start: {}", err))?;
+        let export = |store: &Store<WasmState>, name: &str| {
+            instance
+                .get_export(store, name)
+                .ok_or_else(|| anyhow::anyhow!("missing export {:?}", name))
+        };
+        let memory = export(&store, "memory")?
+            .into_memory()
+            .ok_or_else(|| anyhow::anyhow!("export \"memory\" is not a memory"))?;
+        let alloc = export(&store, "alloc")?
+            .into_func()
+            .ok_or_else(|| anyhow::anyhow!("export \"alloc\" is not a function"))?
+            .typed::<i32, i32>(&store)
+            .map_err(|err| anyhow::anyhow!("export \"alloc\": {}", err))?;
+        let filter = export(&store, "filter")?
+            .into_func()
+            .ok_or_else(|| anyhow::anyhow!("export \"filter\" is not a function"))?
+            .typed::<(i32, i32), i32>(&store)
+            .map_err(|err| anyhow::anyhow!("export \"filter\": {}", err))?;
+        Ok(WasmInstance {
+            store,
+            memory,
+            alloc,
+            filter,
+        })
+    }
+}
+
+impl fmt::Debug for WasmFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmFilter")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl PartialEq for WasmFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// load_wat loads a module with the name of its memory export and the body of `filter`.
+    fn load_wat(exports: &str, body: &str) -> anyhow::Result<Arc<WasmFilter>> {
+        let wat = format!(
+            r#"(module
+                (memory (export "{}") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 16)
+                (func (export "filter") (param i32 i32) (result i32) {}))"#,
+            exports, body
+        );
+        let path = std::env::temp_dir().join(format!("s3d-test-{}.wasm", uuid::Uuid::new_v4()));
+        std::fs::write(&path, wat::parse_str(&wat)?)?;
+        let res = WasmFilter::load(&path);
+        std::fs::remove_file(&path)?;
+        Ok(Arc::new(res?))
+    }
+
+    #[tokio::test]
+    async fn results() {
+        let md = ObjectMd {
+            bucket: "b".to_string(),
+            key: "k".to_string(),
+            ..Default::default()
+        };
+        assert!(
+            load_wat("memory", "i32.const 1")
+                .unwrap()
+                .matches(&md)
+                .await
+        );
+        assert!(
+            !load_wat("memory", "i32.const 0")
+                .unwrap()
+                .matches(&md)
+                .await
+        );
+        assert!(
+            !load_wat("memory", "i32.const 2")
+                .unwrap()
+                .matches(&md)
+                .await
+        );
+        assert!(
+            !load_wat("memory", "unreachable")
+                .unwrap()
+                .matches(&md)
+                .await
+        );
+        // the input is the json of the object at the allocated address
+        let json = "(i32.and (i32.eq (local.get 0) (i32.const 16)) \
+                    (i32.eq (i32.load8_u (local.get 0)) (i32.const 123)))";
+        assert!(load_wat("memory", json).unwrap().matches(&md).await);
+    }
+
+    #[test]
+    fn missing_exports() {
+        let err = load_wat("mem", "i32.const 1").unwrap_err();
+        assert!(
+            err.to_string().contains("missing export \"memory\""),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let md = ObjectMd::default();
+        // a module that never returns runs out of fuel
+        let spin = load_wat("memory", "(loop br 0) i32.const 1").unwrap();
+        assert!(!spin.matches(&md).await);
+        // growing the memory beyond MAX_MEMORY fails with -1
+        let grow = "(i32.eq (memory.grow (i32.const 1000)) (i32.const -1))";
+        assert!(load_wat("memory", grow).unwrap().matches(&md).await);
+        let grow = "(i32.ne (memory.grow (i32.const 10)) (i32.const -1))";
+        assert!(load_wat("memory", grow).unwrap().matches(&md).await);
+    }
+}
@@ -5,9 +5,16 @@
 // #![doc = include_str!("../README.md")]
 // #![allow(unused)]
 
+pub mod byte_range;
+pub mod checksum;
 pub mod cli;
 pub mod codegen_include;
 pub mod config;
+pub mod connectivity;
+pub mod filter;
+pub mod md_cache;
+pub mod object_md;
+pub mod read_cache;
 pub mod s3;
 pub mod utils;
 pub mod write_queue;
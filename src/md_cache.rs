@@ -0,0 +1,161 @@
+//! Metadata cache of the remote storage.
+//!
+//! Listing pages and HeadBucket results of the remote are kept in memory for a short
+//! TTL, so that browsing (`s3 ls`, FUSE readdir) over a high-latency link does not
+//! round-trip to the remote on every request. The cached pages are the remote pages,
+//! and the write queue is merged into them on every request, so queued writes are
+//! listed right away.
+//!
+//! Every write or delete that passes through s3d invalidates the cached pages of its
+//! bucket, and so does every push of the write queue, since it changes the remote.
+//! Changes that are made directly on the remote are seen once the TTL has passed.
+//! While the remote is offline, expired entries are served too.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct MdCacheEntry {
+    bucket: String,
+    stored: Instant,
+    value: Box<dyn Any + Send + Sync>,
+}
+
+pub struct MdCache {
+    pub ttl: Duration,
+    pub max_entries: usize,
+    entries: Mutex<HashMap<String, MdCacheEntry>>,
+    /// generation is increased by every invalidation, so that results of calls
+    /// that started before a write are not stored.
+    generation: AtomicU64,
+}
+
+impl MdCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> MdCache {
+        MdCache {
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// to_key returns the cache key of an op input, which has all the request params.
+    pub fn to_key<I: std::fmt::Debug>(op: &str, i: &I) -> String {
+        format!("{}:{:?}", op, i)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// get returns a cached value within the TTL, or any cached value if allow_stale.
+    pub fn get<T: Clone + 'static>(&self, key: &str, allow_stale: bool) -> Option<T> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if !allow_stale && entry.stored.elapsed() > self.ttl {
+            return None;
+        }
+        entry.value.downcast_ref::<T>().cloned()
+    }
+
+    /// put stores a value unless the cache was invalidated since the generation
+    /// was taken, and evicts the oldest entry when the cache is full.
+    pub fn put<T: Send + Sync + 'static>(
+        &self,
+        key: String,
+        bucket: &str,
+        value: T,
+        generation: u64,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            MdCacheEntry {
+                bucket: bucket.to_string(),
+                stored: Instant::now(),
+                value: Box::new(value),
+            },
+        );
+    }
+
+    /// invalidate removes the cached entries of a bucket.
+    pub fn invalidate(&self, bucket: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.retain(|_, entry| entry.bucket != bucket);
+        debug!("Metadata cache: invalidate {:?}", bucket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_and_put() {
+        let md_cache = MdCache::new(Duration::from_secs(60), 10);
+        let key = MdCache::to_key("ListObjects", &("bucket", Some("prefix/")));
+        assert_eq!(key, "ListObjects:(\"bucket\", Some(\"prefix/\"))");
+        assert_eq!(md_cache.get::<String>(&key, false), None);
+        md_cache.put(key.clone(), "bucket", "page".to_string(), 0);
+        assert_eq!(md_cache.get::<String>(&key, false).as_deref(), Some("page"));
+        // values are only returned as their own type
+        assert_eq!(md_cache.get::<u64>(&key, false), None);
+    }
+
+    #[test]
+    fn stale_entries() {
+        let md_cache = MdCache::new(Duration::ZERO, 10);
+        md_cache.put("a".to_string(), "bucket", 1u64, 0);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(md_cache.get::<u64>("a", false), None);
+        assert_eq!(md_cache.get::<u64>("a", true), Some(1));
+    }
+
+    #[test]
+    fn invalidate_bucket() {
+        let md_cache = MdCache::new(Duration::from_secs(60), 10);
+        md_cache.put("a".to_string(), "a", 1u64, 0);
+        md_cache.put("b".to_string(), "b", 2u64, 0);
+        let generation = md_cache.generation();
+        md_cache.invalidate("a");
+        assert_eq!(md_cache.get::<u64>("a", false), None);
+        assert_eq!(md_cache.get::<u64>("b", false), Some(2));
+        // a result of a call that started before the invalidation is not stored
+        md_cache.put("a".to_string(), "a", 3u64, generation);
+        assert_eq!(md_cache.get::<u64>("a", false), None);
+        md_cache.put("a".to_string(), "a", 4u64, md_cache.generation());
+        assert_eq!(md_cache.get::<u64>("a", false), Some(4));
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let md_cache = MdCache::new(Duration::from_secs(60), 2);
+        for key in ["a", "b", "c"] {
+            md_cache.put(key.to_string(), "bucket", 0u64, 0);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(md_cache.get::<u64>("a", false), None);
+        assert_eq!(md_cache.get::<u64>("b", false), Some(0));
+        assert_eq!(md_cache.get::<u64>("c", false), Some(0));
+        // replacing an entry does not evict another one
+        md_cache.put("b".to_string(), "bucket", 1u64, 0);
+        assert_eq!(md_cache.get::<u64>("b", false), Some(1));
+        assert_eq!(md_cache.get::<u64>("c", false), Some(0));
+    }
+}
@@ -0,0 +1,430 @@
+//! Object meta-data records.
+//!
+//! An `ObjectMd` keeps everything about an object that is not its data -
+//! the standard headers, user meta-data, tags and storage class.
+//! It is stored as a yaml sidecar file next to the object data file,
+//! named `<data-file>@s3d-object-md.yaml`. Data files are named by urlencoded keys,
+//! where `@` is always encoded, so a data file never looks like a sidecar.
+
+use crate::byte_range::content_range;
+use crate::checksum::{CRC32, CRC32C, SHA1, SHA256};
+use crate::s3::errors::S3Error;
+use crate::utils::{read_yaml_file, write_yaml_file};
+use aws_smithy_http::byte_stream::ByteStream;
+use aws_smithy_types::DateTime;
+use s3d_smithy_codegen_server_s3::{
+    input::{CreateMultipartUploadInput, PutObjectInput},
+    model::StorageClass,
+    output::{GetObjectOutput, HeadObjectOutput},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const MD_SUFFIX: &str = "@s3d-object-md.yaml";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObjectMd {
+    pub bucket: String,
+    pub key: String,
+    pub content_length: i64,
+    /// Sizes of the parts of an object that was completed from a multipart upload,
+    /// to serve requests by part number
+    pub part_sizes: Vec<u64>,
+    /// Milliseconds since epoch
+    pub last_modified: i64,
+    pub e_tag: Option<String>,
+    /// Base64 MD5 of the data
+    pub content_md5: Option<String>,
+    /// Base64 checksums of the data by algorithm name (e.g CRC32)
+    pub checksums: BTreeMap<String, String>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_disposition: Option<String>,
+    pub content_language: Option<String>,
+    pub cache_control: Option<String>,
+    /// Seconds since epoch
+    pub expires: Option<i64>,
+    pub storage_class: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+    /// Set on tombstones, which record deletes of objects in the write queue
+    pub delete_marker: bool,
+}
+
+impl ObjectMd {
+    pub fn from_put_object_input(i: &PutObjectInput) -> ObjectMd {
+        ObjectMd {
+            bucket: i.bucket().to_string(),
+            key: i.key().to_string(),
+            content_length: i.content_length(),
+            part_sizes: Vec::new(),
+            last_modified: chrono::Utc::now().timestamp_millis(),
+            e_tag: None,
+            content_md5: None,
+            checksums: BTreeMap::new(),
+            content_type: i.content_type().map(String::from),
+            content_encoding: i.content_encoding().map(String::from),
+            content_disposition: i.content_disposition().map(String::from),
+            content_language: i.content_language().map(String::from),
+            cache_control: i.cache_control().map(String::from),
+            expires: i.expires().map(|t| t.secs()),
+            storage_class: i.storage_class().map(|s| s.as_str().to_string()),
+            metadata: i
+                .metadata()
+                .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            tags: i.tagging().map(parse_tagging).unwrap_or_default(),
+            delete_marker: false,
+        }
+    }
+
+    /// from_create_multipart_upload_input keeps the headers of a multipart upload
+    /// to apply to the object when the upload is completed.
+    pub fn from_create_multipart_upload_input(i: &CreateMultipartUploadInput) -> ObjectMd {
+        ObjectMd {
+            bucket: i.bucket().to_string(),
+            key: i.key().to_string(),
+            last_modified: chrono::Utc::now().timestamp_millis(),
+            content_type: i.content_type().map(String::from),
+            content_encoding: i.content_encoding().map(String::from),
+            content_disposition: i.content_disposition().map(String::from),
+            content_language: i.content_language().map(String::from),
+            cache_control: i.cache_control().map(String::from),
+            expires: i.expires().map(|t| t.secs()),
+            storage_class: i.storage_class().map(|s| s.as_str().to_string()),
+            metadata: i
+                .metadata()
+                .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            tags: i.tagging().map(parse_tagging).unwrap_or_default(),
+            ..ObjectMd::default()
+        }
+    }
+
+    /// from_client_get_object_output keeps the headers of an object read from the remote.
+    /// Tags are not returned by GetObject, only their count.
+    pub fn from_client_get_object_output(
+        bucket: &str,
+        key: &str,
+        o: &aws_sdk_s3::output::GetObjectOutput,
+    ) -> ObjectMd {
+        ObjectMd {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_length: o.content_length(),
+            last_modified: o
+                .last_modified()
+                .map(|t| t.secs() * 1000 + t.subsec_nanos() as i64 / 1_000_000)
+                .unwrap_or_default(),
+            e_tag: o.e_tag().map(String::from),
+            content_type: o.content_type().map(String::from),
+            content_encoding: o.content_encoding().map(String::from),
+            content_disposition: o.content_disposition().map(String::from),
+            content_language: o.content_language().map(String::from),
+            cache_control: o.cache_control().map(String::from),
+            expires: o.expires().map(|t| t.secs()),
+            storage_class: o.storage_class().map(|s| s.as_str().to_string()),
+            metadata: o
+                .metadata()
+                .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            ..ObjectMd::default()
+        }
+    }
+
+    /// from_get_object_output keeps the headers of an object read through the gateway,
+    /// to serve them again from a local copy. Tags are not returned by GetObject.
+    pub fn from_get_object_output(bucket: &str, key: &str, o: &GetObjectOutput) -> ObjectMd {
+        ObjectMd {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_length: o.content_length(),
+            last_modified: o
+                .last_modified()
+                .map(|t| t.secs() * 1000 + t.subsec_nanos() as i64 / 1_000_000)
+                .unwrap_or_default(),
+            e_tag: o.e_tag().map(String::from),
+            checksums: [
+                (CRC32, o.checksum_crc32()),
+                (CRC32C, o.checksum_crc32_c()),
+                (SHA1, o.checksum_sha1()),
+                (SHA256, o.checksum_sha256()),
+            ]
+            .into_iter()
+            .filter_map(|(algo, sum)| Some((algo.to_string(), sum?.to_string())))
+            .collect(),
+            content_type: o.content_type().map(String::from),
+            content_encoding: o.content_encoding().map(String::from),
+            content_disposition: o.content_disposition().map(String::from),
+            content_language: o.content_language().map(String::from),
+            cache_control: o.cache_control().map(String::from),
+            expires: o.expires().map(|t| t.secs()),
+            storage_class: o.storage_class().map(|s| s.as_str().to_string()),
+            metadata: o
+                .metadata()
+                .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            ..ObjectMd::default()
+        }
+    }
+
+    /// tombstone returns the meta-data of a delete of an object.
+    pub fn tombstone(bucket: &str, key: &str) -> ObjectMd {
+        ObjectMd {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            last_modified: chrono::Utc::now().timestamp_millis(),
+            delete_marker: true,
+            ..ObjectMd::default()
+        }
+    }
+
+    pub async fn read(path: &Path) -> anyhow::Result<ObjectMd> {
+        read_yaml_file(path).await
+    }
+
+    pub async fn write(&self, path: &Path) -> anyhow::Result<()> {
+        write_yaml_file(path, self).await
+    }
+
+    pub fn last_modified_time(&self) -> DateTime {
+        DateTime::from_millis(self.last_modified)
+    }
+
+    pub fn expires_time(&self) -> Option<DateTime> {
+        self.expires.map(DateTime::from_secs)
+    }
+
+    pub fn metadata_map(&self) -> Option<HashMap<String, String>> {
+        if self.metadata.is_empty() {
+            return None;
+        }
+        Some(self.metadata.clone().into_iter().collect())
+    }
+
+    /// tagging returns the tags encoded as the `x-amz-tagging` header value.
+    pub fn tagging(&self) -> Option<String> {
+        if self.tags.is_empty() {
+            return None;
+        }
+        Some(
+            url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(self.tags.iter())
+                .finish(),
+        )
+    }
+
+    pub fn to_head_object_output(&self) -> HeadObjectOutput {
+        HeadObjectOutput::builder()
+            .accept_ranges("bytes")
+            .content_length(self.content_length)
+            .last_modified(self.last_modified_time())
+            .set_e_tag(self.e_tag.clone())
+            .set_content_type(self.content_type.clone())
+            .set_content_encoding(self.content_encoding.clone())
+            .set_content_disposition(self.content_disposition.clone())
+            .set_content_language(self.content_language.clone())
+            .set_cache_control(self.cache_control.clone())
+            .set_expires(self.expires_time())
+            .set_storage_class(self.storage_class.as_deref().map(StorageClass::from))
+            .set_metadata(self.metadata_map())
+            .set_checksum_crc32(self.checksums.get(CRC32).cloned())
+            .set_checksum_crc32_c(self.checksums.get(CRC32C).cloned())
+            .set_checksum_sha1(self.checksums.get(SHA1).cloned())
+            .set_checksum_sha256(self.checksums.get(SHA256).cloned())
+            .build()
+    }
+
+    pub fn to_get_object_output(&self, body: ByteStream) -> GetObjectOutput {
+        GetObjectOutput::builder()
+            .body(body)
+            .accept_ranges("bytes")
+            .content_length(self.content_length)
+            .last_modified(self.last_modified_time())
+            .set_e_tag(self.e_tag.clone())
+            .set_content_type(self.content_type.clone())
+            .set_content_encoding(self.content_encoding.clone())
+            .set_content_disposition(self.content_disposition.clone())
+            .set_content_language(self.content_language.clone())
+            .set_cache_control(self.cache_control.clone())
+            .set_expires(self.expires_time())
+            .set_storage_class(self.storage_class.as_deref().map(StorageClass::from))
+            .set_metadata(self.metadata_map())
+            .set_checksum_crc32(self.checksums.get(CRC32).cloned())
+            .set_checksum_crc32_c(self.checksums.get(CRC32C).cloned())
+            .set_checksum_sha1(self.checksums.get(SHA1).cloned())
+            .set_checksum_sha256(self.checksums.get(SHA256).cloned())
+            .set_tag_count(if self.tags.is_empty() {
+                None
+            } else {
+                Some(self.tags.len() as i32)
+            })
+            .build()
+    }
+
+    /// to_get_object_range_output is `to_get_object_output` for a partial response,
+    /// where the body has the bytes from first to last (inclusive).
+    pub fn to_get_object_range_output(
+        &self,
+        body: ByteStream,
+        first: u64,
+        last: u64,
+    ) -> GetObjectOutput {
+        let mut o = self.to_get_object_output(body);
+        o.content_length = (last - first + 1) as i64;
+        o.content_range = Some(content_range(first, last, self.content_length as u64));
+        // checksums are of the whole object
+        o.checksum_crc32 = None;
+        o.checksum_crc32_c = None;
+        o.checksum_sha1 = None;
+        o.checksum_sha256 = None;
+        o
+    }
+
+    /// part_range returns the first and last byte of a part of the object, or None
+    /// for part 1 of an object that was not uploaded in parts, which is the whole object.
+    pub fn part_range(&self, part_number: i32) -> Result<Option<(u64, u64)>, S3Error> {
+        let invalid = || {
+            S3Error::new(
+                "InvalidPartNumber",
+                "The requested partnumber is not satisfiable",
+            )
+        };
+        if self.part_sizes.is_empty() {
+            return match part_number {
+                1 => Ok(None),
+                _ => Err(invalid()),
+            };
+        }
+        let index = usize::try_from(part_number - 1).map_err(|_| invalid())?;
+        let size = *self.part_sizes.get(index).ok_or_else(invalid)?;
+        if size == 0 {
+            return Err(S3Error::new(
+                "InvalidRange",
+                "The requested range is not satisfiable",
+            ));
+        }
+        let first = self.part_sizes[..index].iter().sum::<u64>();
+        Ok(Some((first, first + size - 1)))
+    }
+
+    /// to_get_object_part_output is `to_get_object_range_output` for a request by part number,
+    /// which also returns the number of parts.
+    pub fn to_get_object_part_output(
+        &self,
+        body: ByteStream,
+        first: u64,
+        last: u64,
+    ) -> GetObjectOutput {
+        let mut o = self.to_get_object_range_output(body, first, last);
+        o.parts_count = self.part_sizes.len() as i32;
+        o
+    }
+
+    /// to_head_object_part_output is `to_head_object_output` for a request by part number.
+    pub fn to_head_object_part_output(&self, first: u64, last: u64) -> HeadObjectOutput {
+        let mut o = self.to_head_object_output();
+        o.content_length = (last - first + 1) as i64;
+        o.parts_count = self.part_sizes.len() as i32;
+        o.checksum_crc32 = None;
+        o.checksum_crc32_c = None;
+        o.checksum_sha1 = None;
+        o.checksum_sha256 = None;
+        o
+    }
+
+    /// apply_to_put_object sets the stored meta-data on a client put request,
+    /// so that the remote object is created with the same headers as the original write.
+    /// The stored digests are sent too, so the remote verifies the data end to end.
+    pub fn apply_to_put_object(
+        &self,
+        req: aws_sdk_s3::client::fluent_builders::PutObject,
+    ) -> aws_sdk_s3::client::fluent_builders::PutObject {
+        req.set_content_type(self.content_type.clone())
+            .set_content_encoding(self.content_encoding.clone())
+            .set_content_disposition(self.content_disposition.clone())
+            .set_content_language(self.content_language.clone())
+            .set_cache_control(self.cache_control.clone())
+            .set_expires(self.expires_time())
+            .set_storage_class(
+                self.storage_class
+                    .as_deref()
+                    .map(aws_sdk_s3::model::StorageClass::from),
+            )
+            .set_metadata(self.metadata_map())
+            .set_tagging(self.tagging())
+            .set_content_md5(self.content_md5.clone())
+            .set_checksum_crc32(self.checksums.get(CRC32).cloned())
+            .set_checksum_crc32_c(self.checksums.get(CRC32C).cloned())
+            .set_checksum_sha1(self.checksums.get(SHA1).cloned())
+            .set_checksum_sha256(self.checksums.get(SHA256).cloned())
+    }
+
+    /// apply_to_create_multipart_upload is the same as `apply_to_put_object`
+    /// for pushes of large objects, except for the digests which are sent per part.
+    pub fn apply_to_create_multipart_upload(
+        &self,
+        req: aws_sdk_s3::client::fluent_builders::CreateMultipartUpload,
+    ) -> aws_sdk_s3::client::fluent_builders::CreateMultipartUpload {
+        req.set_content_type(self.content_type.clone())
+            .set_content_encoding(self.content_encoding.clone())
+            .set_content_disposition(self.content_disposition.clone())
+            .set_content_language(self.content_language.clone())
+            .set_cache_control(self.cache_control.clone())
+            .set_expires(self.expires_time())
+            .set_storage_class(
+                self.storage_class
+                    .as_deref()
+                    .map(aws_sdk_s3::model::StorageClass::from),
+            )
+            .set_metadata(self.metadata_map())
+            .set_tagging(self.tagging())
+    }
+}
+
+/// parse_tagging decodes the `x-amz-tagging` header value (`k1=v1&k2=v2`).
+pub fn parse_tagging(tagging: &str) -> BTreeMap<String, String> {
+    url::form_urlencoded::parse(tagging.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+pub fn md_path(data_path: &Path) -> PathBuf {
+    let mut s = data_path.as_os_str().to_owned();
+    s.push(MD_SUFFIX);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_ranges() {
+        let range = |md: &ObjectMd, part_number| md.part_range(part_number).map_err(|err| err.code);
+        let single = ObjectMd {
+            content_length: 13,
+            ..ObjectMd::default()
+        };
+        assert_eq!(range(&single, 1), Ok(None));
+        assert_eq!(range(&single, 2), Err("InvalidPartNumber"));
+        let parts = ObjectMd {
+            content_length: 13,
+            part_sizes: vec![5, 5, 3],
+            ..ObjectMd::default()
+        };
+        assert_eq!(range(&parts, 1), Ok(Some((0, 4))));
+        assert_eq!(range(&parts, 2), Ok(Some((5, 9))));
+        assert_eq!(range(&parts, 3), Ok(Some((10, 12))));
+        for bad in [-1, 0, 4] {
+            assert_eq!(range(&parts, bad), Err("InvalidPartNumber"), "{}", bad);
+        }
+        let empty = ObjectMd {
+            part_sizes: vec![0],
+            ..ObjectMd::default()
+        };
+        assert_eq!(range(&empty, 1), Err("InvalidRange"));
+    }
+}
@@ -0,0 +1,326 @@
+//! Block cache for byte ranges of objects.
+//!
+//! Range reads of objects that are not cached whole (typically huge objects like
+//! videos, model weights or parquet files) cache only the touched blocks of the object.
+//! Blocks are items of the cache like whole objects, named `.blocks/<entry>/<index>`,
+//! and each block has a sidecar with the meta-data of the whole object,
+//! so the blocks of an object are evicted independently by the LRU order.
+//!
+//! Missing blocks of a range are fetched with a single ranged read of the remote,
+//! with If-Match on the ETag of the cached blocks, so that blocks of different
+//! versions of an object are never served together. Ranges that span more than
+//! `MAX_READ_BLOCKS` are passed to the remote, and so are suffix ranges of objects
+//! with no cached blocks, since their offsets are not known.
+
+use crate::byte_range::{parse_content_range, ByteRange};
+use crate::object_md::{md_path, ObjectMd, MD_SUFFIX};
+use crate::read_cache::{CacheItem, ReadCache};
+use crate::utils::read_ranges_as_stream;
+use aws_smithy_http::result::SdkError;
+use s3d_smithy_codegen_server_s3::{input::GetObjectInput, output::GetObjectOutput};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+
+pub const BLOCKS_DIR: &str = ".blocks";
+pub const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+/// Ranges of more blocks than this are not cached.
+pub const MAX_READ_BLOCKS: u64 = 16;
+
+impl ReadCache {
+    pub fn blocks_prefix(&self, entry: &str) -> String {
+        format!("{}/{}/", BLOCKS_DIR, entry)
+    }
+
+    pub fn block_name(&self, entry: &str, index: u64) -> String {
+        format!("{}{:08}", self.blocks_prefix(entry), index)
+    }
+
+    /// load_blocks adds the cached blocks to the index on start,
+    /// removing blocks or sidecars that lost their other half.
+    pub async fn load_blocks(&self, items: &mut HashMap<String, CacheItem>) -> anyhow::Result<()> {
+        let mut dirs =
+            tokio::fs::read_dir(Path::new(&self.read_cache_dir).join(BLOCKS_DIR)).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            let entry = dir.file_name().to_string_lossy().to_string();
+            let mut count = 0;
+            let mut blocks = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(block) = blocks.next_entry().await? {
+                let name = block.file_name().to_string_lossy().to_string();
+                if let Some(body) = name.strip_suffix(MD_SUFFIX) {
+                    if tokio::fs::metadata(dir.path().join(body)).await.is_err() {
+                        tokio::fs::remove_file(block.path()).await?;
+                    }
+                    continue;
+                }
+                let validated = match tokio::fs::metadata(md_path(&block.path())).await {
+                    Ok(md_stat) => md_stat.modified()?,
+                    Err(_) => {
+                        tokio::fs::remove_file(block.path()).await?;
+                        continue;
+                    }
+                };
+                let size = block.metadata().await?.len();
+                items.insert(
+                    format!("{}{}", self.blocks_prefix(&entry), name),
+                    CacheItem {
+                        size,
+                        validated,
+                        accessed: validated,
+                        pinned: false,
+                    },
+                );
+                count += 1;
+            }
+            if count == 0 {
+                tokio::fs::remove_dir_all(dir.path()).await.ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// get_blocks serves a range of an object from its blocks, fetching the missing ones.
+    /// Returns None for ranges that should be passed to the remote.
+    pub async fn get_blocks(
+        &'static self,
+        i: &GetObjectInput,
+        range: ByteRange,
+    ) -> Option<GetObjectOutput> {
+        let entry = self.to_entry_name(i.bucket(), i.key());
+        let known = self.fresh_block_md(&entry).await;
+        let size = known.as_ref().map(|md| md.content_length as u64);
+        let (first, last) = read_span(range, size)?;
+        let missing = block_indexes(first, last)?
+            .filter(|index| !self.is_fresh(&self.block_name(&entry, *index)))
+            .collect::<Vec<_>>();
+        let md = match (missing.first(), missing.last()) {
+            (Some(m0), Some(m1)) => {
+                let e_tag = known.as_ref().and_then(|md| md.e_tag.as_deref());
+                self.fetch_blocks(i, &entry, *m0, *m1, e_tag).await?
+            }
+            _ => known?,
+        };
+
+        // the range is resolved again now that the size is known
+        let (first, last) = range.resolve(md.content_length as u64).ok()?;
+        let mut ranges = vec![];
+        for index in block_indexes(first, last)? {
+            let (block_md, file, fresh) = self.find_item(&self.block_name(&entry, index)).await?;
+            if !fresh || block_md.e_tag != md.e_tag {
+                return None;
+            }
+            let (from, len) = block_slice(index, first, last);
+            ranges.push((file, from, len));
+        }
+        debug!("Read cache hit: {}/{} {:?}", i.bucket(), i.key(), range);
+        let body = read_ranges_as_stream(ranges);
+        Some(md.to_get_object_range_output(body, first, last))
+    }
+
+    /// fetch_blocks reads the blocks from first to last from the remote and caches them.
+    /// Returns the meta-data of the object, or None if the read failed or the object
+    /// was modified since the cached blocks were fetched (which invalidates them).
+    async fn fetch_blocks(
+        &self,
+        i: &GetObjectInput,
+        entry: &str,
+        first: u64,
+        last: u64,
+        e_tag: Option<&str>,
+    ) -> Option<ObjectMd> {
+        if !self.connectivity.is_online() {
+            return None;
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let res = self
+            .s3_client
+            .get_object()
+            .bucket(i.bucket())
+            .key(i.key())
+            .range(format!(
+                "bytes={}-{}",
+                first * BLOCK_SIZE,
+                (last + 1) * BLOCK_SIZE - 1
+            ))
+            .set_if_match(e_tag.map(String::from))
+            .send()
+            .await;
+        self.connectivity.check(&res);
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                if let SdkError::ServiceError { ref raw, .. } = err {
+                    if raw.http().status().as_u16() == 412 {
+                        self.invalidate(i.bucket(), i.key()).await;
+                    }
+                }
+                debug!("Read cache: fetch blocks {:?} {}", entry, err);
+                return None;
+            }
+        };
+        // a remote that ignores the range returns the whole object
+        let (start, _, size) = match res.content_range() {
+            Some(content_range) => parse_content_range(content_range)?,
+            None => (0, 0, res.content_length() as u64),
+        };
+        if start % BLOCK_SIZE != 0 {
+            return None;
+        }
+        let mut md = ObjectMd::from_client_get_object_output(i.bucket(), i.key(), &res);
+        md.content_length = size as i64;
+
+        let mut body = res.body;
+        let mut index = start / BLOCK_SIZE;
+        let mut buf = Vec::with_capacity(BLOCK_SIZE as usize);
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    debug!("Read cache: fetch blocks {:?} {}", entry, err);
+                    break;
+                }
+            };
+            let mut data = &chunk[..];
+            while !data.is_empty() {
+                let n = data.len().min(BLOCK_SIZE as usize - buf.len());
+                buf.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if buf.len() as u64 == BLOCK_SIZE {
+                    self.commit_block(entry, index, &buf, &md, generation).await;
+                    index += 1;
+                    buf.clear();
+                }
+            }
+        }
+        // the last block of the object is shorter
+        if !buf.is_empty() && index * BLOCK_SIZE + buf.len() as u64 == size {
+            self.commit_block(entry, index, &buf, &md, generation).await;
+        }
+        Some(md)
+    }
+
+    async fn commit_block(
+        &self,
+        entry: &str,
+        index: u64,
+        data: &[u8],
+        md: &ObjectMd,
+        generation: u64,
+    ) {
+        let name = self.block_name(entry, index);
+        let staged = self.staging_dir().join(uuid::Uuid::new_v4().to_string());
+        let res = async {
+            let mut file = tokio::fs::File::create(&staged).await?;
+            file.write_all(data).await?;
+            self.commit(&name, &staged, file, md, data.len() as u64, generation)
+                .await
+        }
+        .await;
+        if let Err(err) = res {
+            warn!("Read cache: commit {:?} {}", name, err);
+        }
+        tokio::fs::remove_file(&staged).await.ok();
+    }
+
+    /// fresh_block_md returns the meta-data of the object from any of its fresh blocks.
+    async fn fresh_block_md(&self, entry: &str) -> Option<ObjectMd> {
+        let prefix = self.blocks_prefix(entry);
+        let name = {
+            let items = self.items.lock().unwrap();
+            items
+                .iter()
+                .find(|(name, item)| name.starts_with(&prefix) && self.is_fresh_item(item))
+                .map(|(name, _)| name.clone())?
+        };
+        let (md, _, _) = self.find_item(&name).await?;
+        Some(md)
+    }
+
+    fn is_fresh(&self, name: &str) -> bool {
+        let items = self.items.lock().unwrap();
+        matches!(items.get(name), Some(item) if self.is_fresh_item(item))
+    }
+}
+
+/// read_span returns the first and last offsets of a range to read from the blocks,
+/// resolved by the size of the object when it is known, or else the whole blocks of
+/// an open range. Returns None for ranges that should be passed to the remote.
+fn read_span(range: ByteRange, size: Option<u64>) -> Option<(u64, u64)> {
+    match (size, range) {
+        (Some(size), _) => range.resolve(size).ok(),
+        (None, ByteRange::FromTo(first, last)) => Some((first, last)),
+        (None, ByteRange::From(first)) => Some((
+            first,
+            (first / BLOCK_SIZE + MAX_READ_BLOCKS) * BLOCK_SIZE - 1,
+        )),
+        (None, ByteRange::Suffix(_)) => None,
+    }
+}
+
+/// block_indexes returns the indexes of the blocks of a span,
+/// or None if it spans more than `MAX_READ_BLOCKS`.
+fn block_indexes(first: u64, last: u64) -> Option<RangeInclusive<u64>> {
+    if last / BLOCK_SIZE - first / BLOCK_SIZE + 1 > MAX_READ_BLOCKS {
+        return None;
+    }
+    Some(first / BLOCK_SIZE..=last / BLOCK_SIZE)
+}
+
+/// block_slice returns the offset and length of the part of a block within a span.
+fn block_slice(index: u64, first: u64, last: u64) -> (u64, u64) {
+    let block_start = index * BLOCK_SIZE;
+    let from = first.max(block_start) - block_start;
+    let to = last.min(block_start + BLOCK_SIZE - 1) - block_start;
+    (from, to - from + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const B: u64 = BLOCK_SIZE;
+
+    #[test]
+    fn read_spans() {
+        assert_eq!(read_span(ByteRange::FromTo(10, 20), None), Some((10, 20)));
+        assert_eq!(
+            read_span(ByteRange::FromTo(10, 20), Some(15)),
+            Some((10, 14))
+        );
+        assert_eq!(
+            read_span(ByteRange::From(B + 1), None),
+            Some((B + 1, (MAX_READ_BLOCKS + 1) * B - 1))
+        );
+        assert_eq!(
+            read_span(ByteRange::From(B + 1), Some(2 * B)),
+            Some((B + 1, 2 * B - 1))
+        );
+        assert_eq!(read_span(ByteRange::From(B), Some(B)), None);
+        assert_eq!(read_span(ByteRange::Suffix(10), None), None);
+        assert_eq!(
+            read_span(ByteRange::Suffix(10), Some(B)),
+            Some((B - 10, B - 1))
+        );
+    }
+
+    #[test]
+    fn block_spans() {
+        assert_eq!(block_indexes(0, 0), Some(0..=0));
+        assert_eq!(block_indexes(B - 1, B), Some(0..=1));
+        assert_eq!(
+            block_indexes(0, MAX_READ_BLOCKS * B - 1),
+            Some(0..=MAX_READ_BLOCKS - 1)
+        );
+        assert_eq!(block_indexes(B - 1, MAX_READ_BLOCKS * B), None);
+        // an open range of an object with no cached blocks reads whole blocks
+        let (first, last) = read_span(ByteRange::From(B + 1), None).unwrap();
+        assert_eq!(block_indexes(first, last), Some(1..=MAX_READ_BLOCKS));
+        assert_eq!(block_slice(0, 10, 3 * B), (10, B - 10));
+        assert_eq!(block_slice(1, 10, 3 * B), (0, B));
+        assert_eq!(block_slice(3, 10, 3 * B), (0, 1));
+        assert_eq!(block_slice(2, 2 * B + 5, 2 * B + 5), (5, 1));
+    }
+}
@@ -0,0 +1,236 @@
+//! Single-flight fills of the read cache.
+//!
+//! When many clients miss the cache for the same object at the same time,
+//! only the first one (the leader) reads the object from the remote, and the others
+//! (followers) wait for it and stream the object from the staged file of the fill
+//! while it is being written. The leader publishes its progress on a watch channel,
+//! so followers read only the bytes that were already written.
+//!
+//! A follower of a fill that fails before it started streaming (e.g. the remote
+//! returned an error, or the object is too big to cache) reads from the remote itself.
+//! Invalidation removes the flight, so reads that start after a write never follow
+//! a fill that started before it.
+
+use crate::object_md::ObjectMd;
+use crate::read_cache::ReadCache;
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{input::GetObjectInput, output::GetObjectOutput};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+
+const FOLLOW_BUF_SIZE: usize = 64 * 1024;
+
+/// FlightState is the progress of a fill, published by the leader.
+#[derive(Debug, Clone, Default)]
+pub struct FlightState {
+    /// The object and the staged file, once the leader got the response
+    pub streaming: Option<(Arc<ObjectMd>, PathBuf)>,
+    /// Number of bytes written to the staged file
+    pub written: u64,
+    /// The whole object was written to the staged file
+    pub done: bool,
+    pub failed: bool,
+}
+
+pub enum Flight {
+    Leader(FlightLeader),
+    Follower(watch::Receiver<FlightState>),
+}
+
+/// FlightLeader is held by the fill of the leader, and fails the flight
+/// for its followers if it is dropped before the fill is done.
+pub struct FlightLeader {
+    read_cache: &'static ReadCache,
+    entry: String,
+    id: u64,
+    state: FlightState,
+    tx: watch::Sender<FlightState>,
+}
+
+impl FlightLeader {
+    pub fn streaming(&mut self, md: &ObjectMd, staged: PathBuf) {
+        self.state.streaming = Some((Arc::new(md.clone()), staged));
+        self.tx.send(self.state.clone()).ok();
+    }
+
+    pub fn written(&mut self, num_bytes: u64) {
+        self.state.written = num_bytes;
+        self.tx.send(self.state.clone()).ok();
+    }
+
+    pub fn done(&mut self) {
+        self.state.done = true;
+        self.tx.send(self.state.clone()).ok();
+    }
+}
+
+impl Drop for FlightLeader {
+    fn drop(&mut self) {
+        if !self.state.done {
+            self.state.failed = true;
+            self.tx.send(self.state.clone()).ok();
+        }
+        let mut flights = self.read_cache.flights.lock().unwrap();
+        if matches!(flights.get(&self.entry), Some((id, _)) if *id == self.id) {
+            flights.remove(&self.entry);
+        }
+    }
+}
+
+impl ReadCache {
+    /// join_flight makes the caller the leader of a new fill of the object,
+    /// or a follower of the fill that is already in flight.
+    pub fn join_flight(&'static self, i: &GetObjectInput) -> Flight {
+        let entry = self.to_entry_name(i.bucket(), i.key());
+        let mut flights = self.flights.lock().unwrap();
+        if let Some((_, rx)) = flights.get(&entry) {
+            return Flight::Follower(rx.clone());
+        }
+        let id = self.flight_ids.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = watch::channel(FlightState::default());
+        flights.insert(entry.clone(), (id, rx));
+        Flight::Leader(FlightLeader {
+            read_cache: self,
+            entry,
+            id,
+            state: FlightState::default(),
+            tx,
+        })
+    }
+
+    /// follow waits for the leader to start streaming and streams the object
+    /// from the staged file. Returns None if the leader failed before streaming.
+    pub async fn follow(
+        &'static self,
+        i: &GetObjectInput,
+        mut rx: watch::Receiver<FlightState>,
+    ) -> Option<GetObjectOutput> {
+        let (md, staged) = loop {
+            {
+                let state = rx.borrow();
+                if state.failed {
+                    return None;
+                }
+                if let Some(streaming) = state.streaming.as_ref() {
+                    break streaming.clone();
+                }
+            }
+            rx.changed().await.ok()?;
+        };
+        let mut file = match tokio::fs::File::open(&staged).await {
+            Ok(file) => file,
+            // the fill was already committed
+            Err(_) => return self.get_object(i).await,
+        };
+        debug!("Read cache follow: {}/{}", i.bucket(), i.key());
+        let (mut tx, client_body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; FOLLOW_BUF_SIZE];
+            let mut sent = 0u64;
+            let mut closed = false;
+            loop {
+                let (written, done, failed) = {
+                    let state = rx.borrow();
+                    (state.written, state.done, state.failed)
+                };
+                if failed {
+                    tx.abort();
+                    return;
+                }
+                if sent < written {
+                    let n = (written - sent).min(FOLLOW_BUF_SIZE as u64) as usize;
+                    match file.read(&mut buf[..n]).await {
+                        Ok(n) if n > 0 => {
+                            sent += n as u64;
+                            let data = bytes::Bytes::copy_from_slice(&buf[..n]);
+                            if tx.send_data(data).await.is_err() {
+                                return;
+                            }
+                        }
+                        _ => {
+                            tx.abort();
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                if done {
+                    return;
+                }
+                if closed {
+                    tx.abort();
+                    return;
+                }
+                // the last state is still read after the leader is gone
+                if rx.changed().await.is_err() {
+                    closed = true;
+                }
+            }
+        });
+        Some(md.to_get_object_output(ByteStream::new(SdkBody::from(client_body))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_cache::tests::test_cache;
+
+    /// lead starts a flight of an entry like join_flight, which needs a request.
+    fn lead(
+        read_cache: &'static ReadCache,
+        entry: &str,
+    ) -> (FlightLeader, watch::Receiver<FlightState>) {
+        let id = read_cache.flight_ids.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = watch::channel(FlightState::default());
+        read_cache
+            .flights
+            .lock()
+            .unwrap()
+            .insert(entry.to_string(), (id, rx.clone()));
+        let leader = FlightLeader {
+            read_cache,
+            entry: entry.to_string(),
+            id,
+            state: FlightState::default(),
+            tx,
+        };
+        (leader, rx)
+    }
+
+    #[tokio::test]
+    async fn dropped_leader_fails_flight() {
+        let read_cache = crate::utils::staticify(test_cache().await);
+        let (mut leader, rx) = lead(read_cache, "a");
+        leader.written(3);
+        assert_eq!(rx.borrow().written, 3);
+        drop(leader);
+        assert!(rx.borrow().failed);
+        assert!(!rx.borrow().done);
+        assert!(read_cache.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn done_leader_keeps_newer_flight() {
+        let read_cache = crate::utils::staticify(test_cache().await);
+        let (mut old, old_rx) = lead(read_cache, "a");
+        // invalidation removes the flight, so the next read leads a new one
+        read_cache.flights.lock().unwrap().remove("a");
+        let (new, _) = lead(read_cache, "a");
+        old.done();
+        drop(old);
+        assert!(old_rx.borrow().done);
+        assert!(!old_rx.borrow().failed);
+        let id = read_cache
+            .flights
+            .lock()
+            .unwrap()
+            .get("a")
+            .map(|(id, _)| *id);
+        assert_eq!(id, Some(new.id));
+    }
+}
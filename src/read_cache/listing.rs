@@ -0,0 +1,160 @@
+//! Listing cached objects while the remote is offline.
+//!
+//! The cached objects stand in for the remote page, and the router merges
+//! the write queue into it like into a remote page (see `write_queue::listing`).
+//! Such listings only have the objects that were read recently,
+//! and object versions cannot be listed offline.
+
+use crate::connectivity::remote_unavailable;
+use crate::object_md::{md_path, ObjectMd};
+use crate::read_cache::blocks::BLOCKS_DIR;
+use crate::read_cache::ReadCache;
+use crate::utils::{is_not_found, to_internal_err};
+use crate::write_queue::listing::{
+    cut_objects_page, list_v2_start, max_keys_or_default, merge_page, queued_object,
+    to_continuation_token,
+};
+use crate::write_queue::parse_entry_name;
+use s3d_smithy_codegen_server_s3::{
+    error::{ListObjectVersionsError, ListObjectsError, ListObjectsV2Error},
+    input::{ListObjectVersionsInput, ListObjectsInput, ListObjectsV2Input},
+    output::{ListObjectVersionsOutput, ListObjectsOutput, ListObjectsV2Output},
+};
+use std::collections::BTreeMap;
+
+impl ReadCache {
+    /// cached_objects returns the objects of the bucket that are cached whole.
+    pub async fn cached_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> anyhow::Result<BTreeMap<String, ObjectMd>> {
+        let entries = self
+            .items
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|name| !name.starts_with(BLOCKS_DIR))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut objects = BTreeMap::new();
+        for entry in entries {
+            let (entry_bucket, key) = match parse_entry_name(&entry) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if entry_bucket != bucket || !key.starts_with(prefix) {
+                continue;
+            }
+            // the item may be evicted since it was listed
+            let md = match ObjectMd::read(&md_path(&self.entry_path(&entry))).await {
+                Ok(md) => md,
+                Err(err) if is_not_found(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            objects.insert(key, md);
+        }
+        Ok(objects)
+    }
+}
+
+pub async fn list_objects_offline(
+    read_cache: Option<&ReadCache>,
+    i: &ListObjectsInput,
+) -> Result<ListObjectsOutput, ListObjectsError> {
+    let prefix = i.prefix().unwrap_or("");
+    let max_keys = max_keys_or_default(i.max_keys());
+    let cached = match read_cache {
+        Some(read_cache) => read_cache
+            .cached_objects(i.bucket(), prefix)
+            .await
+            .map_err(to_internal_err)?,
+        None => BTreeMap::new(),
+    };
+    let items = merge_page(
+        vec![],
+        false,
+        &cached,
+        i.marker().unwrap_or(""),
+        prefix,
+        i.delimiter(),
+        |_, md| queued_object(md),
+    );
+    let (contents, common_prefixes, next_marker) = cut_objects_page(items, max_keys, None);
+    Ok(ListObjectsOutput::builder()
+        .name(i.bucket())
+        .set_prefix(i.prefix().map(String::from))
+        .set_delimiter(i.delimiter().map(String::from))
+        .set_marker(i.marker().map(String::from))
+        .max_keys(max_keys as i32)
+        .is_truncated(next_marker.is_some())
+        .set_next_marker(next_marker)
+        .set_contents(Some(contents))
+        .set_common_prefixes(Some(common_prefixes))
+        .build())
+}
+
+pub async fn list_objects_v2_offline(
+    read_cache: Option<&ReadCache>,
+    i: &ListObjectsV2Input,
+) -> Result<ListObjectsV2Output, ListObjectsV2Error> {
+    let prefix = i.prefix().unwrap_or("");
+    let max_keys = max_keys_or_default(i.max_keys());
+    let start = list_v2_start(i).map_err(to_internal_err)?;
+    let cached = match read_cache {
+        Some(read_cache) => read_cache
+            .cached_objects(i.bucket(), prefix)
+            .await
+            .map_err(to_internal_err)?,
+        None => BTreeMap::new(),
+    };
+    let items = merge_page(
+        vec![],
+        false,
+        &cached,
+        start.as_deref().unwrap_or(""),
+        prefix,
+        i.delimiter(),
+        |_, md| queued_object(md),
+    );
+    let (contents, common_prefixes, next_marker) = cut_objects_page(items, max_keys, None);
+    Ok(ListObjectsV2Output::builder()
+        .name(i.bucket())
+        .set_prefix(i.prefix().map(String::from))
+        .set_delimiter(i.delimiter().map(String::from))
+        .set_continuation_token(i.continuation_token().map(String::from))
+        .set_start_after(i.start_after().map(String::from))
+        .max_keys(max_keys as i32)
+        .key_count((contents.len() + common_prefixes.len()) as i32)
+        .is_truncated(next_marker.is_some())
+        .set_next_continuation_token(next_marker.map(|m| to_continuation_token(&m)))
+        .set_contents(Some(contents))
+        .set_common_prefixes(Some(common_prefixes))
+        .build())
+}
+
+pub async fn list_object_versions_offline(
+    _read_cache: Option<&ReadCache>,
+    _i: &ListObjectVersionsInput,
+) -> Result<ListObjectVersionsOutput, ListObjectVersionsError> {
+    Err(to_internal_err(remote_unavailable()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_cache::tests::{put_item, test_cache};
+
+    #[tokio::test]
+    async fn cached_objects_of_prefix() {
+        let cache = test_cache().await;
+        put_item(&cache, "dir/a", b"a", 0).await;
+        put_item(&cache, "dir/b", b"bb", 0).await;
+        put_item(&cache, "c", b"c", 0).await;
+        let objects = cache.cached_objects("bucket", "dir/").await.unwrap();
+        assert_eq!(objects.keys().collect::<Vec<_>>(), vec!["dir/a", "dir/b"]);
+        assert_eq!(objects["dir/b"].content_length, 2);
+        assert_eq!(cache.cached_objects("bucket", "").await.unwrap().len(), 3);
+        assert!(cache.cached_objects("other", "").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,725 @@
+//! Read cache of objects from the remote.
+//!
+//! Objects that are read through the gateway are written to the cache dir
+//! while they are streamed to the client, with a sidecar meta-data file like
+//! write queue entries (urlencoded `bucket/key` names), and repeated reads are
+//! served from the local copy. The cache is pruned by the least recently used
+//! order when it exceeds its limits.
+//!
+//! Items are served without asking the remote for the TTL since they were fetched
+//! or last validated, and then they are revalidated with a conditional read
+//! (see `revalidate`). Items that were not validated for the max age are not served.
+//!
+//! Byte ranges are served from a cached object, and ranges of objects that are not
+//! cached whole are cached in blocks (see `blocks`), so that reading a small part of
+//! a huge object does not download all of it. Reads that ask for something else than
+//! the current object (versions, conditions, etc.) are passed to the remote,
+//! and writes invalidate the cached key.
+
+use crate::byte_range::ByteRange;
+use crate::config::Limits;
+use crate::connectivity::Connectivity;
+use crate::filter::Filter;
+use crate::object_md::{md_path, ObjectMd, MD_SUFFIX};
+use crate::read_cache::blocks::BLOCKS_DIR;
+use crate::read_cache::flight::{FlightLeader, FlightState};
+use crate::utils::{is_not_found, read_ranges_as_stream, sync_dir};
+use crate::write_queue::is_entry_name;
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{
+    input::{GetObjectInput, HeadObjectInput},
+    output::{GetObjectOutput, HeadObjectOutput},
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+pub mod blocks;
+pub mod flight;
+pub mod listing;
+pub mod pin;
+pub mod prefetch;
+pub mod revalidate;
+
+pub const STAGING_DIR: &str = ".staging";
+
+/// CacheItem is the in-memory index record of a cached object.
+#[derive(Debug, Clone)]
+pub struct CacheItem {
+    pub size: u64,
+    /// When the object was fetched or revalidated with the remote
+    pub validated: SystemTime,
+    /// When the object was last served, for the LRU order
+    pub accessed: SystemTime,
+    /// Pinned items are not evicted, see `pin`
+    pub pinned: bool,
+}
+
+pub struct ReadCache {
+    pub s3_client: &'static aws_sdk_s3::Client,
+    pub connectivity: &'static Connectivity,
+    pub read_cache_dir: String,
+    pub limits: Limits,
+    /// How long items are served before they are revalidated
+    pub ttl: Duration,
+    /// Only the objects that match the filter are cached, see `is_cacheable_key`
+    pub filter: Option<Filter>,
+    /// Objects that match the pin filter are pinned, see `pin`
+    pub pin: Option<Filter>,
+    /// commit_lock is held for any change to the items of the cache dir,
+    /// and by readers that open a body and its sidecar together.
+    pub commit_lock: Mutex<()>,
+    pub items: std::sync::Mutex<HashMap<String, CacheItem>>,
+    /// generation is increased by every invalidation, so that fills which started
+    /// before a write do not commit the data that was read before it.
+    pub generation: AtomicU64,
+    /// flights are the fills in progress by entry, see `flight`.
+    pub flights:
+        std::sync::Mutex<HashMap<String, (u64, tokio::sync::watch::Receiver<FlightState>)>>,
+    pub flight_ids: AtomicU64,
+}
+
+impl ReadCache {
+    pub async fn new(
+        s3_client: &'static aws_sdk_s3::Client,
+        connectivity: &'static Connectivity,
+        read_cache_dir: String,
+        limits: Limits,
+        ttl: Duration,
+        filter: Option<Filter>,
+        pin: Option<Filter>,
+    ) -> anyhow::Result<ReadCache> {
+        let staging = Path::new(&read_cache_dir).join(STAGING_DIR);
+        tokio::fs::create_dir_all(&staging).await?;
+        tokio::fs::create_dir_all(Path::new(&read_cache_dir).join(BLOCKS_DIR)).await?;
+        Ok(ReadCache {
+            s3_client,
+            connectivity,
+            read_cache_dir,
+            limits,
+            ttl,
+            filter,
+            pin,
+            commit_lock: Mutex::new(()),
+            items: std::sync::Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            flights: std::sync::Mutex::new(HashMap::new()),
+            flight_ids: AtomicU64::new(0),
+        })
+    }
+
+    /// start loads the index from the cache dir, and removes staged fills
+    /// and bodies or sidecars that lost their other half in a crash.
+    pub async fn start(&self) -> anyhow::Result<()> {
+        let mut staging = tokio::fs::read_dir(self.staging_dir()).await?;
+        while let Some(staged) = staging.next_entry().await? {
+            tokio::fs::remove_file(staged.path()).await?;
+        }
+        let mut items = HashMap::new();
+        let mut dir = tokio::fs::read_dir(&self.read_cache_dir).await?;
+        while let Some(item) = dir.next_entry().await? {
+            let name = item.file_name().to_string_lossy().to_string();
+            if let Some(entry) = name.strip_suffix(MD_SUFFIX) {
+                if tokio::fs::metadata(self.entry_path(entry)).await.is_err() {
+                    tokio::fs::remove_file(item.path()).await?;
+                }
+                continue;
+            }
+            if !is_entry_name(&name) {
+                continue;
+            }
+            // the sidecar is rewritten on every revalidation
+            let validated = match tokio::fs::metadata(md_path(&item.path())).await {
+                Ok(md_stat) => md_stat.modified()?,
+                Err(_) => {
+                    tokio::fs::remove_file(item.path()).await?;
+                    continue;
+                }
+            };
+            let size = item.metadata().await?.len();
+            let pinned = match ObjectMd::read(&md_path(&item.path())).await {
+                Ok(md) => self.is_pinned(&md).await,
+                Err(_) => false,
+            };
+            items.insert(
+                name,
+                CacheItem {
+                    size,
+                    validated,
+                    accessed: validated,
+                    pinned,
+                },
+            );
+        }
+        self.load_blocks(&mut items).await?;
+        info!(
+            "Read cache: {} items {} bytes",
+            items.len(),
+            items.values().map(|item| item.size).sum::<u64>()
+        );
+        *self.items.lock().unwrap() = items;
+        sync_dir(Path::new(&self.read_cache_dir)).await?;
+        Ok(())
+    }
+
+    /// get_object serves a cached object, or returns None on a miss.
+    /// A stale object is revalidated first, and if it was modified on the remote
+    /// the new object is returned (and replaces the cached one).
+    /// Ranges of objects that are not cached whole are served from blocks.
+    pub async fn get_object(&'static self, i: &GetObjectInput) -> Option<GetObjectOutput> {
+        let range = i.range().and_then(ByteRange::parse);
+        let (md, file, fresh) = match self.find(i.bucket(), i.key()).await {
+            Some(found) => found,
+            None => return self.get_blocks(i, range?).await,
+        };
+        if !fresh {
+            match self.revalidate(i, &md).await {
+                Ok(None) => {}
+                Ok(Some(o)) => return Some(self.fill(i.bucket(), i.key(), o, None)),
+                // the remote went away during the revalidation
+                Err(_) if !self.connectivity.is_online() => {}
+                Err(err) => {
+                    warn!("Read cache: revalidate {}/{} {}", i.bucket(), i.key(), err);
+                    return None;
+                }
+            }
+        }
+        if let Some(range) = range {
+            // an unsatisfiable range is answered by the remote
+            let (first, last) = range.resolve(md.content_length as u64).ok()?;
+            debug!("Read cache hit: {}/{} {:?}", i.bucket(), i.key(), range);
+            let body = read_ranges_as_stream(vec![(file, first, last - first + 1)]);
+            return Some(md.to_get_object_range_output(body, first, last));
+        }
+        let body = match ByteStream::read_from().file(file).build().await {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Read cache: read {}/{} {}", i.bucket(), i.key(), err);
+                return None;
+            }
+        };
+        debug!("Read cache hit: {}/{}", i.bucket(), i.key());
+        Some(md.to_get_object_output(body))
+    }
+
+    /// head_object serves only fresh objects, since a HeadObject of the remote
+    /// costs the same as revalidating.
+    pub async fn head_object(&self, i: &HeadObjectInput) -> Option<HeadObjectOutput> {
+        let (md, _, fresh) = self.find(i.bucket(), i.key()).await?;
+        if !fresh {
+            return None;
+        }
+        debug!("Read cache hit: {}/{}", i.bucket(), i.key());
+        Some(md.to_head_object_output())
+    }
+
+    /// find opens a cached object that is not expired, marks it as accessed,
+    /// and tells if it is still fresh (validated within the TTL).
+    /// While the remote is offline, expired and stale objects are served too.
+    /// Errors are logged and taken as misses, so the read goes to the remote.
+    pub async fn find(&self, bucket: &str, key: &str) -> Option<(ObjectMd, tokio::fs::File, bool)> {
+        self.find_item(&self.to_entry_name(bucket, key)).await
+    }
+
+    /// find_item is `find` for any item of the cache (objects and blocks).
+    pub async fn find_item(&self, entry: &str) -> Option<(ObjectMd, tokio::fs::File, bool)> {
+        let fresh = {
+            let mut items = self.items.lock().unwrap();
+            let item = items.get_mut(entry)?;
+            if self.is_expired(item) && self.connectivity.is_online() {
+                return None;
+            }
+            item.accessed = SystemTime::now();
+            self.is_fresh_item(item)
+        };
+        let path = self.entry_path(entry);
+        let res = async {
+            let _guard = self.commit_lock.lock().await;
+            let file = tokio::fs::File::open(&path).await?;
+            let md = ObjectMd::read(&md_path(&path)).await?;
+            anyhow::Ok((md, file, fresh))
+        }
+        .await;
+        match res {
+            Ok(found) => Some(found),
+            Err(err) => {
+                if !is_not_found(&err) {
+                    warn!("Read cache: open {:?} {}", entry, err);
+                }
+                None
+            }
+        }
+    }
+
+    /// fill returns the output with a body that writes the object to the cache
+    /// while it is streamed to the client. The object is committed to the cache only
+    /// if it was read completely. The read continues even if the client goes away,
+    /// since most of the object was already paid for.
+    /// Partial responses are not filled, see `get_blocks` for ranges.
+    /// The leader of a flight publishes the progress of the fill to its followers.
+    pub fn fill(
+        &'static self,
+        bucket: &str,
+        key: &str,
+        mut o: GetObjectOutput,
+        mut leader: Option<FlightLeader>,
+    ) -> GetObjectOutput {
+        let mut md = ObjectMd::from_get_object_output(bucket, key, &o);
+        let tag_count = o.tag_count();
+        if o.content_range().is_some()
+            || md.content_length < 0
+            || md.content_length as u64 > self.limits.max_size
+        {
+            return o;
+        }
+        let mut body = std::mem::replace(&mut o.body, ByteStream::from_static(b""));
+        let generation = self.generation.load(Ordering::SeqCst);
+        let (mut tx, client_body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let entry = self.to_entry_name(&md.bucket, &md.key);
+            let staged = self.staging_dir().join(uuid::Uuid::new_v4().to_string());
+            let mut file = match tokio::fs::File::create(&staged).await {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    warn!("Read cache: create {:?} {}", staged, err);
+                    None
+                }
+            };
+            if let (Some(leader), Some(_)) = (leader.as_mut(), file.as_ref()) {
+                leader.streaming(&md, staged.clone());
+            }
+            let mut client = Some(tx);
+            let mut num_bytes = 0u64;
+            while let Some(chunk) = body.next().await {
+                let buf = match chunk {
+                    Ok(buf) => buf,
+                    Err(err) => {
+                        warn!("Read cache: read remote {:?} {}", entry, err);
+                        if let Some(tx) = client.take() {
+                            tx.abort();
+                        }
+                        file = None;
+                        break;
+                    }
+                };
+                num_bytes += buf.len() as u64;
+                if let Some(f) = file.as_mut() {
+                    if let Err(err) = f.write_all(&buf).await {
+                        warn!("Read cache: write {:?} {}", staged, err);
+                        file = None;
+                    }
+                }
+                // followers read the staged file, so the data is flushed to it first
+                if let (Some(leader), Some(f)) = (leader.as_mut(), file.as_mut()) {
+                    match f.flush().await {
+                        Ok(()) => leader.written(num_bytes),
+                        Err(err) => {
+                            warn!("Read cache: write {:?} {}", staged, err);
+                            file = None;
+                        }
+                    }
+                }
+                if let Some(tx) = client.as_mut() {
+                    if tx.send_data(buf).await.is_err() {
+                        client = None;
+                    }
+                }
+                if file.is_none() && client.is_none() {
+                    break;
+                }
+            }
+            drop(client);
+            let res = match file {
+                Some(file) if num_bytes == md.content_length as u64 => {
+                    if let Some(leader) = leader.as_mut() {
+                        leader.done();
+                    }
+                    if tag_count > 0 {
+                        if let Err(err) = self.fetch_tags(&mut md).await {
+                            debug!("Read cache: fetch tags {:?} {}", entry, err);
+                        }
+                    }
+                    let included = match &self.filter {
+                        Some(f) => f.matches(&md).await,
+                        None => true,
+                    };
+                    if included {
+                        let size = md.content_length as u64;
+                        self.commit(&entry, &staged, file, &md, size, generation)
+                            .await
+                    } else {
+                        debug!("Read cache: filtered out {:?}", entry);
+                        Ok(())
+                    }
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = res {
+                warn!("Read cache: commit {:?} {}", entry, err);
+            }
+            tokio::fs::remove_file(&staged).await.ok();
+            drop(leader);
+        });
+        o.body = ByteStream::new(SdkBody::from(client_body));
+        o
+    }
+
+    /// is_cacheable_key checks the bucket and key against the read cache filter.
+    /// The rest of the filter is checked when a fill commits, since it needs the object.
+    /// Blocks of ranges are cached for the objects that match by bucket and key.
+    pub fn is_cacheable_key(&self, bucket: &str, key: &str) -> bool {
+        self.filter
+            .as_ref()
+            .map_or(true, |f| f.matches_key(bucket, key))
+    }
+
+    /// commit moves a complete fill into the cache, unless the key was invalidated
+    /// since the fill started, and prunes the cache to make room for it.
+    /// The size is of the staged file, which is a block for items of blocks.
+    async fn commit(
+        &self,
+        entry: &str,
+        staged: &Path,
+        mut file: tokio::fs::File,
+        md: &ObjectMd,
+        size: u64,
+        generation: u64,
+    ) -> anyhow::Result<()> {
+        file.flush().await?;
+        file.sync_all().await?;
+        drop(file);
+        let staged_md = md_path(staged);
+        md.write(&staged_md).await?;
+
+        let _guard = self.commit_lock.lock().await;
+        if self.generation.load(Ordering::SeqCst) != generation {
+            tokio::fs::remove_file(&staged_md).await.ok();
+            return Ok(());
+        }
+        let (victims, fits) = self.make_room(entry, size);
+        for victim in victims {
+            debug!("Read cache: evict {:?}", victim);
+            self.remove_files(&victim).await;
+        }
+        if !fits {
+            debug!("Read cache: no room for {:?} next to pinned items", entry);
+            tokio::fs::remove_file(&staged_md).await.ok();
+            return Ok(());
+        }
+        let path = self.entry_path(entry);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::rename(&staged_md, md_path(&path)).await?;
+        tokio::fs::rename(staged, &path).await?;
+        let now = SystemTime::now();
+        let pinned = !entry.starts_with(BLOCKS_DIR) && self.is_pinned(md).await;
+        self.items.lock().unwrap().insert(
+            entry.to_string(),
+            CacheItem {
+                size,
+                validated: now,
+                accessed: now,
+                pinned,
+            },
+        );
+        sync_dir(Path::new(&self.read_cache_dir)).await?;
+        debug!("Read cache fill: {:?} {} bytes", entry, size);
+        Ok(())
+    }
+
+    /// make_room removes from the index the expired items and then the least
+    /// recently used items, until an item of the given size fits in the limits.
+    /// Pinned items are never removed, except when replaced by the new fill.
+    /// Returns the removed entries, for the caller to remove their files,
+    /// and whether the item fits.
+    fn make_room(&self, entry: &str, size: u64) -> (Vec<String>, bool) {
+        let mut items = self.items.lock().unwrap();
+        let mut victims = vec![];
+        // the item is replaced by the new fill
+        if items.remove(entry).is_some() {
+            victims.push(entry.to_string());
+        }
+        let expired = items
+            .iter()
+            .filter(|(_, item)| !item.pinned && self.is_expired(item))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in expired {
+            items.remove(&name);
+            victims.push(name);
+        }
+        let mut total = items.values().map(|item| item.size).sum::<u64>();
+        let fits = |total: u64, count: usize| {
+            total + size <= self.limits.max_size && count as u64 + 1 <= self.limits.max_files
+        };
+        while !fits(total, items.len()) {
+            let lru = items
+                .iter()
+                .filter(|(_, item)| !item.pinned)
+                .min_by_key(|(_, item)| item.accessed)
+                .map(|(name, _)| name.clone());
+            let lru = match lru {
+                Some(lru) => lru,
+                None => break,
+            };
+            total -= items.remove(&lru).unwrap().size;
+            victims.push(lru);
+        }
+        let fits = fits(total, items.len());
+        (victims, fits)
+    }
+
+    /// invalidate removes a key from the cache when it is written or deleted,
+    /// together with its blocks.
+    pub async fn invalidate(&self, bucket: &str, key: &str) {
+        let entry = self.to_entry_name(bucket, key);
+        let blocks_prefix = self.blocks_prefix(&entry);
+        let _guard = self.commit_lock.lock().await;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.flights.lock().unwrap().remove(&entry);
+        let removed = {
+            let mut items = self.items.lock().unwrap();
+            let removed = items
+                .keys()
+                .filter(|name| **name == entry || name.starts_with(&blocks_prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+            for name in removed.iter() {
+                items.remove(name);
+            }
+            removed
+        };
+        for name in removed {
+            debug!("Read cache: invalidate {:?}", name);
+            self.remove_files(&name).await;
+        }
+    }
+
+    /// remove_files removes the body and then the sidecar, which is the order
+    /// that `start` expects after a crash. The caller must hold the commit lock.
+    async fn remove_files(&self, entry: &str) {
+        let path = self.entry_path(entry);
+        for p in [path.clone(), md_path(&path)] {
+            if let Err(err) = tokio::fs::remove_file(&p).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Read cache: remove {:?} {}", p, err);
+                }
+            }
+        }
+    }
+
+    /// is_fresh_item checks if the item was validated within the TTL.
+    /// While the remote is offline every item is served as fresh,
+    /// since it cannot be revalidated.
+    pub fn is_fresh_item(&self, item: &CacheItem) -> bool {
+        !self.connectivity.is_online()
+            || matches!(item.validated.elapsed(), Ok(age) if age <= self.ttl)
+    }
+
+    /// is_expired checks if the item was not validated for the max age.
+    pub fn is_expired(&self, item: &CacheItem) -> bool {
+        match item.validated.elapsed() {
+            Ok(age) => age > self.limits.max_age,
+            Err(_) => false,
+        }
+    }
+
+    pub fn to_entry_name(&self, bucket: &str, key: &str) -> String {
+        urlencoding::encode(&format!("{}/{}", bucket, key)).into_owned()
+    }
+
+    pub fn entry_path(&self, entry: &str) -> PathBuf {
+        Path::new(&self.read_cache_dir).join(entry)
+    }
+
+    pub fn staging_dir(&self) -> PathBuf {
+        Path::new(&self.read_cache_dir).join(STAGING_DIR)
+    }
+}
+
+/// is_cacheable_get checks that the request reads the whole current object
+/// with no conditions or response overrides, so that it can be served from the cache.
+/// Ranges are served from whole cached objects or from blocks.
+pub fn is_cacheable_get(i: &GetObjectInput) -> bool {
+    i.part_number() == 0
+        && i.version_id().is_none()
+        && i.if_match().is_none()
+        && i.if_none_match().is_none()
+        && i.if_modified_since().is_none()
+        && i.if_unmodified_since().is_none()
+        && i.sse_customer_algorithm().is_none()
+        && i.response_cache_control().is_none()
+        && i.response_content_disposition().is_none()
+        && i.response_content_encoding().is_none()
+        && i.response_content_language().is_none()
+        && i.response_content_type().is_none()
+        && i.response_expires().is_none()
+        && i.checksum_mode().is_none()
+}
+
+/// is_cacheable_head is the same as `is_cacheable_get` for HeadObject.
+pub fn is_cacheable_head(i: &HeadObjectInput) -> bool {
+    i.range().is_none()
+        && i.part_number() == 0
+        && i.version_id().is_none()
+        && i.if_match().is_none()
+        && i.if_none_match().is_none()
+        && i.if_modified_since().is_none()
+        && i.if_unmodified_since().is_none()
+        && i.sse_customer_algorithm().is_none()
+        && i.checksum_mode().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_smithy_http::result::SdkError;
+
+    fn test_client() -> &'static aws_sdk_s3::Client {
+        crate::utils::staticify(aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .region(aws_sdk_s3::Region::new("s3d"))
+                .build(),
+        ))
+    }
+
+    /// test_cache creates an empty cache of 10 bytes and 3 items in a new temp dir.
+    pub async fn test_cache() -> ReadCache {
+        let dir = std::env::temp_dir().join(format!("s3d-test-{}", uuid::Uuid::new_v4()));
+        let limits = Limits {
+            max_size: 10,
+            max_files: 3,
+            max_age: Duration::from_secs(3600),
+        };
+        ReadCache::new(
+            test_client(),
+            crate::utils::staticify(Connectivity::new(test_client())),
+            dir.to_str().unwrap().to_string(),
+            limits,
+            Duration::from_secs(60),
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// put_item commits an object of the bucket "bucket" like a fill
+    /// that started at the given generation.
+    pub async fn put_item(cache: &ReadCache, key: &str, data: &[u8], generation: u64) {
+        let staged = cache.staging_dir().join(uuid::Uuid::new_v4().to_string());
+        let mut file = tokio::fs::File::create(&staged).await.unwrap();
+        file.write_all(data).await.unwrap();
+        let md = ObjectMd {
+            bucket: "bucket".to_string(),
+            key: key.to_string(),
+            content_length: data.len() as i64,
+            ..ObjectMd::default()
+        };
+        let entry = cache.to_entry_name("bucket", key);
+        cache
+            .commit(&entry, &staged, file, &md, data.len() as u64, generation)
+            .await
+            .unwrap();
+        tokio::fs::remove_file(&staged).await.ok();
+    }
+
+    fn item(size: u64, accessed: u64) -> CacheItem {
+        CacheItem {
+            size,
+            validated: SystemTime::now(),
+            accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(accessed),
+            pinned: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_find_invalidate() {
+        let cache = test_cache().await;
+        put_item(&cache, "key", b"data", 0).await;
+        let (md, _, fresh) = cache.find("bucket", "key").await.unwrap();
+        assert_eq!(md.content_length, 4);
+        assert!(fresh);
+        cache.invalidate("bucket", "key").await;
+        assert!(cache.find("bucket", "key").await.is_none());
+        assert!(!cache
+            .entry_path(&cache.to_entry_name("bucket", "key"))
+            .exists());
+        // a fill that started before the invalidation is not committed
+        put_item(&cache, "key", b"data", 0).await;
+        assert!(cache.find("bucket", "key").await.is_none());
+        put_item(
+            &cache,
+            "key",
+            b"data",
+            cache.generation.load(Ordering::SeqCst),
+        )
+        .await;
+        assert!(cache.find("bucket", "key").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_and_expired_items() {
+        let cache = test_cache().await;
+        put_item(&cache, "key", b"data", 0).await;
+        let entry = cache.to_entry_name("bucket", "key");
+        let set_age = |secs| {
+            let mut items = cache.items.lock().unwrap();
+            items.get_mut(&entry).unwrap().validated =
+                SystemTime::now() - Duration::from_secs(secs);
+        };
+        set_age(120);
+        let (_, _, fresh) = cache.find("bucket", "key").await.unwrap();
+        assert!(!fresh);
+        set_age(7200);
+        assert!(cache.find("bucket", "key").await.is_none());
+        // expired items are still served while the remote is offline
+        let unreachable: Result<(), SdkError<std::io::Error>> =
+            Err(SdkError::TimeoutError("timeout".into()));
+        cache.connectivity.check(&unreachable);
+        let (_, _, fresh) = cache.find("bucket", "key").await.unwrap();
+        assert!(!fresh);
+    }
+
+    #[tokio::test]
+    async fn make_room_evicts_expired_then_lru() {
+        let cache = test_cache().await;
+        let mut expired = item(1, 4);
+        expired.validated = SystemTime::now() - Duration::from_secs(7200);
+        cache.items.lock().unwrap().extend([
+            ("a".to_string(), item(4, 3)),
+            ("b".to_string(), item(4, 1)),
+            ("c".to_string(), item(1, 2)),
+            ("expired".to_string(), expired),
+        ]);
+        assert_eq!(
+            cache.make_room("d", 1),
+            (vec!["expired".to_string(), "b".to_string()], true)
+        );
+        // a replaced item is removed first, and then as many items as needed
+        assert_eq!(
+            cache.make_room("a", 10),
+            (vec!["a".to_string(), "c".to_string()], true)
+        );
+    }
+
+    #[tokio::test]
+    async fn make_room_keeps_pinned() {
+        let cache = test_cache().await;
+        let mut pinned = item(8, 0);
+        pinned.pinned = true;
+        cache.items.lock().unwrap().extend([
+            ("pinned".to_string(), pinned),
+            ("a".to_string(), item(1, 1)),
+        ]);
+        assert_eq!(cache.make_room("b", 1), (vec![], true));
+        assert_eq!(cache.make_room("b", 2), (vec!["a".to_string()], true));
+        assert_eq!(cache.make_room("b", 3), (vec![], false));
+    }
+}
@@ -0,0 +1,116 @@
+//! Pinned objects of the read cache.
+//!
+//! Pinned objects are never evicted to make room for other objects, so that
+//! datasets that are needed offline stay in the cache. An object is pinned by
+//! the `s3d.pin=true` tag, or by matching the `S3D_READ_CACHE_PIN` filter
+//! (see `filter`), such as `bucket`, `bucket/prefix*` or `bucket[tag:dataset]`.
+//!
+//! Only objects that are cached whole are pinned, and pinned objects are still
+//! revalidated and replaced when they change on the remote, or when they are
+//! written through s3d.
+
+use crate::object_md::{md_path, ObjectMd};
+use crate::read_cache::ReadCache;
+
+pub const PIN_TAG: &str = "s3d.pin";
+
+impl ReadCache {
+    /// is_pinned checks the pin tag and the pin filter of an object.
+    pub async fn is_pinned(&self, md: &ObjectMd) -> bool {
+        if md.tags.get(PIN_TAG).map(String::as_str) == Some("true") {
+            return true;
+        }
+        match &self.pin {
+            Some(f) => f.matches(md).await,
+            None => false,
+        }
+    }
+
+    /// fetch_tags reads the tags of an object that is being filled,
+    /// since they are not returned by GetObject but they can pin it.
+    pub async fn fetch_tags(&self, md: &mut ObjectMd) -> anyhow::Result<()> {
+        let res = self
+            .s3_client
+            .get_object_tagging()
+            .bucket(&md.bucket)
+            .key(&md.key)
+            .send()
+            .await?;
+        md.tags = res
+            .tag_set()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|t| Some((t.key()?.to_string(), t.value()?.to_string())))
+            .collect();
+        Ok(())
+    }
+
+    /// refresh_tags reads the tags of a cached object again after they were changed
+    /// through s3d, which pins or unpins it. The tags are fetched from the remote
+    /// without holding the commit lock, which is taken only to apply them, unless the
+    /// object was replaced in the meantime (a new fill fetches its own tags).
+    pub async fn refresh_tags(&self, bucket: &str, key: &str) {
+        let entry = self.to_entry_name(bucket, key);
+        let path = md_path(&self.entry_path(&entry));
+        if !self.items.lock().unwrap().contains_key(&entry) {
+            return;
+        }
+        let res = async {
+            let mut md = ObjectMd::read(&path).await?;
+            let (e_tag, last_modified) = (md.e_tag.clone(), md.last_modified);
+            self.fetch_tags(&mut md).await?;
+            let _guard = self.commit_lock.lock().await;
+            if !self.items.lock().unwrap().contains_key(&entry) {
+                return Ok(None);
+            }
+            let cached = ObjectMd::read(&path).await?;
+            if cached.e_tag != e_tag || cached.last_modified != last_modified {
+                return Ok(None);
+            }
+            if cached.tags != md.tags {
+                let staged_md = md_path(&self.staging_dir().join(uuid::Uuid::new_v4().to_string()));
+                md.write(&staged_md).await?;
+                tokio::fs::rename(&staged_md, &path).await?;
+            }
+            let pinned = self.is_pinned(&md).await;
+            if let Some(item) = self.items.lock().unwrap().get_mut(&entry) {
+                item.pinned = pinned;
+            }
+            anyhow::Ok(Some(()))
+        }
+        .await;
+        match res {
+            Ok(Some(())) => {}
+            Ok(None) => debug!("Read cache: refresh tags {:?} replaced", entry),
+            Err(err) => warn!("Read cache: refresh tags {:?} {}", entry, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::read_cache::tests::test_cache;
+
+    fn md(bucket: &str, key: &str) -> ObjectMd {
+        ObjectMd {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_by_tag_or_filter() {
+        let mut cache = test_cache().await;
+        cache.pin = Some(Filter::parse("data/models/*").unwrap());
+        assert!(cache.is_pinned(&md("data", "models/a")).await);
+        assert!(!cache.is_pinned(&md("data", "raw/a")).await);
+        let mut tagged = md("data", "raw/a");
+        tagged.tags.insert(PIN_TAG.to_string(), "true".to_string());
+        assert!(cache.is_pinned(&tagged).await);
+        tagged.tags.insert(PIN_TAG.to_string(), "false".to_string());
+        assert!(!cache.is_pinned(&tagged).await);
+    }
+}
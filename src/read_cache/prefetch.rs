@@ -0,0 +1,129 @@
+//! Prefetching objects into the read cache.
+//!
+//! The prefetch job warms the cache with the objects of the configured
+//! `bucket/prefix` targets (`S3D_READ_CACHE_PREFETCH`), so that edge sites
+//! can preload datasets before they go offline. It runs on start and then
+//! periodically, and fetches only the objects that are not fresh in the cache,
+//! so later runs also refresh the objects that changed on the remote.
+//!
+//! A target is fetched up to the max size of the cache, since anything beyond it
+//! would evict the objects that were just fetched.
+
+use crate::codegen_include::conv_from_client_get_object_output;
+use crate::read_cache::ReadCache;
+use crate::utils::parse_bucket_and_prefix;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+impl ReadCache {
+    pub fn start_prefetch(&'static self, targets: Vec<(String, String)>, interval: Duration) {
+        if targets.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                for (bucket, prefix) in targets.iter() {
+                    if let Err(err) = self.prefetch(bucket, prefix).await {
+                        warn!("Read cache prefetch {}/{}: {}", bucket, prefix, err);
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    /// prefetch fetches the objects under the prefix that are not fresh in the cache.
+    pub async fn prefetch(&'static self, bucket: &str, prefix: &str) -> anyhow::Result<()> {
+        info!("Read cache prefetch {}/{} ...", bucket, prefix);
+        let mut token = None;
+        let mut total = 0u64;
+        let mut count = 0u64;
+        loop {
+            if !self.connectivity.is_online() {
+                info!(
+                    "Read cache prefetch {}/{}: remote is offline",
+                    bucket, prefix
+                );
+                return Ok(());
+            }
+            let res = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_continuation_token(token)
+                .send()
+                .await;
+            self.connectivity.check(&res);
+            let res = res?;
+            for o in res.contents().unwrap_or_default() {
+                let (key, size) = match o.key() {
+                    Some(key) => (key, o.size().max(0) as u64),
+                    None => continue,
+                };
+                if size > self.limits.max_size {
+                    continue;
+                }
+                total += size;
+                if total > self.limits.max_size {
+                    warn!(
+                        "Read cache prefetch {}/{}: stopped at the cache max size",
+                        bucket, prefix
+                    );
+                    return Ok(());
+                }
+                if matches!(self.find(bucket, key).await, Some((_, _, true))) {
+                    continue;
+                }
+                match self.prefetch_object(bucket, key).await {
+                    Ok(()) => count += 1,
+                    Err(err) => warn!("Read cache prefetch {}/{}: {}", bucket, key, err),
+                }
+            }
+            if !res.is_truncated() {
+                break;
+            }
+            token = res.next_continuation_token().map(String::from);
+        }
+        info!(
+            "Read cache prefetch {}/{}: fetched {} objects",
+            bucket, prefix, count
+        );
+        Ok(())
+    }
+
+    /// prefetch_object reads an object through a fill, which commits it to the cache.
+    async fn prefetch_object(&'static self, bucket: &str, key: &str) -> anyhow::Result<()> {
+        let res = self
+            .s3_client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await;
+        self.connectivity.check(&res);
+        let o = conv_from_client_get_object_output(res?);
+        let mut o = self.fill(bucket, key, o, None);
+        while let Some(chunk) = o.body.next().await {
+            chunk?;
+        }
+        debug!("Read cache prefetch: {}/{}", bucket, key);
+        Ok(())
+    }
+}
+
+/// parse_prefetch_targets parses the comma separated `bucket[/prefix]` targets.
+pub fn parse_prefetch_targets(targets: &str) -> anyhow::Result<Vec<(String, String)>> {
+    targets
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            let (bucket, prefix) = parse_bucket_and_prefix(t)?;
+            if bucket.is_empty() {
+                anyhow::bail!("Invalid read cache prefetch target {:?}", t);
+            }
+            Ok((bucket, prefix))
+        })
+        .collect()
+}
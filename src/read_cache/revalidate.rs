@@ -0,0 +1,147 @@
+//! Revalidation of stale read cache items.
+//!
+//! A stale item is revalidated with a conditional GetObject of the remote -
+//! If-None-Match with the cached ETag, or If-Modified-Since when there is no ETag.
+//! A 304 response means the cached copy is still current, so only the headers
+//! that can change without changing the data are updated in the sidecar,
+//! which also restarts the TTL. Otherwise the response has the new object
+//! (or the requested range of it), which is served and replaces the cached copy
+//! in a single request.
+
+use crate::codegen_include::conv_from_client_get_object_output;
+use crate::object_md::{md_path, ObjectMd};
+use crate::read_cache::ReadCache;
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use s3d_smithy_codegen_server_s3::{input::GetObjectInput, output::GetObjectOutput};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+impl ReadCache {
+    /// revalidate returns None if the cached object was not modified on the remote,
+    /// or the new object if it was. An object that was deleted on the remote
+    /// is removed from the cache and returned as an error.
+    pub async fn revalidate(
+        &self,
+        i: &GetObjectInput,
+        md: &ObjectMd,
+    ) -> anyhow::Result<Option<GetObjectOutput>> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let req = self
+            .s3_client
+            .get_object()
+            .bucket(i.bucket())
+            .key(i.key())
+            .set_range(i.range().map(String::from));
+        let req = match md.e_tag.as_deref() {
+            Some(e_tag) => req.if_none_match(e_tag),
+            None => req.if_modified_since(md.last_modified_time()),
+        };
+        let res = req.send().await;
+        self.connectivity.check(&res);
+        match res {
+            Ok(o) => {
+                debug!("Read cache revalidate: {}/{} modified", i.bucket(), i.key());
+                // a partial response does not replace the cached copy
+                self.invalidate(i.bucket(), i.key()).await;
+                Ok(Some(conv_from_client_get_object_output(o)))
+            }
+            Err(SdkError::ServiceError { err, raw }) => {
+                let status = raw.http().status().as_u16();
+                if status == 304 {
+                    debug!(
+                        "Read cache revalidate: {}/{} not modified",
+                        i.bucket(),
+                        i.key()
+                    );
+                    let mut md = md.clone();
+                    update_from_not_modified(&mut md, raw.http().headers());
+                    self.validated(i.bucket(), i.key(), &md, generation).await?;
+                    return Ok(None);
+                }
+                if status == 404 || err.code() == Some("NoSuchKey") {
+                    self.invalidate(i.bucket(), i.key()).await;
+                }
+                Err(SdkError::ServiceError { err, raw }.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// validated rewrites the sidecar of a revalidated item and restarts its TTL,
+    /// unless the item was invalidated during the revalidation.
+    async fn validated(
+        &self,
+        bucket: &str,
+        key: &str,
+        md: &ObjectMd,
+        generation: u64,
+    ) -> anyhow::Result<()> {
+        let entry = self.to_entry_name(bucket, key);
+        let staged_md = md_path(&self.staging_dir().join(uuid::Uuid::new_v4().to_string()));
+        md.write(&staged_md).await?;
+        let _guard = self.commit_lock.lock().await;
+        let exists = self.items.lock().unwrap().contains_key(&entry);
+        if !exists || self.generation.load(Ordering::SeqCst) != generation {
+            tokio::fs::remove_file(&staged_md).await.ok();
+            return Ok(());
+        }
+        tokio::fs::rename(&staged_md, md_path(&self.entry_path(&entry))).await?;
+        if let Some(item) = self.items.lock().unwrap().get_mut(&entry) {
+            item.validated = SystemTime::now();
+        }
+        Ok(())
+    }
+}
+
+/// update_from_not_modified updates the headers that a 304 response returns,
+/// which S3 (and http caches) allow to change without changing the data.
+fn update_from_not_modified(md: &mut ObjectMd, headers: &hyper::HeaderMap) {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    if let Some(e_tag) = header("etag") {
+        md.e_tag = Some(e_tag);
+    }
+    if let Some(cache_control) = header("cache-control") {
+        md.cache_control = Some(cache_control);
+    }
+    if let Some(expires) = header("expires") {
+        if let Ok(t) = chrono::DateTime::parse_from_rfc2822(&expires) {
+            md.expires = Some(t.timestamp());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_modified_headers() {
+        let mut md = ObjectMd {
+            e_tag: Some("\"1\"".to_string()),
+            cache_control: Some("max-age=60".to_string()),
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("etag", "\"2\"".parse().unwrap());
+        headers.insert("expires", "Thu, 01 Jan 2026 00:00:00 GMT".parse().unwrap());
+        update_from_not_modified(&mut md, &headers);
+        assert_eq!(md.e_tag.as_deref(), Some("\"2\""));
+        assert_eq!(md.cache_control.as_deref(), Some("max-age=60"));
+        assert_eq!(md.expires, Some(1767225600));
+
+        headers.insert("cache-control", "no-cache".parse().unwrap());
+        headers.insert("expires", "0".parse().unwrap());
+        headers.insert("content-type", "image/png".parse().unwrap());
+        update_from_not_modified(&mut md, &headers);
+        assert_eq!(md.cache_control.as_deref(), Some("no-cache"));
+        assert_eq!(md.expires, Some(1767225600));
+        assert_eq!(md.content_type.as_deref(), Some("text/plain"));
+    }
+}
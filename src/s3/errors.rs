@@ -0,0 +1,307 @@
+//! S3 errors that are not modeled for every operation.
+//!
+//! The smithy model defines only a few errors per operation (PutObject has none),
+//! so errors like BadDigest are returned as InternalServerError, and the `S3Error`
+//! is kept aside for the request by `to_internal_err` (see `record_error`),
+//! so that `fix_error_response` rewrites the http response to the status and code
+//! that S3 clients expect.
+//!
+//! Errors from the remote are carried the same way by `to_gateway_err`,
+//! so clients of gateway ops get the real error of the remote.
+
+use crate::utils::to_internal_err;
+use aws_smithy_http::result::SdkError;
+use aws_smithy_http_server::body::{boxed, BoxBody};
+use aws_smithy_types::retry::ProvideErrorKind;
+use hyper::{header, Response, StatusCode};
+use s3d_smithy_codegen_server_s3::error::InternalServerError;
+use std::cell::RefCell;
+use std::future::Future;
+
+/// S3 error codes that s3d returns, and their http status.
+pub const S3_ERROR_CODES: &[(&str, u16)] = &[
+    ("AccessDenied", 403),
+    ("BadDigest", 400),
+    ("BadRequest", 400),
+    ("BucketAlreadyExists", 409),
+    ("BucketAlreadyOwnedByYou", 409),
+    ("BucketNotEmpty", 409),
+    ("EntityTooLarge", 400),
+    ("EntityTooSmall", 400),
+    ("ExpiredToken", 400),
+    ("InvalidAccessKeyId", 403),
+    ("InvalidArgument", 400),
+    ("InvalidBucketName", 400),
+    ("InvalidDigest", 400),
+    ("InvalidObjectState", 403),
+    ("InvalidPart", 400),
+    ("InvalidPartNumber", 416),
+    ("InvalidPartOrder", 400),
+    ("InvalidRange", 416),
+    ("InvalidRequest", 400),
+    ("InvalidTag", 400),
+    ("KeyTooLongError", 400),
+    ("MalformedXML", 400),
+    ("MethodNotAllowed", 405),
+    ("NoSuchBucket", 404),
+    ("NoSuchKey", 404),
+    ("NoSuchTagSet", 404),
+    ("NoSuchUpload", 404),
+    ("NotFound", 404),
+    ("NotImplemented", 501),
+    ("NotModified", 304),
+    ("PreconditionFailed", 412),
+    ("RequestTimeout", 400),
+    ("ServiceUnavailable", 503),
+    ("SignatureDoesNotMatch", 403),
+    ("SlowDown", 503),
+];
+
+/// find_error_code returns the static code from the table, if known.
+pub fn find_error_code(code: &str) -> Option<&'static str> {
+    S3_ERROR_CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(c, _)| *c)
+}
+
+/// error_code_for_status is used for error responses without a body (e.g HeadObject).
+pub fn error_code_for_status(status: u16) -> Option<&'static str> {
+    match status {
+        304 => Some("NotModified"),
+        403 => Some("AccessDenied"),
+        404 => Some("NotFound"),
+        412 => Some("PreconditionFailed"),
+        503 => Some("ServiceUnavailable"),
+        _ => None,
+    }
+}
+
+/// S3Error is an error with an S3 error code, to be used with anyhow
+/// and converted to the operation error with `to_internal_err`.
+#[derive(Debug, Clone)]
+pub struct S3Error {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl S3Error {
+    pub fn new<M: ToString>(code: &'static str, message: M) -> S3Error {
+        S3Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for S3Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for S3Error {}
+
+/// to_gateway_err converts an error of a remote call to the op error,
+/// keeping the S3 error code of service errors so that the client gets the same error.
+pub fn to_gateway_err<E, T>(err: SdkError<E>) -> T
+where
+    E: ProvideErrorKind + std::error::Error + 'static,
+    T: From<InternalServerError>,
+{
+    if let SdkError::ServiceError {
+        err: ref service_err,
+        ref raw,
+    } = err
+    {
+        let status = raw.http().status().as_u16();
+        let code = service_err
+            .code()
+            .and_then(find_error_code)
+            .or_else(|| error_code_for_status(status));
+        if let Some(code) = code {
+            return to_internal_err(S3Error::new(code, service_err));
+        }
+    }
+    to_internal_err(err)
+}
+
+/// error_response builds the standard S3 error response of an error.
+pub fn error_response(err: &S3Error) -> Response<BoxBody> {
+    let status = S3_ERROR_CODES
+        .iter()
+        .find(|(code, _)| *code == err.code)
+        .map_or(StatusCode::INTERNAL_SERVER_ERROR, |(_, status)| {
+            StatusCode::from_u16(*status).unwrap()
+        });
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(boxed(hyper::Body::from(to_error_xml(
+            err.code,
+            &err.message,
+        ))))
+        .unwrap()
+}
+
+fn to_error_xml(code: &str, message: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <Error><Code>{}</Code><Message>{}</Message></Error>",
+        xml_escape(code),
+        xml_escape(message)
+    )
+}
+
+/// xml_escape escapes text for an xml element, since messages can carry
+/// keys and remote errors that have markup characters.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+tokio::task_local! {
+    static REQUEST_ERROR: RefCell<Option<S3Error>>;
+}
+
+/// record_error keeps the S3 error of an internal error that an op returns,
+/// or forgets the kept error for other errors, so that the response is fixed
+/// with the error of the last conversion. Errors outside of `scope_errors` are not kept.
+pub fn record_error(err: Option<&S3Error>) {
+    REQUEST_ERROR
+        .try_with(|kept| *kept.borrow_mut() = err.cloned())
+        .ok();
+}
+
+/// scope_errors runs the serving of a request, and returns its output
+/// with the S3 error that was kept by `record_error`, if any.
+pub async fn scope_errors<F: Future>(serve: F) -> (F::Output, Option<S3Error>) {
+    REQUEST_ERROR
+        .scope(RefCell::new(None), async {
+            let output = serve.await;
+            (output, REQUEST_ERROR.with(|kept| kept.borrow_mut().take()))
+        })
+        .await
+}
+
+/// fix_error_response replaces an internal error response with the standard
+/// S3 error response of the S3 error that the request failed with.
+pub fn fix_error_response(res: Response<BoxBody>, err: Option<S3Error>) -> Response<BoxBody> {
+    match err {
+        Some(err) if res.status() == StatusCode::INTERNAL_SERVER_ERROR => {
+            let (mut parts, _) = res.into_parts();
+            let fixed = error_response(&err);
+            parts.status = fixed.status();
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/xml"),
+            );
+            Response::from_parts(parts, fixed.into_body())
+        }
+        _ => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal_error() -> Response<BoxBody> {
+        let body = "<ErrorResponse><Error><Code>InternalServerError</Code>\
+                    <Message>disk full</Message></Error></ErrorResponse>";
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(boxed(hyper::Body::from(body)))
+            .unwrap()
+    }
+
+    async fn body_text(res: Response<BoxBody>) -> String {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(find_error_code("NoSuchKey"), Some("NoSuchKey"));
+        assert_eq!(find_error_code("NoSuchThing"), None);
+        assert_eq!(error_code_for_status(404), Some("NotFound"));
+        assert_eq!(error_code_for_status(412), Some("PreconditionFailed"));
+        // SlowDown is only used when the remote says so
+        assert_eq!(error_code_for_status(503), Some("ServiceUnavailable"));
+        assert_eq!(error_code_for_status(500), None);
+    }
+
+    #[tokio::test]
+    async fn fix_internal_errors() {
+        let err = S3Error::new("NoSuchKey", "The key <a&b> does not exist.");
+        let res = fix_error_response(internal_error(), Some(err.clone()));
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/xml");
+        assert!(res.headers().get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(
+            body_text(res).await,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Error><Code>NoSuchKey</Code>\
+             <Message>The key &lt;a&amp;b&gt; does not exist.</Message></Error>"
+        );
+        // internal errors without an S3 error are kept as is
+        let res = fix_error_response(internal_error(), None);
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body_text(res)
+            .await
+            .contains("<Message>disk full</Message>"));
+        let ok = Response::builder()
+            .status(StatusCode::OK)
+            .body(boxed(hyper::Body::from("data")))
+            .unwrap();
+        let res = fix_error_response(ok, Some(err));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await, "data");
+    }
+
+    #[tokio::test]
+    async fn recorded_errors() {
+        let convert = |err: anyhow::Error| -> InternalServerError { to_internal_err(err) };
+        let ((), err) = scope_errors(async {
+            convert(S3Error::new("NoSuchKey", "gone").into());
+        })
+        .await;
+        assert_eq!(
+            err.map(|err| (err.code, err.message)),
+            Some(("NoSuchKey", "gone".to_string()))
+        );
+        // the last converted error is the one returned by the op
+        let ((), err) = scope_errors(async {
+            convert(S3Error::new("NoSuchKey", "gone").into());
+            convert(anyhow::anyhow!("disk full"));
+        })
+        .await;
+        assert!(err.is_none());
+        // errors outside of a request are not kept
+        convert(S3Error::new("NoSuchKey", "gone").into());
+    }
+
+    #[test]
+    fn escape() {
+        let text = "a&b <c> \"d\" 'e' ü";
+        let escaped = xml_escape(text);
+        assert_eq!(escaped, "a&amp;b &lt;c&gt; &quot;d&quot; &apos;e&apos; ü");
+        assert_eq!(
+            to_error_xml("NoSuchKey", "key <a&b>"),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Error><Code>NoSuchKey</Code><Message>key &lt;a&amp;b&gt;</Message></Error>"
+        );
+    }
+}
@@ -1,2 +1,4 @@
 pub mod api;
+pub mod errors;
+pub mod policy;
 pub mod server;
@@ -0,0 +1,589 @@
+//! Request authorization policies.
+//!
+//! The policy file (`S3D_POLICY`) is a declarative list of statements that allow
+//! or deny requests, which is evaluated for every request before it is routed:
+//!
+//! ```yaml
+//! default: deny
+//! statements:
+//!   - effect: allow
+//!     operations: ["Get*", "Head*", "List*"]
+//!     resources: [photos, "photos/*"]
+//!   - effect: deny
+//!     operations: ["Delete*"]
+//!     resources: ["*"]
+//!     headers:
+//!       x-amz-storage-class: GLACIER
+//! ```
+//!
+//! The input of a request is its operation name (e.g. `GetObject`),
+//! resource (`bucket` or `bucket/key`, and empty for ListBuckets) and headers.
+//! A statement applies if all of its fields match, where a missing field matches
+//! anything, and values are patterns where `*` matches any characters (see `glob_match`).
+//! Deny statements take precedence over allow statements, and requests that no
+//! statement applies to get the default effect. Denied requests fail with AccessDenied.
+//!
+//! The bucket and key are taken from the path of path-style requests, or from the
+//! Host header of virtual-hosted-style requests (`bucket.<domain>`), where the domain
+//! is the host of `S3D_ENDPOINT` (see `request_bucket_and_key`).
+//!
+//! Statements cannot match principals, since s3d does not verify request signatures
+//! and any client could claim the access key id of another, so a statement that denies
+//! a principal would be bypassed by any other access key id. Policies with principals
+//! fail to load (see `Policy::load`).
+//!
+//! Copies are also checked as a `GetObject` of their source.
+
+use crate::filter::glob_match;
+use crate::s3::errors::{error_response, S3Error};
+use aws_smithy_http_server::body::BoxBody;
+use hyper::{header, Body, HeaderMap, Method, Request, Response};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Effect::Deny
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Effect,
+    #[serde(default)]
+    pub statements: Vec<Statement>,
+    /// The domain of virtual-hosted-style requests, which is not part of the file
+    #[serde(skip)]
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Statement {
+    pub effect: Effect,
+    /// Principals are rejected when the policy is loaded (see `Policy::check`)
+    pub principals: Option<serde_yaml::Value>,
+    pub operations: Option<Vec<String>>,
+    pub resources: Option<Vec<String>>,
+    /// Header names are case insensitive, and a header that the request does not have never matches
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+/// PolicyInput is what policies are evaluated on.
+#[derive(Debug, Clone)]
+pub struct PolicyInput<'a> {
+    pub operation: &'a str,
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub headers: &'a HeaderMap,
+}
+
+impl PolicyInput<'_> {
+    pub fn resource(&self) -> String {
+        match (self.bucket, self.key) {
+            (bucket, "") => bucket.to_string(),
+            (bucket, key) => format!("{}/{}", bucket, key),
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> anyhow::Result<Policy> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("Read policy {:?}: {}", path, err))?;
+        let policy: Policy = serde_yaml::from_str(&text)
+            .map_err(|err| anyhow::anyhow!("Invalid policy {:?}: {}", path, err))?;
+        policy
+            .check()
+            .map_err(|err| anyhow::anyhow!("Invalid policy {:?}: {}", path, err))?;
+        info!(
+            "Policy loaded: {:?} with {} statements, default {:?}",
+            path,
+            policy.statements.len(),
+            policy.default
+        );
+        Ok(policy)
+    }
+
+    /// check rejects statements with principals, which s3d cannot enforce
+    /// since it does not verify the signatures of requests.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Some(index) = self.statements.iter().position(|s| s.principals.is_some()) {
+            anyhow::bail!(
+                "statement {} has principals, which are not supported since s3d \
+                 does not verify request signatures - restrict by resources instead",
+                index + 1
+            );
+        }
+        Ok(())
+    }
+
+    /// evaluate returns the effect of the policy on a request input.
+    pub fn evaluate(&self, input: &PolicyInput) -> Effect {
+        let resource = input.resource();
+        let mut effect = None;
+        for statement in self.statements.iter() {
+            if statement.matches(input, &resource) {
+                if statement.effect == Effect::Deny {
+                    return Effect::Deny;
+                }
+                effect = Some(Effect::Allow);
+            }
+        }
+        effect.unwrap_or(self.default)
+    }
+
+    /// authorize evaluates the policy on an http request, and on the source of copies.
+    pub fn authorize<B>(&self, req: &Request<B>) -> Result<(), S3Error> {
+        let (bucket, key) = request_bucket_and_key(req, self.domain.as_deref());
+        let operation = request_operation(req, &bucket, &key);
+        let mut inputs = vec![(operation, bucket, key)];
+        if let Some(source) = req.headers().get("x-amz-copy-source") {
+            let source = source.to_str().unwrap_or_default();
+            let source = urlencoding::decode(source.split('?').next().unwrap_or_default())
+                .map(|s| s.into_owned())
+                .unwrap_or_default();
+            let (src_bucket, src_key) = source
+                .trim_start_matches('/')
+                .split_once('/')
+                .map(|(b, k)| (b.to_string(), k.to_string()))
+                .unwrap_or_default();
+            inputs.push(("GetObject", src_bucket, src_key));
+        }
+        for (operation, bucket, key) in inputs {
+            let input = PolicyInput {
+                operation,
+                bucket: &bucket,
+                key: &key,
+                headers: req.headers(),
+            };
+            if self.evaluate(&input) == Effect::Deny {
+                info!("Policy denied {} {:?}", operation, input.resource());
+                return Err(S3Error::new("AccessDenied", "Access Denied"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Statement {
+    pub fn matches(&self, input: &PolicyInput, resource: &str) -> bool {
+        let any = |patterns: &Option<Vec<String>>, value: &str| {
+            patterns.as_ref().map_or(true, |patterns| {
+                patterns.iter().any(|p| glob_match(p, value))
+            })
+        };
+        any(&self.operations, input.operation)
+            && any(&self.resources, resource)
+            && self.headers.as_ref().map_or(true, |headers| {
+                headers.iter().all(|(name, pattern)| {
+                    input
+                        .headers
+                        .get(name.as_str())
+                        .and_then(|v| v.to_str().ok())
+                        .map_or(false, |v| glob_match(pattern, v))
+                })
+            })
+    }
+}
+
+/// request_bucket_and_key decodes the bucket and key of a request. Requests to a
+/// subdomain of the domain are virtual-hosted-style, where the subdomain is the bucket
+/// and the path is the key, and other requests are path-style.
+pub fn request_bucket_and_key<B>(req: &Request<B>, domain: Option<&str>) -> (String, String) {
+    let path = req.uri().path().trim_start_matches('/');
+    let decode = |s: &str| {
+        urlencoding::decode(s)
+            .map(|s| s.into_owned())
+            .unwrap_or_else(|_| s.to_string())
+    };
+    if let Some(bucket) = domain.and_then(|domain| virtual_host_bucket(req, domain)) {
+        return (bucket, decode(path));
+    }
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    (decode(bucket), decode(key))
+}
+
+/// virtual_host_bucket returns the bucket of a virtual-hosted-style request,
+/// from the Host header (or the authority of the uri) without the port.
+fn virtual_host_bucket<B>(req: &Request<B>, domain: &str) -> Option<String> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    let host = host.rsplit_once(':').map_or(host, |(host, _port)| host);
+    let bucket = host
+        .len()
+        .checked_sub(domain.len() + 1)
+        .filter(|&n| n > 0 && host.is_char_boundary(n))
+        .filter(|&n| host[n..].eq_ignore_ascii_case(&format!(".{}", domain)))
+        .map(|n| &host[..n])?;
+    Some(bucket.to_ascii_lowercase())
+}
+
+fn query_params<B>(req: &Request<B>) -> Vec<(String, String)> {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect()
+}
+
+/// Query params of ListObjects and ListObjectsV2, which tell them apart
+/// from the sub-resources of the bucket.
+const LIST_PARAMS: &[&str] = &[
+    "continuation-token",
+    "delimiter",
+    "encoding-type",
+    "fetch-owner",
+    "list-type",
+    "marker",
+    "max-keys",
+    "prefix",
+    "start-after",
+];
+
+/// request_operation returns the name of the S3 operation of a request,
+/// by its method, bucket, key and sub-resource.
+pub fn request_operation<B>(req: &Request<B>, bucket: &str, key: &str) -> &'static str {
+    let params = query_params(req);
+    let has = |name: &str| params.iter().any(|(k, _)| k == name);
+    let is_copy = req.headers().contains_key("x-amz-copy-source");
+    let is_list = params
+        .iter()
+        .all(|(k, _)| LIST_PARAMS.contains(&k.as_str()));
+    if bucket.is_empty() {
+        return "ListBuckets";
+    }
+    if !key.is_empty() {
+        return match *req.method() {
+            Method::GET if has("tagging") => "GetObjectTagging",
+            Method::GET if has("acl") => "GetObjectAcl",
+            Method::GET if has("uploadId") => "ListParts",
+            Method::GET if has("attributes") => "GetObjectAttributes",
+            Method::GET if has("legal-hold") => "GetObjectLegalHold",
+            Method::GET if has("retention") => "GetObjectRetention",
+            Method::GET if has("torrent") => "GetObjectTorrent",
+            Method::GET => "GetObject",
+            Method::HEAD => "HeadObject",
+            Method::PUT if has("tagging") => "PutObjectTagging",
+            Method::PUT if has("acl") => "PutObjectAcl",
+            Method::PUT if has("legal-hold") => "PutObjectLegalHold",
+            Method::PUT if has("retention") => "PutObjectRetention",
+            Method::PUT if has("uploadId") && is_copy => "UploadPartCopy",
+            Method::PUT if has("uploadId") => "UploadPart",
+            Method::PUT if is_copy => "CopyObject",
+            Method::PUT => "PutObject",
+            Method::DELETE if has("tagging") => "DeleteObjectTagging",
+            Method::DELETE if has("uploadId") => "AbortMultipartUpload",
+            Method::DELETE => "DeleteObject",
+            Method::POST if has("uploads") => "CreateMultipartUpload",
+            Method::POST if has("uploadId") => "CompleteMultipartUpload",
+            Method::POST if has("restore") => "RestoreObject",
+            Method::POST if has("select") => "SelectObjectContent",
+            _ => "Unknown",
+        };
+    }
+    match *req.method() {
+        Method::GET if has("versions") => "ListObjectVersions",
+        Method::GET if has("uploads") => "ListMultipartUploads",
+        Method::GET if has("location") => "GetBucketLocation",
+        Method::GET if has("tagging") => "GetBucketTagging",
+        Method::GET if has("acl") => "GetBucketAcl",
+        Method::GET if has("policy") => "GetBucketPolicy",
+        Method::GET if has("policyStatus") => "GetBucketPolicyStatus",
+        Method::GET if has("versioning") => "GetBucketVersioning",
+        Method::GET if has("lifecycle") => "GetBucketLifecycleConfiguration",
+        Method::GET if has("cors") => "GetBucketCors",
+        Method::GET if has("encryption") => "GetBucketEncryption",
+        Method::GET if has("website") => "GetBucketWebsite",
+        Method::GET if has("object-lock") => "GetObjectLockConfiguration",
+        Method::GET if has("publicAccessBlock") => "GetPublicAccessBlock",
+        Method::GET if is_list && params.iter().any(|(k, v)| k == "list-type" && v == "2") => {
+            "ListObjectsV2"
+        }
+        Method::GET if is_list => "ListObjects",
+        Method::HEAD => "HeadBucket",
+        Method::PUT if has("tagging") => "PutBucketTagging",
+        Method::PUT if has("acl") => "PutBucketAcl",
+        Method::PUT if has("policy") => "PutBucketPolicy",
+        Method::PUT if has("versioning") => "PutBucketVersioning",
+        Method::PUT if has("lifecycle") => "PutBucketLifecycleConfiguration",
+        Method::PUT if has("cors") => "PutBucketCors",
+        Method::PUT if has("encryption") => "PutBucketEncryption",
+        Method::PUT if has("website") => "PutBucketWebsite",
+        Method::PUT if has("object-lock") => "PutObjectLockConfiguration",
+        Method::PUT if has("publicAccessBlock") => "PutPublicAccessBlock",
+        Method::PUT if params.is_empty() => "CreateBucket",
+        Method::DELETE if has("tagging") => "DeleteBucketTagging",
+        Method::DELETE if has("policy") => "DeleteBucketPolicy",
+        Method::DELETE if has("lifecycle") => "DeleteBucketLifecycle",
+        Method::DELETE if has("cors") => "DeleteBucketCors",
+        Method::DELETE if has("encryption") => "DeleteBucketEncryption",
+        Method::DELETE if has("website") => "DeleteBucketWebsite",
+        Method::DELETE if has("publicAccessBlock") => "DeletePublicAccessBlock",
+        Method::DELETE if params.is_empty() => "DeleteBucket",
+        Method::POST if has("delete") => "DeleteObjects",
+        _ => "Unknown",
+    }
+}
+
+/// PolicyLayer authorizes every request of the router by the policy,
+/// and answers denied requests with AccessDenied without routing them.
+#[derive(Clone)]
+pub struct PolicyLayer {
+    pub policy: &'static Policy,
+}
+
+impl<S> tower::Layer<S> for PolicyLayer {
+    type Service = PolicyService<S>;
+    fn layer(&self, inner: S) -> PolicyService<S> {
+        PolicyService {
+            policy: self.policy,
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PolicyService<S> {
+    policy: &'static Policy,
+    inner: S,
+}
+
+impl<S> tower::Service<Request<Body>> for PolicyService<S>
+where
+    S: tower::Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match self.policy.authorize(&req) {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(err) => Box::pin(async move { Ok(error_response(&err)) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+default: deny
+statements:
+  - effect: allow
+    operations: ["Get*", "Head*", "List*"]
+    resources: [photos, "photos/*"]
+  - effect: allow
+    resources: [logs, "logs/*"]
+  - effect: deny
+    operations: ["Delete*"]
+    resources: ["*"]
+    headers:
+      x-amz-storage-class: GLACIER
+"#;
+
+    fn request(method: Method, uri: &str) -> hyper::http::request::Builder {
+        Request::builder().method(method).uri(uri)
+    }
+
+    fn policy() -> Policy {
+        serde_yaml::from_str(POLICY).unwrap()
+    }
+
+    #[test]
+    fn evaluate() {
+        let policy = policy();
+        let headers = HeaderMap::new();
+        let mut glacier = HeaderMap::new();
+        glacier.insert("x-amz-storage-class", "GLACIER".parse().unwrap());
+        let effect = |operation, bucket, key, headers| {
+            policy.evaluate(&PolicyInput {
+                operation,
+                bucket,
+                key,
+                headers,
+            })
+        };
+        assert_eq!(
+            effect("GetObject", "photos", "a.jpg", &headers),
+            Effect::Allow
+        );
+        assert_eq!(
+            effect("ListObjectsV2", "photos", "", &headers),
+            Effect::Allow
+        );
+        assert_eq!(
+            effect("PutObject", "photos", "a.jpg", &headers),
+            Effect::Deny
+        );
+        assert_eq!(effect("GetObject", "photos2", "a", &headers), Effect::Deny);
+        assert_eq!(
+            effect("PutObject", "logs", "a.log", &headers),
+            Effect::Allow
+        );
+        assert_eq!(
+            effect("DeleteObject", "logs", "a.log", &headers),
+            Effect::Allow
+        );
+        assert_eq!(
+            effect("DeleteObject", "logs", "a.log", &glacier),
+            Effect::Deny
+        );
+        let allow_all = Policy {
+            default: Effect::Allow,
+            ..policy.clone()
+        };
+        let input = PolicyInput {
+            operation: "GetObject",
+            bucket: "other",
+            key: "",
+            headers: &glacier,
+        };
+        assert_eq!(allow_all.evaluate(&input), Effect::Allow);
+        assert!(serde_yaml::from_str::<Policy>("statements: [{effect: maybe}]").is_err());
+        assert!(serde_yaml::from_str::<Policy>("statement: []").is_err());
+    }
+
+    #[test]
+    fn authorize() {
+        let policy = policy();
+        let req = |method: Method, uri: &str| request(method, uri).body(()).unwrap();
+        assert!(policy.authorize(&req(Method::GET, "/photos/a.jpg")).is_ok());
+        let err = policy
+            .authorize(&req(Method::PUT, "/photos/a.jpg"))
+            .unwrap_err();
+        assert_eq!(err.code, "AccessDenied");
+        // copies are checked as a read of the source too
+        let copy = |source: &str| {
+            let mut req = req(Method::PUT, "/logs/copy.jpg");
+            req.headers_mut()
+                .insert("x-amz-copy-source", source.parse().unwrap());
+            policy.authorize(&req)
+        };
+        assert!(copy("/logs/a.log").is_ok());
+        assert!(copy("photos2/a.jpg?versionId=1").is_err());
+    }
+
+    #[test]
+    fn principals_rejected() {
+        assert!(policy().check().is_ok());
+        let policy: Policy = serde_yaml::from_str(
+            "statements: [{effect: allow}, {effect: deny, principals: [app1]}]",
+        )
+        .unwrap();
+        let err = policy.check().unwrap_err().to_string();
+        assert!(err.contains("statement 2 has principals"), "{}", err);
+    }
+
+    #[test]
+    fn bucket_and_key() {
+        let parse = |uri: &str, host: Option<&str>, domain: Option<&str>| {
+            let mut builder = request(Method::GET, uri);
+            if let Some(host) = host {
+                builder = builder.header(header::HOST, host);
+            }
+            request_bucket_and_key(&builder.body(()).unwrap(), domain)
+        };
+        let pair = |bucket: &str, key: &str| (bucket.to_string(), key.to_string());
+        assert_eq!(parse("/", None, None), pair("", ""));
+        assert_eq!(parse("/b", None, None), pair("b", ""));
+        assert_eq!(parse("/b/", None, None), pair("b", ""));
+        assert_eq!(parse("/b/dir/a%20b", None, None), pair("b", "dir/a b"));
+        let domain = Some("s3d.local");
+        assert_eq!(
+            parse("/dir/key", Some("b.s3d.local:33333"), domain),
+            pair("b", "dir/key")
+        );
+        assert_eq!(
+            parse("/", Some("My.Bucket.S3D.local"), domain),
+            pair("my.bucket", "")
+        );
+        assert_eq!(
+            parse("/b/key", Some("s3d.local:33333"), domain),
+            pair("b", "key")
+        );
+        assert_eq!(
+            parse("/b/key", Some("xs3d.local"), domain),
+            pair("b", "key")
+        );
+        assert_eq!(parse("/b/key", Some("127.0.0.1"), domain), pair("b", "key"));
+        assert_eq!(
+            parse("http://b.s3d.local/key", None, domain),
+            pair("b", "key")
+        );
+    }
+
+    #[test]
+    fn operations() {
+        let op = |method: Method, uri: &str, copy: bool| {
+            let mut builder = request(method, uri);
+            if copy {
+                builder = builder.header("x-amz-copy-source", "/b/src");
+            }
+            let req = builder.body(()).unwrap();
+            let (bucket, key) = request_bucket_and_key(&req, None);
+            request_operation(&req, &bucket, &key)
+        };
+        assert_eq!(op(Method::GET, "/", false), "ListBuckets");
+        assert_eq!(op(Method::GET, "/b", false), "ListObjects");
+        assert_eq!(
+            op(Method::GET, "/b?list-type=2&prefix=a", false),
+            "ListObjectsV2"
+        );
+        assert_eq!(op(Method::GET, "/b?versions", false), "ListObjectVersions");
+        assert_eq!(op(Method::GET, "/b?tagging", false), "GetBucketTagging");
+        assert_eq!(op(Method::HEAD, "/b", false), "HeadBucket");
+        assert_eq!(op(Method::PUT, "/b", false), "CreateBucket");
+        assert_eq!(op(Method::DELETE, "/b", false), "DeleteBucket");
+        assert_eq!(op(Method::POST, "/b?delete", false), "DeleteObjects");
+        assert_eq!(op(Method::GET, "/b/k", false), "GetObject");
+        assert_eq!(op(Method::GET, "/b/k?tagging", false), "GetObjectTagging");
+        assert_eq!(op(Method::HEAD, "/b/k", false), "HeadObject");
+        assert_eq!(op(Method::PUT, "/b/k", false), "PutObject");
+        assert_eq!(op(Method::PUT, "/b/k", true), "CopyObject");
+        assert_eq!(
+            op(Method::PUT, "/b/k?partNumber=1&uploadId=u", false),
+            "UploadPart"
+        );
+        assert_eq!(
+            op(Method::PUT, "/b/k?partNumber=1&uploadId=u", true),
+            "UploadPartCopy"
+        );
+        assert_eq!(
+            op(Method::POST, "/b/k?uploads", false),
+            "CreateMultipartUpload"
+        );
+        assert_eq!(
+            op(Method::POST, "/b/k?uploadId=u", false),
+            "CompleteMultipartUpload"
+        );
+        assert_eq!(
+            op(Method::DELETE, "/b/k?uploadId=u", false),
+            "AbortMultipartUpload"
+        );
+        assert_eq!(op(Method::DELETE, "/b/k", false), "DeleteObject");
+        assert_eq!(op(Method::PATCH, "/b/k", false), "Unknown");
+    }
+}
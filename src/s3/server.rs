@@ -1,7 +1,27 @@
 use crate::config;
+use crate::connectivity::{remote_unavailable, Connectivity};
+use crate::md_cache::MdCache;
+use crate::object_md::ObjectMd;
+use crate::read_cache::flight::Flight;
+use crate::read_cache::listing::{
+    list_object_versions_offline, list_objects_offline, list_objects_v2_offline,
+};
+use crate::read_cache::prefetch::parse_prefetch_targets;
+use crate::read_cache::{is_cacheable_get, is_cacheable_head, ReadCache};
+use crate::s3::errors::{fix_error_response, scope_errors, to_gateway_err};
+use crate::s3::policy::{Policy, PolicyLayer};
 use crate::utils::{staticify, to_internal_err};
+use crate::write_queue::tombstones::{
+    is_versioned_delete, merge_delete_objects_outputs, split_versioned_deletes,
+};
 use crate::write_queue::WriteQueue;
-use s3d_smithy_codegen_server_s3::{input::*, operation_registry::*};
+use s3d_smithy_codegen_server_s3::{
+    error::{CopyObjectError, DeleteObjectsError, GetObjectError, HeadObjectError, PutObjectError},
+    input::*,
+    operation_registry::*,
+    output::{CopyObjectOutput, DeleteObjectsOutput, ListMultipartUploadsOutput, PutObjectOutput},
+};
+use tower::ServiceExt;
 
 pub type Router = aws_smithy_http_server::Router<hyper::Body>;
 
@@ -11,6 +31,9 @@ pub type SMClient = aws_smithy_client::Client<
 >;
 
 pub async fn serve() -> anyhow::Result<()> {
+    // the sync folder uses its filter in a later stage,
+    // but a filter with a syntax error should fail the startup
+    config::sync_folder_filter()?;
     let s3_config = aws_config::load_from_env().await;
     let s3_client = staticify(aws_sdk_s3::Client::new(&s3_config));
     let sleep_impl = aws_smithy_async::rt::sleep::default_async_sleep();
@@ -18,14 +41,90 @@ pub async fn serve() -> anyhow::Result<()> {
         .sleep_impl(sleep_impl)
         .middleware(aws_sdk_s3::middleware::DefaultMiddleware::new());
     let sm_client = staticify(sm_builder.build());
-    let write_queue = staticify(WriteQueue {
+    let connectivity = staticify(Connectivity::new(s3_client));
+    connectivity.start();
+    let md_cache = if *config::S3D_MD_CACHE == "true" {
+        info!("Metadata cache enabled");
+        Some(staticify(MdCache::new(
+            config::md_cache_ttl()?,
+            config::md_cache_max_entries()?,
+        )))
+    } else {
+        debug!("Metadata cache disabled");
+        None
+    };
+    let write_queue = if *config::S3D_WRITE_QUEUE == "true" {
+        info!("Write queue enabled");
+        let write_queue = staticify(
+            WriteQueue::new(
+                s3_client,
+                connectivity,
+                md_cache,
+                config::S3D_WRITE_QUEUE_DIR.to_string(),
+                config::Limits::write_queue()?,
+                config::write_queue_workers()?,
+                config::write_queue_filter()?,
+            )
+            .await?,
+        );
+        write_queue.start().await?;
+        Some(write_queue)
+    } else {
+        debug!("Write queue disabled");
+        None
+    };
+    let read_cache = if *config::S3D_READ_CACHE == "true" {
+        info!("Read cache enabled");
+        let read_cache = staticify(
+            ReadCache::new(
+                s3_client,
+                connectivity,
+                config::S3D_READ_CACHE_DIR.to_string(),
+                config::Limits::read_cache()?,
+                config::read_cache_ttl()?,
+                config::read_cache_filter()?,
+                config::read_cache_pin()?,
+            )
+            .await?,
+        );
+        read_cache.start().await?;
+        read_cache.start_prefetch(
+            parse_prefetch_targets(config::S3D_READ_CACHE_PREFETCH.as_deref().unwrap_or(""))?,
+            config::read_cache_prefetch_interval()?,
+        );
+        Some(read_cache)
+    } else {
+        debug!("Read cache disabled");
+        None
+    };
+    let policy = match config::policy()? {
+        Some(policy) => {
+            info!("Policy enabled");
+            Some(staticify(policy))
+        }
+        None => {
+            debug!("Policy disabled");
+            None
+        }
+    };
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 33333));
+    let router = build_router(
+        sm_client,
         s3_client,
-        write_queue_dir: config::S3D_WRITE_QUEUE_DIR.to_string(),
+        connectivity,
+        md_cache,
+        write_queue,
+        read_cache,
+        policy,
+    );
+    let service = tower::service_fn(move |req: hyper::Request<hyper::Body>| {
+        let router = router.clone();
+        async move {
+            let (res, err) = scope_errors(router.oneshot(req)).await;
+            Ok::<_, std::convert::Infallible>(fix_error_response(fix_partial_content(res?), err))
+        }
     });
-    write_queue.start();
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 33333));
-    let router = build_router(sm_client, s3_client, write_queue);
-    let server = hyper::Server::bind(&addr).serve(router.into_make_service());
+    let server = hyper::Server::bind(&addr).serve(tower::make::Shared::new(service));
     info!("###################################");
     info!("Listening on http://{}", addr);
     info!("###################################");
@@ -36,22 +135,40 @@ pub async fn serve() -> anyhow::Result<()> {
 pub fn build_router(
     sm_client: &'static SMClient,
     s3_client: &'static aws_sdk_s3::Client,
-    write_queue: &'static WriteQueue,
+    connectivity: &'static Connectivity,
+    md_cache: Option<&'static MdCache>,
+    write_queue: Option<&'static WriteQueue>,
+    read_cache: Option<&'static ReadCache>,
+    policy: Option<&'static Policy>,
 ) -> Router {
     let mut b = OperationRegistryBuilder::default();
 
+    // call the remote with the op input converted to the client types,
+    // or fail fast with ServiceUnavailable while the remote is offline.
+    macro_rules! s3_gateway_call {
+        ($op:ident, $i:expr) => {
+            paste::paste! {{
+                let to_client = crate::codegen_include::[<conv_to_client_ $op:snake _input>];
+                let from_client = crate::codegen_include::[<conv_from_client_ $op:snake _output>];
+                if connectivity.is_online() {
+                    let r = sm_client
+                        .call(to_client($i).make_operation(s3_client.conf()).await.unwrap())
+                        .await;
+                    connectivity.check(&r);
+                    r.map(from_client).map_err(to_gateway_err)
+                } else {
+                    Err(to_internal_err(remote_unavailable()))
+                }
+            }}
+        };
+    }
+
     macro_rules! register_s3_gateway_op {
         ($op:ident) => {
             paste::paste! {
                 b = b.[<$op:snake>](move |i: [<$op Input>]| async {
                     info!("{}: {:?}", stringify!([<$op:snake>]), i);
-                    let to_client = crate::codegen_include::[<conv_to_client_ $op:snake _input>];
-                    let from_client = crate::codegen_include::[<conv_from_client_ $op:snake _output>];
-                    let r = sm_client
-                        .call(to_client(i).make_operation(s3_client.conf()).await.unwrap())
-                        .await
-                        .map(from_client)
-                        .map_err(to_internal_err);
+                    let r = s3_gateway_call!($op, i);
                     info!("{}: {:?}", stringify!([<$op:snake>]), r);
                     r
                 });
@@ -59,64 +176,409 @@ pub fn build_router(
         };
     }
 
-    b = b.put_object(move |i: PutObjectInput| async {
+    // call the remote through the metadata cache when enabled
+    macro_rules! s3_cached_gateway_call {
+        ($op:ident, $bucket:expr, $i:expr) => {{
+            let key = MdCache::to_key(stringify!($op), &$i);
+            match md_cache.and_then(|c| c.get(&key, !connectivity.is_online())) {
+                Some(o) => {
+                    debug!("Metadata cache hit: {}", key);
+                    Ok(o)
+                }
+                None => {
+                    let generation = md_cache.map(|c| c.generation());
+                    let r = s3_gateway_call!($op, $i);
+                    if let (Ok(o), Some(c), Some(generation)) = (&r, md_cache, generation) {
+                        c.put(key, $bucket, o.clone(), generation);
+                    }
+                    r
+                }
+            }
+        }};
+    }
+
+    // writes invalidate the keys they change in the read cache,
+    // and the listings of their bucket in the metadata cache
+    macro_rules! invalidate_caches {
+        ($bucket:expr, $key:expr) => {
+            if let Some(read_cache) = read_cache {
+                read_cache.invalidate($bucket, $key).await;
+            }
+            if let Some(md_cache) = md_cache {
+                md_cache.invalidate($bucket);
+            }
+        };
+    }
+
+    // writes that are passed to the remote replace the queued write of their key,
+    // which is claimed so that it is not pushed over them (see `claim_entry`).
+    macro_rules! s3_write_through_call {
+        ($op:ident, $i:expr, $bucket:expr, $key:expr) => {
+            paste::paste! {{
+                let claim = match write_queue {
+                    Some(write_queue) => write_queue.claim_entry($bucket, $key).await,
+                    None => None,
+                };
+                let r = s3_gateway_call!($op, $i);
+                if let (Ok(_), Some(claim)) = (&r, claim) {
+                    if let Err(err) = claim.replaced().await {
+                        warn!(
+                            "{}: remove replaced queued write {}",
+                            stringify!([<$op:snake>]),
+                            err
+                        );
+                    }
+                }
+                r
+            }}
+        };
+    }
+
+    b = b.put_object(move |i: PutObjectInput| async move {
         info!("put_object: {:?}", i);
-        write_queue.put_object(i).await
+        let (bucket, key) = (i.bucket().to_string(), i.key().to_string());
+        let r: Result<PutObjectOutput, PutObjectError> = async {
+            if let Some(write_queue) = write_queue {
+                if !write_queue
+                    .should_queue(&ObjectMd::from_put_object_input(&i))
+                    .await
+                {
+                    info!("put_object: write queue filter does not match, write to remote");
+                } else if let Some(reservation) =
+                    write_queue.reserve(i.content_length().max(0) as u64).await
+                {
+                    // bodies without a content length take more room as they are written
+                    return write_queue.put_object(i, reservation).await;
+                } else {
+                    info!("put_object: write queue has no room, write to remote");
+                }
+            }
+            let r = s3_write_through_call!(PutObject, i, &bucket, &key);
+            info!("put_object: write to remote {:?}", r);
+            r
+        }
+        .await;
+        invalidate_caches!(&bucket, &key);
+        r
     });
 
     b = b.get_object(move |i: GetObjectInput| async move {
         info!("get_object: {:?}", i);
-        let i1 = i.to_owned();
-        let i2 = i.to_owned();
-        let qres = write_queue.get_object(i1).await;
-        if qres.is_ok() {
-            return qres;
+        if let Some(write_queue) = write_queue {
+            match write_queue.get_object(i.to_owned()).await {
+                Err(GetObjectError::NoSuchKey(_)) => {}
+                qres => return qres,
+            }
+        }
+        if let Some(read_cache) = read_cache {
+            if is_cacheable_get(&i) && read_cache.is_cacheable_key(i.bucket(), i.key()) {
+                if let Some(r) = read_cache.get_object(&i).await {
+                    return Ok(r);
+                }
+                // ranges are not coalesced, and are cached in blocks
+                let leader = match i.range() {
+                    Some(_) => None,
+                    None => match read_cache.join_flight(&i) {
+                        Flight::Leader(leader) => Some(leader),
+                        Flight::Follower(rx) => {
+                            if let Some(r) = read_cache.follow(&i, rx).await {
+                                return Ok(r);
+                            }
+                            None
+                        }
+                    },
+                };
+                info!("get_object: read from remote to cache");
+                let r = s3_gateway_call!(GetObject, i.clone());
+                info!("get_object: read from remote to cache {:?}", r);
+                return r.map(|o| read_cache.fill(i.bucket(), i.key(), o, leader));
+            }
         }
         info!("get_object: read from remote");
-        let to_client = crate::codegen_include::conv_to_client_get_object_input;
-        let from_client = crate::codegen_include::conv_from_client_get_object_output;
-        let r = sm_client
-            .call(
-                to_client(i2)
-                    .make_operation(s3_client.conf())
-                    .await
-                    .unwrap(),
-            )
-            .await
-            .map(from_client)
-            .map_err(to_internal_err);
+        let r = s3_gateway_call!(GetObject, i);
         info!("get_object: read from remote {:?}", r);
         r
     });
 
+    b = b.head_object(move |i: HeadObjectInput| async move {
+        info!("head_object: {:?}", i);
+        if let Some(write_queue) = write_queue {
+            match write_queue.head_object(i.to_owned()).await {
+                Err(HeadObjectError::NotFound(_)) => {}
+                qres => return qres,
+            }
+        }
+        if let Some(read_cache) = read_cache {
+            if is_cacheable_head(&i) {
+                if let Some(r) = read_cache.head_object(&i).await {
+                    return Ok(r);
+                }
+            }
+        }
+        info!("head_object: read from remote");
+        let r = s3_gateway_call!(HeadObject, i);
+        info!("head_object: read from remote {:?}", r);
+        r
+    });
+
+    b = b.copy_object(move |i: CopyObjectInput| async move {
+        info!("copy_object: {:?}", i);
+        let (bucket, key) = (i.bucket().to_string(), i.key().to_string());
+        let r: Result<CopyObjectOutput, CopyObjectError> = async {
+            if let Some(write_queue) = write_queue {
+                if let Some(r) = write_queue.copy_object(&i).await? {
+                    return Ok(r);
+                }
+            }
+            info!("copy_object: copy on remote");
+            let r = s3_write_through_call!(CopyObject, i, &bucket, &key);
+            info!("copy_object: copy on remote {:?}", r);
+            r
+        }
+        .await;
+        invalidate_caches!(&bucket, &key);
+        r
+    });
+
+    b = b.delete_object(move |i: DeleteObjectInput| async move {
+        info!("delete_object: {:?}", i);
+        let (bucket, key) = (i.bucket().to_string(), i.key().to_string());
+        let r = match write_queue {
+            Some(write_queue) if !is_versioned_delete(&i) => write_queue.delete_object(i).await,
+            _ => s3_gateway_call!(DeleteObject, i),
+        };
+        info!("delete_object: {:?}", r);
+        invalidate_caches!(&bucket, &key);
+        r
+    });
+
+    b = b.delete_objects(move |mut i: DeleteObjectsInput| async move {
+        info!("delete_objects: {:?}", i);
+        let bucket = i.bucket().to_string();
+        let keys = i
+            .delete()
+            .objects()
+            .iter()
+            .map(|o| o.key().to_string())
+            .collect::<Vec<_>>();
+        let r: Result<DeleteObjectsOutput, DeleteObjectsError> = async {
+            let write_queue = match write_queue {
+                Some(write_queue) => write_queue,
+                None => return s3_gateway_call!(DeleteObjects, i),
+            };
+            // deletes of versions are passed to the remote
+            let remote = match split_versioned_deletes(&mut i) {
+                Some(remote_i) => s3_gateway_call!(DeleteObjects, remote_i)?,
+                None => return write_queue.delete_objects(i).await,
+            };
+            if i.delete().objects().is_empty() {
+                return Ok(remote);
+            }
+            let queued = write_queue.delete_objects(i).await?;
+            Ok(merge_delete_objects_outputs(queued, remote))
+        }
+        .await;
+        info!("delete_objects: {:?}", r);
+        for key in keys.iter() {
+            invalidate_caches!(&bucket, key);
+        }
+        r
+    });
+
+    // uploads that do not match the write queue filter are created on the remote,
+    // and their parts are passed through since their upload ids are not local.
+    b = b.create_multipart_upload(move |i: CreateMultipartUploadInput| async move {
+        info!("create_multipart_upload: {:?}", i);
+        if let Some(write_queue) = write_queue {
+            if !write_queue
+                .should_queue(&ObjectMd::from_create_multipart_upload_input(&i))
+                .await
+            {
+                info!(
+                    "create_multipart_upload: write queue filter does not match, create on remote"
+                );
+            } else if let Some(reservation) = write_queue.reserve_room(0, 1).await {
+                return write_queue.create_multipart_upload(i, reservation).await;
+            } else {
+                info!("create_multipart_upload: write queue has no room, create on remote");
+            }
+        }
+        let r = s3_gateway_call!(CreateMultipartUpload, i);
+        info!("create_multipart_upload: {:?}", r);
+        r
+    });
+
+    b = b.complete_multipart_upload(move |i: CompleteMultipartUploadInput| async move {
+        info!("complete_multipart_upload: {:?}", i);
+        let (bucket, key) = (i.bucket().to_string(), i.key().to_string());
+        let r = match write_queue {
+            Some(write_queue) if write_queue.has_upload(i.upload_id()).await => {
+                write_queue.complete_multipart_upload(i).await
+            }
+            _ => s3_write_through_call!(CompleteMultipartUpload, i, &bucket, &key),
+        };
+        info!("complete_multipart_upload: {:?}", r);
+        invalidate_caches!(&bucket, &key);
+        r
+    });
+
+    // uploads hosted by the write queue are merged into the uploads of the remote,
+    // which are left out while it is offline.
+    b = b.list_multipart_uploads(move |i: ListMultipartUploadsInput| async move {
+        info!("list_multipart_uploads: {:?}", i);
+        if let Some(write_queue) = write_queue {
+            let remote_i = write_queue.list_multipart_uploads_remote_input(&i).await;
+            let remote = match s3_gateway_call!(ListMultipartUploads, remote_i) {
+                Ok(remote) => remote,
+                Err(_) if !connectivity.is_online() => {
+                    ListMultipartUploadsOutput::builder().build()
+                }
+                Err(err) => return Err(err),
+            };
+            return write_queue.merge_list_multipart_uploads(&i, remote).await;
+        }
+        let r = s3_gateway_call!(ListMultipartUploads, i);
+        info!("list_multipart_uploads: {:?}", r);
+        r
+    });
+
+    // tags of queued objects are changed in the write queue, and are pushed with them.
+    // tags can pin cached objects, so cached tags are refreshed after they change.
+    macro_rules! register_object_tagging_op {
+        ($op:ident) => {
+            paste::paste! {
+                b = b.[<$op:snake>](move |i: [<$op Input>]| async move {
+                    info!("{}: {:?}", stringify!([<$op:snake>]), i);
+                    if let Some(write_queue) = write_queue {
+                        if let Some(r) = write_queue.[<$op:snake>](&i).await? {
+                            info!("{}: queued {:?}", stringify!([<$op:snake>]), r);
+                            return Ok(r);
+                        }
+                    }
+                    let (bucket, key) = (i.bucket().to_string(), i.key().to_string());
+                    let r = s3_gateway_call!($op, i);
+                    info!("{}: {:?}", stringify!([<$op:snake>]), r);
+                    if let (Ok(_), Some(read_cache)) = (&r, read_cache) {
+                        read_cache.refresh_tags(&bucket, &key).await;
+                    }
+                    r
+                });
+            }
+        };
+    }
+
+    register_object_tagging_op!(PutObjectTagging);
+    register_object_tagging_op!(DeleteObjectTagging);
+
+    b = b.get_object_tagging(move |i: GetObjectTaggingInput| async move {
+        info!("get_object_tagging: {:?}", i);
+        if let Some(write_queue) = write_queue {
+            if let Some(r) = write_queue.get_object_tagging(&i).await? {
+                info!("get_object_tagging: queued {:?}", r);
+                return Ok(r);
+            }
+        }
+        let r = s3_gateway_call!(GetObjectTagging, i);
+        info!("get_object_tagging: {:?}", r);
+        r
+    });
+
+    // multipart uploads are hosted by the write queue when enabled,
+    // and uploads that were created on the remote are passed through.
+    macro_rules! register_write_queue_upload_op {
+        ($op:ident) => {
+            paste::paste! {
+                b = b.[<$op:snake>](move |i: [<$op Input>]| async move {
+                    info!("{}: {:?}", stringify!([<$op:snake>]), i);
+                    if let Some(write_queue) = write_queue {
+                        if write_queue.has_upload(i.upload_id()).await {
+                            return write_queue.[<$op:snake>](i).await;
+                        }
+                    }
+                    let r = s3_gateway_call!($op, i);
+                    info!("{}: {:?}", stringify!([<$op:snake>]), r);
+                    r
+                });
+            }
+        };
+    }
+
+    // listings of the remote are merged with the write queue when enabled,
+    // and the cached objects stand in for the remote while it is offline.
+    // The remote pages are kept in the metadata cache when enabled.
+    macro_rules! register_write_queue_list_op {
+        ($op:ident) => {
+            paste::paste! {
+                b = b.[<$op:snake>](move |i: [<$op Input>]| async move {
+                    info!("{}: {:?}", stringify!([<$op:snake>]), i);
+                    if let Some(write_queue) = write_queue {
+                        let remote_i = write_queue.[<$op:snake _remote_input>](&i)?;
+                        let bucket = remote_i.bucket().to_string();
+                        let remote = match s3_cached_gateway_call!($op, &bucket, remote_i.clone()) {
+                            Ok(remote) => remote,
+                            Err(_) if !connectivity.is_online() => {
+                                [<$op:snake _offline>](read_cache, &remote_i).await?
+                            }
+                            Err(err) => return Err(err),
+                        };
+                        return write_queue.[<merge_ $op:snake>](&i, remote).await;
+                    }
+                    let bucket = i.bucket().to_string();
+                    let r = match s3_cached_gateway_call!($op, &bucket, i.clone()) {
+                        Err(_) if !connectivity.is_online() => {
+                            [<$op:snake _offline>](read_cache, &i).await
+                        }
+                        r => r,
+                    };
+                    info!("{}: {:?}", stringify!([<$op:snake>]), r);
+                    r
+                });
+            }
+        };
+    }
+
+    b = b.head_bucket(move |i: HeadBucketInput| async move {
+        info!("head_bucket: {:?}", i);
+        let bucket = i.bucket().to_string();
+        let r = s3_cached_gateway_call!(HeadBucket, &bucket, i);
+        info!("head_bucket: {:?}", r);
+        r
+    });
+
+    // bucket ops change the existence of buckets in the metadata cache
+    macro_rules! register_bucket_op {
+        ($op:ident) => {
+            paste::paste! {
+                b = b.[<$op:snake>](move |i: [<$op Input>]| async move {
+                    info!("{}: {:?}", stringify!([<$op:snake>]), i);
+                    let bucket = i.bucket().to_string();
+                    let r = s3_gateway_call!($op, i);
+                    info!("{}: {:?}", stringify!([<$op:snake>]), r);
+                    if let Some(md_cache) = md_cache {
+                        md_cache.invalidate(&bucket);
+                    }
+                    r
+                });
+            }
+        };
+    }
+
+    register_bucket_op!(CreateBucket);
+    register_bucket_op!(DeleteBucket);
+    register_write_queue_upload_op!(UploadPart);
+    register_write_queue_upload_op!(UploadPartCopy);
+    register_write_queue_upload_op!(AbortMultipartUpload);
+    register_write_queue_upload_op!(ListParts);
+    register_write_queue_list_op!(ListObjects);
+    register_write_queue_list_op!(ListObjectsV2);
+    register_write_queue_list_op!(ListObjectVersions);
+
     // LIST OPS
     register_s3_gateway_op!(ListBuckets);
-    register_s3_gateway_op!(ListObjects);
-    register_s3_gateway_op!(ListObjectsV2);
-    register_s3_gateway_op!(ListObjectVersions);
-    // SIMPLE OBJECT OPS
-    register_s3_gateway_op!(HeadObject);
-    register_s3_gateway_op!(CopyObject);
-    register_s3_gateway_op!(DeleteObject);
-    register_s3_gateway_op!(DeleteObjects);
-    register_s3_gateway_op!(GetObjectTagging);
-    register_s3_gateway_op!(PutObjectTagging);
-    register_s3_gateway_op!(DeleteObjectTagging);
     // SIMPLE BUCKET OPS
-    register_s3_gateway_op!(HeadBucket);
-    register_s3_gateway_op!(CreateBucket);
-    register_s3_gateway_op!(DeleteBucket);
     register_s3_gateway_op!(GetBucketTagging);
     register_s3_gateway_op!(PutBucketTagging);
     register_s3_gateway_op!(DeleteBucketTagging);
-    // MULTIPART UPLOAD OPS
-    register_s3_gateway_op!(CreateMultipartUpload);
-    register_s3_gateway_op!(CompleteMultipartUpload);
-    register_s3_gateway_op!(AbortMultipartUpload);
-    register_s3_gateway_op!(ListMultipartUploads);
-    register_s3_gateway_op!(ListParts);
-    register_s3_gateway_op!(UploadPart);
-    register_s3_gateway_op!(UploadPartCopy);
     // ADVANCED OBJECT OPS
     register_s3_gateway_op!(GetObjectAcl);
     register_s3_gateway_op!(PutObjectAcl);
@@ -204,5 +666,23 @@ pub fn build_router(
     }
 
     let router = Router::from(ops);
-    router
+
+    // requests are authorized before they are routed to the ops
+    match policy {
+        Some(policy) => router.layer(PolicyLayer { policy }),
+        None => router,
+    }
+}
+
+/// fix_partial_content sets the 206 status of range responses,
+/// since the generated protocol returns 200 for every GetObject output.
+fn fix_partial_content(
+    mut res: hyper::Response<aws_smithy_http_server::body::BoxBody>,
+) -> hyper::Response<aws_smithy_http_server::body::BoxBody> {
+    if res.status() == hyper::StatusCode::OK
+        && res.headers().contains_key(hyper::header::CONTENT_RANGE)
+    {
+        *res.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+    }
+    res
 }
@@ -1,12 +1,15 @@
 use crate::config;
+use crate::s3::errors::{record_error, S3Error};
+use aws_smithy_http::body::SdkBody;
 use aws_smithy_http::byte_stream::ByteStream;
 use s3d_smithy_codegen_server_s3::error::InternalServerError;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::os::unix::io::FromRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use tokio::fs::{read_to_string, File};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio_stream::StreamExt;
 
 /// staticify uses Box::leak to make a struct with static lifetime.
@@ -59,24 +62,148 @@ pub async fn read_file_as_stream(fname: &str) -> anyhow::Result<ByteStream> {
     Ok(ByteStream::from_path(Path::new(&fname)).await?)
 }
 
+/// read_files_as_stream streams the files one after the other, as a single body.
+/// A failure to read any of the files fails the stream.
+pub fn read_files_as_stream(paths: Vec<PathBuf>) -> ByteStream {
+    let (mut tx, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024 * 1024];
+        for path in paths {
+            let mut file = match File::open(&path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!("read_files_as_stream: open {:?} {}", path, err);
+                    tx.abort();
+                    return;
+                }
+            };
+            loop {
+                let n = match file.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) => {
+                        warn!("read_files_as_stream: read {:?} {}", path, err);
+                        tx.abort();
+                        return;
+                    }
+                };
+                if tx
+                    .send_data(bytes::Bytes::copy_from_slice(&buf[..n]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+    ByteStream::new(SdkBody::from(body))
+}
+
+/// read_ranges_as_stream streams ranges of open files one after the other,
+/// as a single body, where each range is a file with an offset and a length.
+pub fn read_ranges_as_stream(ranges: Vec<(File, u64, u64)>) -> ByteStream {
+    let (mut tx, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024 * 1024];
+        for (mut file, offset, len) in ranges {
+            if let Err(err) = file.seek(std::io::SeekFrom::Start(offset)).await {
+                warn!("read_ranges_as_stream: seek {}", err);
+                tx.abort();
+                return;
+            }
+            let mut left = len;
+            while left > 0 {
+                let want = left.min(buf.len() as u64) as usize;
+                let n = match file.read(&mut buf[..want]).await {
+                    Ok(0) => {
+                        warn!("read_ranges_as_stream: short read");
+                        tx.abort();
+                        return;
+                    }
+                    Ok(n) => n,
+                    Err(err) => {
+                        warn!("read_ranges_as_stream: read {}", err);
+                        tx.abort();
+                        return;
+                    }
+                };
+                left -= n as u64;
+                if tx
+                    .send_data(bytes::Bytes::copy_from_slice(&buf[..n]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+    ByteStream::new(SdkBody::from(body))
+}
+
 pub async fn write_stream_to_file(fname: &str, stream: &mut ByteStream) -> anyhow::Result<u64> {
+    write_stream_to_file_with(fname, stream, |_| Ok(())).await
+}
+
+/// write_stream_to_file_with calls `inspect` with every buffer before it is written
+/// to the file, which is useful to compute checksums while streaming.
+/// An error from `inspect` stops the write.
+pub async fn write_stream_to_file_with<F>(
+    fname: &str,
+    stream: &mut ByteStream,
+    inspect: F,
+) -> anyhow::Result<u64>
+where
+    F: FnMut(&[u8]) -> anyhow::Result<()>,
+{
     let mut file = File::create(fname).await?;
-    let num_bytes = pipe_stream(stream, &mut file).await?;
+    let num_bytes = pipe_stream_with(stream, &mut file, inspect).await?;
     file.flush().await?;
     file.sync_all().await?;
     file.shutdown().await?;
     Ok(num_bytes)
 }
 
+/// is_not_found checks if an error is an io error of a missing file.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<std::io::Error>(),
+        Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound
+    )
+}
+
+/// sync_dir syncs a directory to disk, which makes the creation,
+/// removal and renaming of entries inside it durable.
+pub async fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    File::open(path).await?.sync_all().await?;
+    Ok(())
+}
+
 pub async fn pipe_stream<I, O, E>(input: &mut I, output: &mut O) -> anyhow::Result<u64>
 where
     I: tokio_stream::Stream<Item = Result<bytes::Bytes, E>> + std::marker::Unpin,
     O: tokio::io::AsyncWrite + std::marker::Unpin,
     E: std::error::Error + Send + Sync + 'static,
+{
+    pipe_stream_with(input, output, |_| Ok(())).await
+}
+
+pub async fn pipe_stream_with<I, O, E, F>(
+    input: &mut I,
+    output: &mut O,
+    mut inspect: F,
+) -> anyhow::Result<u64>
+where
+    I: tokio_stream::Stream<Item = Result<bytes::Bytes, E>> + std::marker::Unpin,
+    O: tokio::io::AsyncWrite + std::marker::Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+    F: FnMut(&[u8]) -> anyhow::Result<()>,
 {
     let mut num_bytes: u64 = 0;
     while let Some(ref mut buf) = input.try_next().await? {
         num_bytes += buf.len() as u64;
+        inspect(buf)?;
         output.write_all_buf(buf).await?;
     }
     Ok(num_bytes)
@@ -111,14 +238,33 @@ pub async fn byte_stream_from_infile_or_stdin(infile: Option<&str>) -> anyhow::R
     Ok(stream)
 }
 
-pub async fn _read_yaml_file<T>(path: &Path) -> anyhow::Result<T>
+pub async fn read_yaml_file<T>(path: &Path) -> anyhow::Result<T>
 where
     T: for<'de> Deserialize<'de>,
 {
     Ok(serde_yaml::from_str(&read_to_string(path).await?)?)
 }
 
-pub fn to_internal_err<F: ToString, T: From<InternalServerError>>(err: F) -> T {
+/// write_yaml_file writes and syncs the file, but does not sync the parent dir,
+/// which is left for the caller to do once (e.g. after renaming it into place).
+pub async fn write_yaml_file<T>(path: &Path, value: &T) -> anyhow::Result<()>
+where
+    T: Serialize,
+{
+    let mut file = File::create(path).await?;
+    file.write_all(serde_yaml::to_string(value)?.as_bytes()).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// to_internal_err converts an error to the InternalServerError of an op,
+/// and keeps the `S3Error` it carries for the response (see `record_error`).
+pub fn to_internal_err<F: ToString + 'static, T: From<InternalServerError>>(err: F) -> T {
+    let any = &err as &dyn Any;
+    record_error(any.downcast_ref::<S3Error>().or_else(|| {
+        any.downcast_ref::<anyhow::Error>()
+            .and_then(|err| err.downcast_ref::<S3Error>())
+    }));
     InternalServerError {
         message: err.to_string(),
     }
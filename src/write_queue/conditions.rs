@@ -0,0 +1,234 @@
+//! Conditional requests on queued objects.
+//!
+//! The `If-*` headers of GetObject and HeadObject, and the `x-amz-copy-source-if-*`
+//! headers of copies, are checked against the queued meta-data, since the remote
+//! does not have the queued object yet and cannot check them.
+
+use crate::object_md::ObjectMd;
+use crate::s3::errors::S3Error;
+use aws_smithy_types::DateTime;
+use s3d_smithy_codegen_server_s3::input::{
+    CopyObjectInput, GetObjectInput, HeadObjectInput, UploadPartCopyInput,
+};
+
+/// Conditions are the conditional headers of a request on an object.
+pub struct Conditions<'a> {
+    pub if_match: Option<&'a str>,
+    pub if_none_match: Option<&'a str>,
+    pub if_modified_since: Option<&'a DateTime>,
+    pub if_unmodified_since: Option<&'a DateTime>,
+}
+
+impl<'a> Conditions<'a> {
+    pub fn from_get_object_input(i: &'a GetObjectInput) -> Self {
+        Conditions {
+            if_match: i.if_match(),
+            if_none_match: i.if_none_match(),
+            if_modified_since: i.if_modified_since(),
+            if_unmodified_since: i.if_unmodified_since(),
+        }
+    }
+
+    pub fn from_head_object_input(i: &'a HeadObjectInput) -> Self {
+        Conditions {
+            if_match: i.if_match(),
+            if_none_match: i.if_none_match(),
+            if_modified_since: i.if_modified_since(),
+            if_unmodified_since: i.if_unmodified_since(),
+        }
+    }
+
+    pub fn from_copy_object_input(i: &'a CopyObjectInput) -> Self {
+        Conditions {
+            if_match: i.copy_source_if_match(),
+            if_none_match: i.copy_source_if_none_match(),
+            if_modified_since: i.copy_source_if_modified_since(),
+            if_unmodified_since: i.copy_source_if_unmodified_since(),
+        }
+    }
+
+    pub fn from_upload_part_copy_input(i: &'a UploadPartCopyInput) -> Self {
+        Conditions {
+            if_match: i.copy_source_if_match(),
+            if_none_match: i.copy_source_if_none_match(),
+            if_modified_since: i.copy_source_if_modified_since(),
+            if_unmodified_since: i.copy_source_if_unmodified_since(),
+        }
+    }
+
+    /// check checks the conditions of a read. A failed if-match or if-unmodified-since
+    /// is PreconditionFailed, and a failed if-none-match or if-modified-since is NotModified.
+    pub fn check(&self, md: &ObjectMd) -> Result<(), S3Error> {
+        let (match_failed, none_match_failed) = self.evaluate(md);
+        if match_failed {
+            return Err(precondition_failed());
+        }
+        if none_match_failed {
+            return Err(S3Error::new("NotModified", "Not Modified"));
+        }
+        Ok(())
+    }
+
+    /// check_copy_source checks the conditions on the source of a copy,
+    /// where every failed condition is PreconditionFailed.
+    pub fn check_copy_source(&self, md: &ObjectMd) -> Result<(), S3Error> {
+        let (match_failed, none_match_failed) = self.evaluate(md);
+        if match_failed || none_match_failed {
+            return Err(precondition_failed());
+        }
+        Ok(())
+    }
+
+    /// evaluate returns whether the match and the none-match conditions failed.
+    /// A matching if-match takes precedence over if-unmodified-since,
+    /// and a not matching if-none-match over if-modified-since, like in S3.
+    fn evaluate(&self, md: &ObjectMd) -> (bool, bool) {
+        let e_tag = md.e_tag.as_deref().unwrap_or("").trim_matches('"');
+        let secs = md.last_modified / 1000;
+        let match_failed = match self.if_match {
+            Some(if_match) => if_match.trim_matches('"') != e_tag,
+            None => matches!(self.if_unmodified_since, Some(t) if secs > t.secs()),
+        };
+        let none_match_failed = match self.if_none_match {
+            Some(if_none_match) => if_none_match.trim_matches('"') == e_tag,
+            None => matches!(self.if_modified_since, Some(t) if secs <= t.secs()),
+        };
+        (match_failed, none_match_failed)
+    }
+}
+
+fn precondition_failed() -> S3Error {
+    S3Error::new(
+        "PreconditionFailed",
+        "At least one of the pre-conditions you specified did not hold",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_source_conditions() {
+        let md = ObjectMd {
+            e_tag: Some("\"abc\"".to_string()),
+            last_modified: 2_000_000,
+            ..Default::default()
+        };
+        let (before, at) = (DateTime::from_secs(1999), DateTime::from_secs(2000));
+        let none = Conditions {
+            if_match: None,
+            if_none_match: None,
+            if_modified_since: None,
+            if_unmodified_since: None,
+        };
+        let check = |conditions: Conditions| conditions.check_copy_source(&md).is_ok();
+        assert!(check(Conditions { ..none }));
+        assert!(check(Conditions {
+            if_match: Some("\"abc\""),
+            ..none
+        }));
+        assert!(!check(Conditions {
+            if_match: Some("abd"),
+            ..none
+        }));
+        assert!(check(Conditions {
+            if_none_match: Some("abd"),
+            ..none
+        }));
+        assert!(!check(Conditions {
+            if_none_match: Some("abc"),
+            ..none
+        }));
+        assert!(check(Conditions {
+            if_modified_since: Some(&before),
+            ..none
+        }));
+        assert!(!check(Conditions {
+            if_modified_since: Some(&at),
+            ..none
+        }));
+        assert!(check(Conditions {
+            if_unmodified_since: Some(&at),
+            ..none
+        }));
+        assert!(!check(Conditions {
+            if_unmodified_since: Some(&before),
+            ..none
+        }));
+        // a matching if-match wins over if-unmodified-since,
+        // and a not matching if-none-match over if-modified-since
+        assert!(check(Conditions {
+            if_match: Some("abc"),
+            if_unmodified_since: Some(&before),
+            ..none
+        }));
+        assert!(check(Conditions {
+            if_none_match: Some("abd"),
+            if_modified_since: Some(&at),
+            ..none
+        }));
+    }
+
+    #[test]
+    fn read_conditions() {
+        let md = ObjectMd {
+            e_tag: Some("\"abc\"".to_string()),
+            last_modified: 2_000_000,
+            ..Default::default()
+        };
+        let (before, at) = (DateTime::from_secs(1999), DateTime::from_secs(2000));
+        let none = Conditions {
+            if_match: None,
+            if_none_match: None,
+            if_modified_since: None,
+            if_unmodified_since: None,
+        };
+        let check = |conditions: Conditions| conditions.check(&md).map_err(|err| err.code);
+        assert_eq!(check(Conditions { ..none }), Ok(()));
+        assert_eq!(
+            check(Conditions {
+                if_match: Some("abd"),
+                ..none
+            }),
+            Err("PreconditionFailed")
+        );
+        assert_eq!(
+            check(Conditions {
+                if_unmodified_since: Some(&before),
+                ..none
+            }),
+            Err("PreconditionFailed")
+        );
+        assert_eq!(
+            check(Conditions {
+                if_none_match: Some("\"abc\""),
+                ..none
+            }),
+            Err("NotModified")
+        );
+        assert_eq!(
+            check(Conditions {
+                if_modified_since: Some(&at),
+                ..none
+            }),
+            Err("NotModified")
+        );
+        assert_eq!(
+            check(Conditions {
+                if_modified_since: Some(&before),
+                ..none
+            }),
+            Ok(())
+        );
+        // a failed precondition wins over not modified
+        assert_eq!(
+            check(Conditions {
+                if_match: Some("abd"),
+                if_none_match: Some("abc"),
+                ..none
+            }),
+            Err("PreconditionFailed")
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! CopyObject with the write queue.
+//!
+//! A copy is a server side operation of the remote, which cannot see objects that
+//! were not pushed yet, and the remote copy could also be overwritten by an older
+//! queued write of the destination. So when the source or the destination is in the
+//! queue, the copy is made locally - the source data is read (from the queue or from
+//! the remote) and the destination is committed to the queue like a put.
+//! Copies where neither side is queued are left to the remote.
+//! The sources of UploadPartCopy to local multipart uploads are read the same way.
+//!
+//! Copies that do not match the write queue filter are not queued - they are left to
+//! the remote too, unless the source is only in the queue, in which case the copy
+//! is written directly to the remote.
+
+use crate::byte_range::ByteRange;
+use crate::checksum::{ObjectHasher, CRC32, CRC32C, SHA1, SHA256};
+use crate::connectivity::remote_unavailable;
+use crate::object_md::{parse_tagging, ObjectMd};
+use crate::s3::errors::{to_gateway_err, S3Error};
+use crate::utils::to_internal_err;
+use crate::write_queue::conditions::Conditions;
+use crate::write_queue::{no_such_key_deleted, WriteQueue};
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{
+    error::{CopyObjectError, InternalServerError},
+    input::CopyObjectInput,
+    model::{CopyObjectResult, MetadataDirective, TaggingDirective},
+    output::CopyObjectOutput,
+};
+use std::collections::BTreeMap;
+
+impl WriteQueue {
+    /// copy_object copies locally when the source or the destination is queued,
+    /// and otherwise returns None for the caller to copy on the remote.
+    pub async fn copy_object(
+        &self,
+        i: &CopyObjectInput,
+    ) -> Result<Option<CopyObjectOutput>, CopyObjectError> {
+        let (src_bucket, src_key, src_version_id) =
+            parse_copy_source(i.copy_source()).map_err(to_internal_err)?;
+        let replace_md = i.metadata_directive() == Some(&MetadataDirective::Replace);
+        let replace_tags = i.tagging_directive() == Some(&TaggingDirective::Replace);
+        if src_bucket == i.bucket()
+            && src_key == i.key()
+            && src_version_id.is_none()
+            && !replace_md
+            && i.storage_class().is_none()
+        {
+            return Err(to_internal_err(S3Error::new(
+                "InvalidRequest",
+                "This copy request is illegal because it is trying to copy an object \
+                 to itself without changing the object's metadata, storage class, \
+                 website redirect location or encryption attributes.",
+            )));
+        }
+
+        // a specific version of the source can only be on the remote
+        let src_queued = match src_version_id {
+            Some(_) => None,
+            None => self
+                .find_entry(&src_bucket, &src_key)
+                .await
+                .map_err(to_internal_err)?,
+        };
+        let dst_entry = self.to_entry_name(i.bucket(), i.key());
+        let dst_queued = tokio::fs::metadata(self.entry_path(&dst_entry))
+            .await
+            .is_ok();
+        if src_queued.is_none() && !dst_queued {
+            return Ok(None);
+        }
+
+        let src_is_queued = src_queued.is_some();
+        let (src_md, mut body) = match src_queued {
+            Some((md, file)) => {
+                if md.delete_marker {
+                    return Err(to_internal_err(no_such_key_deleted()));
+                }
+                let body = ByteStream::read_from()
+                    .file(file)
+                    .build()
+                    .await
+                    .map_err(to_internal_err)?;
+                (md, body)
+            }
+            None => {
+                self.get_remote_source::<CopyObjectError>(
+                    &src_bucket,
+                    &src_key,
+                    src_version_id.as_deref(),
+                    None,
+                )
+                .await?
+            }
+        };
+        Conditions::from_copy_object_input(i)
+            .check_copy_source(&src_md)
+            .map_err(to_internal_err)?;
+
+        let mut md = if replace_md {
+            ObjectMd {
+                content_type: i.content_type().map(String::from),
+                content_encoding: i.content_encoding().map(String::from),
+                content_disposition: i.content_disposition().map(String::from),
+                content_language: i.content_language().map(String::from),
+                cache_control: i.cache_control().map(String::from),
+                expires: i.expires().map(|t| t.secs()),
+                metadata: i
+                    .metadata()
+                    .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                    .unwrap_or_default(),
+                ..ObjectMd::default()
+            }
+        } else {
+            ObjectMd {
+                content_type: src_md.content_type.clone(),
+                content_encoding: src_md.content_encoding.clone(),
+                content_disposition: src_md.content_disposition.clone(),
+                content_language: src_md.content_language.clone(),
+                cache_control: src_md.cache_control.clone(),
+                expires: src_md.expires,
+                metadata: src_md.metadata.clone(),
+                ..ObjectMd::default()
+            }
+        };
+        md.bucket = i.bucket().to_string();
+        md.key = i.key().to_string();
+        md.content_length = src_md.content_length;
+        md.last_modified = chrono::Utc::now().timestamp_millis();
+        md.storage_class = i.storage_class().map(|s| s.as_str().to_string());
+        md.tags = if replace_tags {
+            i.tagging().map(parse_tagging).unwrap_or_default()
+        } else {
+            src_md.tags.clone()
+        };
+
+        if !self.should_queue(&md).await {
+            if !src_is_queued {
+                info!("Write queue copy: filter does not match, copy on remote");
+                return Ok(None);
+            }
+            return self.put_remote_copy(&md, body).await.map(Some);
+        }
+
+        // the copy has the checksums of the source and the requested one
+        let mut algorithms = src_md
+            .checksums
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if let Some(algo) = i.checksum_algorithm() {
+            algorithms.push(algo.as_str());
+        }
+
+        let reservation = self
+            .reserve(src_md.content_length.max(0) as u64)
+            .await
+            .ok_or_else(|| to_internal_err(S3Error::new("SlowDown", "Write queue is full")))?;
+        let md = self
+            .stage_and_commit(
+                &dst_entry,
+                &md,
+                &mut body,
+                ObjectHasher::new(&algorithms),
+                Some(&reservation),
+            )
+            .await
+            .map_err(to_internal_err)?;
+        self.wakeup.notify_one();
+        info!(
+            "Write queue copy: {}/{} to {}/{}",
+            src_bucket, src_key, md.bucket, md.key
+        );
+        Ok(Some(
+            CopyObjectOutput::builder()
+                .set_copy_source_version_id(src_version_id)
+                .copy_object_result(
+                    CopyObjectResult::builder()
+                        .set_e_tag(md.e_tag.clone())
+                        .last_modified(md.last_modified_time())
+                        .set_checksum_crc32(md.checksums.get(CRC32).cloned())
+                        .set_checksum_crc32_c(md.checksums.get(CRC32C).cloned())
+                        .set_checksum_sha1(md.checksums.get(SHA1).cloned())
+                        .set_checksum_sha256(md.checksums.get(SHA256).cloned())
+                        .build(),
+                )
+                .build(),
+        ))
+    }
+
+    /// put_remote_copy writes a copy that does not match the write queue filter
+    /// directly to the remote, when its source is queued and the remote cannot copy it.
+    async fn put_remote_copy(
+        &self,
+        md: &ObjectMd,
+        body: ByteStream,
+    ) -> Result<CopyObjectOutput, CopyObjectError> {
+        if !self.connectivity.is_online() {
+            return Err(to_internal_err(remote_unavailable()));
+        }
+        let claim = self.claim_entry(&md.bucket, &md.key).await;
+        let req = self
+            .s3_client
+            .put_object()
+            .bucket(&md.bucket)
+            .key(&md.key)
+            .body(body);
+        let res = md
+            .apply_to_put_object(req)
+            .send()
+            .await
+            .map_err(to_gateway_err)?;
+        if let Some(claim) = claim {
+            if let Err(err) = claim.replaced().await {
+                warn!("Write queue copy: remove replaced queued write {}", err);
+            }
+        }
+        info!(
+            "Write queue copy: filter does not match, written to remote {}/{}",
+            md.bucket, md.key
+        );
+        Ok(CopyObjectOutput::builder()
+            .copy_object_result(
+                CopyObjectResult::builder()
+                    .set_e_tag(res.e_tag().map(String::from))
+                    .last_modified(md.last_modified_time())
+                    .set_checksum_crc32(res.checksum_crc32().map(String::from))
+                    .set_checksum_crc32_c(res.checksum_crc32_c().map(String::from))
+                    .set_checksum_sha1(res.checksum_sha1().map(String::from))
+                    .set_checksum_sha256(res.checksum_sha256().map(String::from))
+                    .build(),
+            )
+            .build())
+    }
+
+    /// get_remote_source reads the source of a copy (or a range of it) from the remote,
+    /// with its tags.
+    pub async fn get_remote_source<E: From<InternalServerError>>(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        range: Option<String>,
+    ) -> Result<(ObjectMd, ByteStream), E> {
+        if !self.connectivity.is_online() {
+            return Err(to_internal_err(remote_unavailable()));
+        }
+        let res = self
+            .s3_client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_version_id(version_id.map(String::from))
+            .set_range(range)
+            .send()
+            .await
+            .map_err(to_gateway_err)?;
+        let mut md = ObjectMd::from_client_get_object_output(bucket, key, &res);
+        if res.tag_count() > 0 {
+            let tagging = self
+                .s3_client
+                .get_object_tagging()
+                .bucket(bucket)
+                .key(key)
+                .set_version_id(version_id.map(String::from))
+                .send()
+                .await
+                .map_err(to_gateway_err)?;
+            md.tags = tagging
+                .tag_set()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|t| Some((t.key()?.to_string(), t.value()?.to_string())))
+                .collect::<BTreeMap<_, _>>();
+        }
+        Ok((md, res.body))
+    }
+}
+
+/// parse_copy_source parses the `x-amz-copy-source` header value,
+/// which is the urlencoded `bucket/key` with an optional `?versionId=` suffix.
+pub fn parse_copy_source(copy_source: &str) -> Result<(String, String, Option<String>), S3Error> {
+    let invalid = || S3Error::new("InvalidArgument", "Invalid copy source");
+    let copy_source = copy_source.strip_prefix('/').unwrap_or(copy_source);
+    let (path, version_id) = match copy_source.split_once("?versionId=") {
+        Some((path, version_id)) => (path, Some(version_id.to_string())),
+        None => (copy_source, None),
+    };
+    let path = urlencoding::decode(path).map_err(|_| invalid())?;
+    let (bucket, key) = path.split_once('/').ok_or_else(invalid)?;
+    if bucket.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    Ok((bucket.to_string(), key.to_string(), version_id))
+}
+
+/// parse_copy_source_range parses the `x-amz-copy-source-range` header value,
+/// which is a range of the form `bytes=first-last`, to the first and last offsets.
+pub fn parse_copy_source_range(header: &str) -> Result<(u64, u64), S3Error> {
+    match ByteRange::parse(header) {
+        Some(ByteRange::FromTo(first, last)) => Ok((first, last)),
+        _ => Err(S3Error::new(
+            "InvalidArgument",
+            "The x-amz-copy-source-range value must be of the form bytes=first-last \
+             where first and last are the zero-based offsets of the first and last bytes to copy",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_sources() {
+        let parse = |s: &str| parse_copy_source(s).map_err(|err| err.code);
+        let source = |bucket: &str, key: &str, version_id: Option<&str>| {
+            Ok((
+                bucket.to_string(),
+                key.to_string(),
+                version_id.map(String::from),
+            ))
+        };
+        assert_eq!(parse("bucket/key"), source("bucket", "key", None));
+        assert_eq!(
+            parse("/bucket/dir/a%20b%2Bc"),
+            source("bucket", "dir/a b+c", None)
+        );
+        assert_eq!(
+            parse("bucket/key?versionId=v1"),
+            source("bucket", "key", Some("v1"))
+        );
+        for bad in ["", "bucket", "bucket/", "/key", "bucket%2F", "%FF/key"] {
+            assert_eq!(parse(bad), Err("InvalidArgument"), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn copy_source_ranges() {
+        assert_eq!(parse_copy_source_range("bytes=0-9").unwrap(), (0, 9));
+        for bad in ["bytes=0-", "bytes=-10", "bytes=9-0", "0-9"] {
+            assert!(parse_copy_source_range(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
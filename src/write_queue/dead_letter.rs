@@ -0,0 +1,138 @@
+//! Dead letters of the write queue.
+//!
+//! Entries that failed to push with permanent errors are moved out of the queue
+//! into the dead letter dir, together with their sidecar and push state,
+//! and stay there until they are retried (moved back to the queue) or discarded.
+//!
+//! This works directly on the queue dir, without the journal and the stats of the
+//! daemon, so the `s3d dead-letter` command changes dead letters only while holding
+//! the lock of the queue dir (see `lock_queue_dir`), and refuses to run while s3d holds it.
+//! Files are only hard-linked into place and never replace existing entries.
+
+use crate::object_md::{md_path, ObjectMd};
+use crate::utils::sync_dir;
+use crate::write_queue::push_state::{PushState, PUSH_STATE_SUFFIX};
+use crate::write_queue::{is_entry_name, DEAD_LETTER_DIR, PUSH_STATE_DIR};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub entry: String,
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+    pub md: Option<ObjectMd>,
+    pub state: PushState,
+}
+
+pub struct DeadLetters {
+    pub write_queue_dir: PathBuf,
+}
+
+impl DeadLetters {
+    pub fn new(write_queue_dir: &str) -> DeadLetters {
+        DeadLetters {
+            write_queue_dir: PathBuf::from(write_queue_dir),
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.write_queue_dir.join(DEAD_LETTER_DIR)
+    }
+
+    pub fn entry_path(&self, entry: &str) -> PathBuf {
+        self.dir().join(entry)
+    }
+
+    pub fn state_path(&self, entry: &str) -> PathBuf {
+        self.dir().join(format!("{}{}", entry, PUSH_STATE_SUFFIX))
+    }
+
+    pub fn to_entry_name(bucket: &str, key: &str) -> String {
+        urlencoding::encode(&format!("{}/{}", bucket, key)).into_owned()
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        let mut items = vec![];
+        let mut dir = match tokio::fs::read_dir(self.dir()).await {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(items),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(item) = dir.next_entry().await? {
+            let entry = item.file_name().to_string_lossy().to_string();
+            if !is_entry_name(&entry) || entry.ends_with(PUSH_STATE_SUFFIX) {
+                continue;
+            }
+            let bucket_path = urlencoding::decode(&entry)?.into_owned();
+            let mut parts = bucket_path.splitn(2, '/');
+            let bucket = parts.next().unwrap_or("").to_string();
+            let key = parts.next().unwrap_or("").to_string();
+            let path = self.entry_path(&entry);
+            let md = ObjectMd::read(&md_path(&path)).await.ok();
+            let state = PushState::read(&self.state_path(&entry)).await;
+            items.push(DeadLetter {
+                size: item.metadata().await?.len(),
+                entry,
+                bucket,
+                key,
+                md,
+                state,
+            });
+        }
+        items.sort_by(|a, b| a.entry.cmp(&b.entry));
+        Ok(items)
+    }
+
+    /// retry moves a dead letter back to the queue, with a fresh push state.
+    /// Fails if the same object was queued again in the meantime,
+    /// since the newer write should win - discard the dead letter instead.
+    pub async fn retry(&self, bucket: &str, key: &str) -> anyhow::Result<()> {
+        let entry = DeadLetters::to_entry_name(bucket, key);
+        let dead = self.entry_path(&entry);
+        let target = self.write_queue_dir.join(&entry);
+        tokio::fs::metadata(&dead)
+            .await
+            .map_err(|_| anyhow::anyhow!("No dead letter for {}/{}", bucket, key))?;
+        if tokio::fs::metadata(&target).await.is_ok() {
+            anyhow::bail!("A newer write of {}/{} is queued", bucket, key);
+        }
+        tokio::fs::remove_file(
+            self.write_queue_dir
+                .join(PUSH_STATE_DIR)
+                .join(format!("{}{}", entry, PUSH_STATE_SUFFIX)),
+        )
+        .await
+        .ok();
+        // link the sidecar before the body, like a commit renames them
+        let has_md = tokio::fs::metadata(md_path(&dead)).await.is_ok();
+        if has_md {
+            tokio::fs::hard_link(md_path(&dead), md_path(&target)).await?;
+        }
+        if let Err(err) = tokio::fs::hard_link(&dead, &target).await {
+            if has_md {
+                tokio::fs::remove_file(md_path(&target)).await.ok();
+            }
+            return Err(err.into());
+        }
+        sync_dir(&self.write_queue_dir).await?;
+        self.discard(bucket, key).await
+    }
+
+    /// discard removes a dead letter for good.
+    pub async fn discard(&self, bucket: &str, key: &str) -> anyhow::Result<()> {
+        let entry = DeadLetters::to_entry_name(bucket, key);
+        let dead = self.entry_path(&entry);
+        remove_if_exists(&dead).await?;
+        remove_if_exists(&md_path(&dead)).await?;
+        remove_if_exists(&self.state_path(&entry)).await?;
+        sync_dir(&self.dir()).await
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,299 @@
+//! Write queue journal.
+//!
+//! The journal is an append-only text file with one record per line:
+//!
+//! - `begin <id> <entry>` - a new body for `<entry>` is being staged under `<id>`.
+//! - `commit <id> <entry>` - the staged body is complete and synced, and is being moved into the queue.
+//! - `done <entry>` - the entry was pushed to the remote and is being removed from the queue.
+//!
+//! Every record is synced to disk before the step it describes proceeds,
+//! so after a crash we can tell staged bodies that are complete (`commit`)
+//! from ones that were cut off mid-upload (`begin` only).
+
+use crate::utils::sync_dir;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Begin { id: String, entry: String },
+    Commit { id: String, entry: String },
+    Done { entry: String },
+}
+
+impl Record {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Record::Begin { id, .. } | Record::Commit { id, .. } => Some(id),
+            Record::Done { .. } => None,
+        }
+    }
+
+    pub fn entry(&self) -> &str {
+        match self {
+            Record::Begin { entry, .. } | Record::Commit { entry, .. } | Record::Done { entry } => {
+                entry
+            }
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Record::Begin { id, entry } => format!("begin {} {}\n", id, entry),
+            Record::Commit { id, entry } => format!("commit {} {}\n", id, entry),
+            Record::Done { entry } => format!("done {}\n", entry),
+        }
+    }
+
+    pub fn from_line(line: &str) -> Option<Record> {
+        let mut parts = line.split(' ');
+        let rec = match (parts.next(), parts.next(), parts.next()) {
+            (Some("begin"), Some(id), Some(entry)) => Record::Begin {
+                id: id.to_string(),
+                entry: entry.to_string(),
+            },
+            (Some("commit"), Some(id), Some(entry)) => Record::Commit {
+                id: id.to_string(),
+                entry: entry.to_string(),
+            },
+            (Some("done"), Some(entry), None) => Record::Done {
+                entry: entry.to_string(),
+            },
+            _ => return None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(rec)
+    }
+}
+
+/// Replay tells recovery what to do with the records of a journal.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Replay<'a> {
+    /// Staged ids to resume into their entry, which were committed
+    /// and are still the last record of the entry.
+    pub resume: HashMap<&'a str, &'a str>,
+    /// Entries whose last record is done, which were pushed and should be removed.
+    pub pushed: Vec<&'a str>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(records: &'a [Record]) -> Replay<'a> {
+        let mut last_by_entry = HashMap::<&str, &Record>::new();
+        for rec in records.iter() {
+            last_by_entry.insert(rec.entry(), rec);
+        }
+        let mut replay = Replay::default();
+        for (entry, rec) in last_by_entry {
+            match rec {
+                Record::Commit { id, .. } => {
+                    replay.resume.insert(id.as_str(), entry);
+                }
+                Record::Done { .. } => replay.pushed.push(entry),
+                Record::Begin { .. } => {}
+            }
+        }
+        replay.pushed.sort_unstable();
+        replay
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub async fn open(path: &Path) -> anyhow::Result<Journal> {
+        let file = Self::open_append(path).await?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    async fn open_append(path: &Path) -> anyhow::Result<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?)
+    }
+
+    /// read_records parses the journal file, ignoring a torn last line
+    /// which can be left by a crash in the middle of an append.
+    pub async fn read_records(path: &Path) -> anyhow::Result<Vec<Record>> {
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let complete = match text.rfind('\n') {
+            Some(pos) => &text[..pos],
+            None => "",
+        };
+        Ok(complete
+            .lines()
+            .filter_map(|line| {
+                let rec = Record::from_line(line);
+                if rec.is_none() {
+                    warn!("Write queue journal: skipping bad record {:?}", line);
+                }
+                rec
+            })
+            .collect())
+    }
+
+    pub async fn append(&self, rec: &Record) -> anyhow::Result<()> {
+        let mut file = self.file.lock().await;
+        file.write_all(rec.to_line().as_bytes()).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// compact rewrites the journal keeping only the records for which `keep` returns true.
+    /// The new journal is written aside and renamed over the old one so that a crash
+    /// during compaction leaves either the old or the new journal intact.
+    pub async fn compact<F>(&self, keep: F) -> anyhow::Result<()>
+    where
+        F: Fn(&Record) -> bool,
+    {
+        let mut file = self.file.lock().await;
+        let records = Self::read_records(&self.path).await?;
+        let kept: String = records
+            .iter()
+            .filter(|rec| keep(rec))
+            .map(Record::to_line)
+            .collect();
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::create(&tmp_path).await?;
+        tmp.write_all(kept.as_bytes()).await?;
+        tmp.sync_all().await?;
+        drop(tmp);
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        if let Some(dir) = self.path.parent() {
+            sync_dir(dir).await?;
+        }
+        *file = Self::open_append(&self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s3d-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn begin(id: &str, entry: &str) -> Record {
+        Record::Begin {
+            id: id.to_string(),
+            entry: entry.to_string(),
+        }
+    }
+
+    fn commit(id: &str, entry: &str) -> Record {
+        Record::Commit {
+            id: id.to_string(),
+            entry: entry.to_string(),
+        }
+    }
+
+    fn done(entry: &str) -> Record {
+        Record::Done {
+            entry: entry.to_string(),
+        }
+    }
+
+    #[test]
+    fn record_lines() {
+        for rec in [begin("id1", "b/k"), commit("id1", "b/k"), done("b/k")] {
+            let line = rec.to_line();
+            assert!(line.ends_with('\n'));
+            assert_eq!(Record::from_line(line.trim_end()), Some(rec));
+        }
+        for line in [
+            "",
+            "begin id1",
+            "done",
+            "done b/k extra",
+            "commit a b c",
+            "put id1 b/k",
+        ] {
+            assert_eq!(Record::from_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_records_skips_torn_line() {
+        let path = temp_path("journal");
+        assert_eq!(Journal::read_records(&path).await.unwrap(), vec![]);
+        std::fs::write(&path, "begin id1 b/k\nbad\ncommit id1 b/k\ndone b/").unwrap();
+        assert_eq!(
+            Journal::read_records(&path).await.unwrap(),
+            vec![begin("id1", "b/k"), commit("id1", "b/k")]
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let records = vec![
+            // cut off while staging
+            begin("id1", "b/partial"),
+            // committed
+            begin("id2", "b/committed"),
+            commit("id2", "b/committed"),
+            // committed, and replaced by a newer write that was cut off
+            begin("id3", "b/replaced"),
+            commit("id3", "b/replaced"),
+            begin("id4", "b/replaced"),
+            // committed and pushed
+            begin("id5", "b/pushed"),
+            commit("id5", "b/pushed"),
+            done("b/pushed"),
+            // pushed, and written again
+            begin("id6", "b/again"),
+            commit("id6", "b/again"),
+            done("b/again"),
+            begin("id7", "b/again"),
+            commit("id7", "b/again"),
+        ];
+        let replay = Replay::new(&records);
+        assert_eq!(
+            replay.resume,
+            HashMap::from([("id2", "b/committed"), ("id7", "b/again")])
+        );
+        assert_eq!(replay.pushed, vec!["b/pushed"]);
+    }
+
+    #[tokio::test]
+    async fn compact_and_append() {
+        let path = temp_path("journal");
+        let journal = Journal::open(&path).await.unwrap();
+        for rec in [
+            begin("id1", "b/k1"),
+            begin("id2", "b/k2"),
+            commit("id1", "b/k1"),
+        ] {
+            journal.append(&rec).await.unwrap();
+        }
+        journal
+            .compact(|rec| rec.id() == Some("id2"))
+            .await
+            .unwrap();
+        journal.append(&done("b/k2")).await.unwrap();
+        assert_eq!(
+            Journal::read_records(&path).await.unwrap(),
+            vec![begin("id2", "b/k2"), done("b/k2")]
+        );
+        assert!(!path.with_extension("compact").exists());
+    }
+}
@@ -0,0 +1,740 @@
+//! Listing objects together with the write queue.
+//!
+//! Listings are served by the remote, which knows nothing about writes that
+//! were not pushed yet, so the router lists the remote and then merges the queued
+//! entries of the bucket into the page - queued objects are added (replacing the
+//! remote object of the same key) and keys with a queued tombstone are hidden.
+//!
+//! A remote page covers the keys from the marker up to its last key or common prefix,
+//! and only queued keys in that range are merged, so that paging through the merged
+//! listing returns every key once. The merged page can have more than max-keys items,
+//! in which case it is cut and the next page starts after its last item. Otherwise,
+//! if the remote page is truncated, the next page starts after the last remote item.
+//! For that reason ListObjectsV2 continuation tokens are made by s3d (they encode
+//! the last listed key), and the remote is listed with start-after instead.
+//!
+//! The remote is listed without encoding-type, and keys are returned as is.
+
+use crate::object_md::ObjectMd;
+use crate::s3::errors::S3Error;
+use crate::utils::{is_not_found, to_internal_err};
+use crate::write_queue::{is_entry_name, parse_entry_name, WriteQueue};
+use s3d_smithy_codegen_server_s3::{
+    error::{ListObjectVersionsError, ListObjectsError, ListObjectsV2Error},
+    input::{ListObjectVersionsInput, ListObjectsInput, ListObjectsV2Input},
+    model::{
+        CommonPrefix, DeleteMarkerEntry, Object, ObjectStorageClass, ObjectVersion,
+        ObjectVersionStorageClass,
+    },
+    output::{ListObjectVersionsOutput, ListObjectsOutput, ListObjectsV2Output},
+};
+use std::collections::BTreeMap;
+
+const DEFAULT_MAX_KEYS: i32 = 1000;
+const TOKEN_PREFIX: &str = "s3d:";
+
+/// Version is an item of a ListObjectVersions page.
+#[derive(Debug, Clone)]
+pub enum Version {
+    Object(ObjectVersion),
+    DeleteMarker(DeleteMarkerEntry),
+}
+
+impl WriteQueue {
+    pub fn list_objects_remote_input(
+        &self,
+        i: &ListObjectsInput,
+    ) -> Result<ListObjectsInput, ListObjectsError> {
+        let mut remote = i.clone();
+        remote.encoding_type = None;
+        Ok(remote)
+    }
+
+    pub async fn merge_list_objects(
+        &self,
+        i: &ListObjectsInput,
+        remote: ListObjectsOutput,
+    ) -> Result<ListObjectsOutput, ListObjectsError> {
+        let prefix = i.prefix().unwrap_or("");
+        let max_keys = max_keys_or_default(i.max_keys());
+        let queued = self
+            .queued_objects(i.bucket(), prefix)
+            .await
+            .map_err(to_internal_err)?;
+        let remote_items = object_items(remote.contents(), remote.common_prefixes());
+        let remote_next = remote_next_marker(&remote_items, remote.is_truncated());
+        let items = merge_page(
+            remote_items,
+            remote.is_truncated(),
+            &queued,
+            i.marker().unwrap_or(""),
+            prefix,
+            i.delimiter(),
+            |_, md| queued_object(md),
+        );
+        let (contents, common_prefixes, next_marker) =
+            cut_objects_page(items, max_keys, remote_next);
+        Ok(ListObjectsOutput::builder()
+            .name(i.bucket())
+            .set_prefix(i.prefix().map(String::from))
+            .set_delimiter(i.delimiter().map(String::from))
+            .set_marker(i.marker().map(String::from))
+            .max_keys(max_keys as i32)
+            .is_truncated(next_marker.is_some())
+            .set_next_marker(next_marker)
+            .set_contents(Some(contents))
+            .set_common_prefixes(Some(common_prefixes))
+            .build())
+    }
+
+    /// list_objects_v2_remote_input replaces the continuation token with start-after,
+    /// since tokens are made by s3d (see the module docs).
+    pub fn list_objects_v2_remote_input(
+        &self,
+        i: &ListObjectsV2Input,
+    ) -> Result<ListObjectsV2Input, ListObjectsV2Error> {
+        let mut remote = i.clone();
+        remote.encoding_type = None;
+        remote.continuation_token = None;
+        remote.start_after = list_v2_start(i).map_err(to_internal_err)?;
+        Ok(remote)
+    }
+
+    pub async fn merge_list_objects_v2(
+        &self,
+        i: &ListObjectsV2Input,
+        remote: ListObjectsV2Output,
+    ) -> Result<ListObjectsV2Output, ListObjectsV2Error> {
+        let prefix = i.prefix().unwrap_or("");
+        let max_keys = max_keys_or_default(i.max_keys());
+        let start = list_v2_start(i).map_err(to_internal_err)?;
+        let queued = self
+            .queued_objects(i.bucket(), prefix)
+            .await
+            .map_err(to_internal_err)?;
+        let remote_items = object_items(remote.contents(), remote.common_prefixes());
+        let remote_next = remote_next_marker(&remote_items, remote.is_truncated());
+        let items = merge_page(
+            remote_items,
+            remote.is_truncated(),
+            &queued,
+            start.as_deref().unwrap_or(""),
+            prefix,
+            i.delimiter(),
+            |_, md| queued_object(md),
+        );
+        let (contents, common_prefixes, next_marker) =
+            cut_objects_page(items, max_keys, remote_next);
+        Ok(ListObjectsV2Output::builder()
+            .name(i.bucket())
+            .set_prefix(i.prefix().map(String::from))
+            .set_delimiter(i.delimiter().map(String::from))
+            .set_continuation_token(i.continuation_token().map(String::from))
+            .set_start_after(i.start_after().map(String::from))
+            .max_keys(max_keys as i32)
+            .key_count((contents.len() + common_prefixes.len()) as i32)
+            .is_truncated(next_marker.is_some())
+            .set_next_continuation_token(next_marker.map(|m| to_continuation_token(&m)))
+            .set_contents(Some(contents))
+            .set_common_prefixes(Some(common_prefixes))
+            .build())
+    }
+
+    pub fn list_object_versions_remote_input(
+        &self,
+        i: &ListObjectVersionsInput,
+    ) -> Result<ListObjectVersionsInput, ListObjectVersionsError> {
+        let mut remote = i.clone();
+        remote.encoding_type = None;
+        Ok(remote)
+    }
+
+    /// merge_list_object_versions lists a queued write as the latest version of its key
+    /// (a tombstone as the latest delete marker), before the versions of the remote.
+    /// Queued versions have no version id yet, so a page is never cut right after one.
+    pub async fn merge_list_object_versions(
+        &self,
+        i: &ListObjectVersionsInput,
+        remote: ListObjectVersionsOutput,
+    ) -> Result<ListObjectVersionsOutput, ListObjectVersionsError> {
+        let prefix = i.prefix().unwrap_or("");
+        let max_keys = max_keys_or_default(i.max_keys());
+        let queued = self
+            .queued_objects(i.bucket(), prefix)
+            .await
+            .map_err(to_internal_err)?;
+
+        let mut by_key = BTreeMap::<String, Vec<Version>>::new();
+        for v in remote.versions().unwrap_or_default() {
+            if let Some(key) = v.key() {
+                by_key
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(Version::Object(v.clone()));
+            }
+        }
+        for d in remote.delete_markers().unwrap_or_default() {
+            if let Some(key) = d.key() {
+                by_key
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(Version::DeleteMarker(d.clone()));
+            }
+        }
+        let mut remote_items = by_key
+            .into_iter()
+            .map(|(key, mut versions)| {
+                // newest first, as the remote lists them
+                versions.sort_by_key(|v| std::cmp::Reverse(v.last_modified_key()));
+                (key, Some(versions))
+            })
+            .collect::<Vec<_>>();
+        for cp in remote.common_prefixes().unwrap_or_default() {
+            if let Some(p) = cp.prefix() {
+                remote_items.push((p.to_string(), None));
+            }
+        }
+
+        // a key marker with a version id marker is listed partially by the remote,
+        // and its queued version was already listed before the remote versions.
+        let items = merge_page(
+            remote_items,
+            remote.is_truncated(),
+            &queued,
+            i.key_marker().unwrap_or(""),
+            prefix,
+            i.delimiter(),
+            |remote_versions, md| {
+                let mut versions = remote_versions.unwrap_or_default();
+                for v in versions.iter_mut() {
+                    v.set_is_latest(false);
+                }
+                versions.insert(0, queued_version(md));
+                Some(versions)
+            },
+        );
+
+        let mut versions = vec![];
+        let mut delete_markers = vec![];
+        let mut common_prefixes = vec![];
+        let mut count = 0;
+        let mut last = (String::new(), None);
+        let mut next_markers = None;
+        'items: for (name, item) in items.into_iter() {
+            if count >= max_keys {
+                next_markers = Some(last);
+                break;
+            }
+            match item {
+                None => {
+                    count += 1;
+                    common_prefixes.push(CommonPrefix::builder().prefix(&name).build());
+                    last = (name, None);
+                }
+                Some(key_versions) => {
+                    for v in key_versions {
+                        // a page cannot end with a version without an id,
+                        // since the next page could not start after it.
+                        if count >= max_keys && last.1.is_some() {
+                            next_markers = Some(last);
+                            break 'items;
+                        }
+                        count += 1;
+                        last = (name.clone(), v.version_id());
+                        match v {
+                            Version::Object(o) => versions.push(o),
+                            Version::DeleteMarker(d) => delete_markers.push(d),
+                        }
+                    }
+                }
+            }
+        }
+        let next_markers = next_markers.or_else(|| {
+            if remote.is_truncated() {
+                Some((
+                    remote.next_key_marker().unwrap_or("").to_string(),
+                    remote.next_version_id_marker().map(String::from),
+                ))
+            } else {
+                None
+            }
+        });
+        let is_truncated = next_markers.is_some();
+        let (next_key_marker, next_version_id_marker) = match next_markers {
+            Some((key, version_id)) => (Some(key), version_id),
+            None => (None, None),
+        };
+        Ok(ListObjectVersionsOutput::builder()
+            .name(i.bucket())
+            .set_prefix(i.prefix().map(String::from))
+            .set_delimiter(i.delimiter().map(String::from))
+            .set_key_marker(i.key_marker().map(String::from))
+            .set_version_id_marker(i.version_id_marker().map(String::from))
+            .max_keys(max_keys as i32)
+            .is_truncated(is_truncated)
+            .set_next_key_marker(next_key_marker)
+            .set_next_version_id_marker(next_version_id_marker)
+            .set_versions(Some(versions))
+            .set_delete_markers(Some(delete_markers))
+            .set_common_prefixes(Some(common_prefixes))
+            .build())
+    }
+
+    /// queued_objects returns the meta-data of the queued entries of a bucket
+    /// under the prefix by key, including tombstones. Entry names are filtered
+    /// by the prefix before any sidecar is read (see `read_entry_md`).
+    pub async fn queued_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> anyhow::Result<BTreeMap<String, ObjectMd>> {
+        let mut objects = BTreeMap::new();
+        let mut queue = tokio::fs::read_dir(&self.write_queue_dir).await?;
+        while let Some(entry) = queue.next_entry().await? {
+            let entry_name = entry.file_name().to_string_lossy().to_string();
+            if !is_entry_name(&entry_name) {
+                continue;
+            }
+            let (entry_bucket, key) = match parse_entry_name(&entry_name) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if entry_bucket != bucket || !key.starts_with(prefix) {
+                continue;
+            }
+            // the entry may be pushed and removed since it was listed
+            let md = match self.read_entry_md(&entry_name).await {
+                Ok(md) => md,
+                Err(err) if is_not_found(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            objects.insert(key, md);
+        }
+        Ok(objects)
+    }
+}
+
+/// merge_page merges the queued objects into the items of a remote page, which are
+/// pairs of key and item, or of common prefix and None. The remote was listed after
+/// the `after` marker, so queued keys and remote common prefixes up to it are skipped,
+/// and `merge` returns the item of a queued key given the remote item of the key if any,
+/// or None to hide the key. Returns the items sorted by key.
+pub fn merge_page<T, F>(
+    remote_items: Vec<(String, Option<T>)>,
+    remote_truncated: bool,
+    queued: &BTreeMap<String, ObjectMd>,
+    after: &str,
+    prefix: &str,
+    delimiter: Option<&str>,
+    merge: F,
+) -> Vec<(String, Option<T>)>
+where
+    F: Fn(Option<T>, &ObjectMd) -> Option<T>,
+{
+    // keys under a common prefix are sorted right after it, so the page
+    // range is compared by the rolled up names of keys.
+    let bound = remote_next_marker(&remote_items, remote_truncated);
+    let mut items = remote_items
+        .into_iter()
+        .filter(|(name, item)| item.is_some() || name.as_str() > after)
+        .collect::<BTreeMap<_, _>>();
+    for (key, md) in queued.iter() {
+        let common_prefix = common_prefix(key, prefix, delimiter);
+        let name = common_prefix.as_deref().unwrap_or(key);
+        if name <= after {
+            continue;
+        }
+        if let Some(bound) = bound.as_deref() {
+            if name > bound {
+                continue;
+            }
+        }
+        if common_prefix.is_some() {
+            // a prefix with tombstones only may still be listed by the remote
+            if !md.delete_marker {
+                items.insert(name.to_string(), None);
+            }
+            continue;
+        }
+        let remote_item = items.remove(key).flatten();
+        if let Some(item) = merge(remote_item, md) {
+            items.insert(key.clone(), Some(item));
+        }
+    }
+    items.into_iter().collect()
+}
+
+/// remote_next_marker returns the last key or common prefix of a truncated remote page,
+/// which is where the next page of the merged listing continues from.
+pub fn remote_next_marker<T>(
+    remote_items: &[(String, Option<T>)],
+    remote_truncated: bool,
+) -> Option<String> {
+    if !remote_truncated {
+        return None;
+    }
+    remote_items.iter().map(|(name, _)| name.clone()).max()
+}
+
+/// common_prefix returns the prefix that the key is rolled up into by the delimiter.
+pub fn common_prefix(key: &str, prefix: &str, delimiter: Option<&str>) -> Option<String> {
+    let d = delimiter.filter(|d| !d.is_empty())?;
+    key.get(prefix.len()..)?
+        .find(d)
+        .map(|pos| key[..prefix.len() + pos + d.len()].to_string())
+}
+
+fn object_items(
+    contents: Option<&[Object]>,
+    common_prefixes: Option<&[CommonPrefix]>,
+) -> Vec<(String, Option<Object>)> {
+    let objects = contents
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|o| o.key().map(|key| (key.to_string(), Some(o.clone()))));
+    let prefixes = common_prefixes
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|cp| cp.prefix().map(|p| (p.to_string(), None)));
+    objects.chain(prefixes).collect()
+}
+
+/// cut_objects_page returns up to max-keys objects and common prefixes of the merged items,
+/// and the marker to continue from if there are more (see `cut_page`).
+pub fn cut_objects_page(
+    items: Vec<(String, Option<Object>)>,
+    max_keys: usize,
+    remote_next: Option<String>,
+) -> (Vec<Object>, Vec<CommonPrefix>, Option<String>) {
+    let (items, next_marker) = cut_page(items, max_keys, remote_next);
+    let mut contents = vec![];
+    let mut common_prefixes = vec![];
+    for (name, item) in items {
+        match item {
+            Some(object) => contents.push(object),
+            None => common_prefixes.push(CommonPrefix::builder().prefix(&name).build()),
+        }
+    }
+    (contents, common_prefixes, next_marker)
+}
+
+/// cut_page returns up to max-keys of the merged items, and the marker to continue from
+/// if there are more. When the page is not cut, the listing continues from the next
+/// marker of the remote page (see `remote_next_marker`), if any, even if the merged page
+/// is shorter or empty because of hidden keys.
+pub fn cut_page<T>(
+    mut items: Vec<(String, Option<T>)>,
+    max_keys: usize,
+    remote_next: Option<String>,
+) -> (Vec<(String, Option<T>)>, Option<String>) {
+    if items.len() <= max_keys {
+        return (items, remote_next);
+    }
+    items.truncate(max_keys);
+    let next_marker = items.last().map(|(name, _)| name.clone());
+    (items, next_marker)
+}
+
+pub fn queued_object(md: &ObjectMd) -> Option<Object> {
+    if md.delete_marker {
+        return None;
+    }
+    Some(
+        Object::builder()
+            .key(&md.key)
+            .size(md.content_length)
+            .last_modified(md.last_modified_time())
+            .set_e_tag(md.e_tag.clone())
+            .set_storage_class(md.storage_class.as_deref().map(ObjectStorageClass::from))
+            .build(),
+    )
+}
+
+fn queued_version(md: &ObjectMd) -> Version {
+    if md.delete_marker {
+        return Version::DeleteMarker(
+            DeleteMarkerEntry::builder()
+                .key(&md.key)
+                .is_latest(true)
+                .last_modified(md.last_modified_time())
+                .build(),
+        );
+    }
+    Version::Object(
+        ObjectVersion::builder()
+            .key(&md.key)
+            .is_latest(true)
+            .size(md.content_length)
+            .last_modified(md.last_modified_time())
+            .set_e_tag(md.e_tag.clone())
+            .set_storage_class(
+                md.storage_class
+                    .as_deref()
+                    .map(ObjectVersionStorageClass::from),
+            )
+            .build(),
+    )
+}
+
+pub fn max_keys_or_default(max_keys: i32) -> usize {
+    if max_keys > 0 {
+        max_keys as usize
+    } else {
+        DEFAULT_MAX_KEYS as usize
+    }
+}
+
+/// list_v2_start returns the key to list after, which is the key in the
+/// continuation token, or the start-after key of the first page.
+pub fn list_v2_start(i: &ListObjectsV2Input) -> anyhow::Result<Option<String>> {
+    let token_key = match i.continuation_token() {
+        Some(token) => Some(from_continuation_token(token).ok_or_else(|| {
+            S3Error::new(
+                "InvalidArgument",
+                "The continuation token provided is incorrect",
+            )
+        })?),
+        None => None,
+    };
+    Ok(match (token_key, i.start_after()) {
+        (Some(key), Some(start_after)) if start_after > key.as_str() => {
+            Some(start_after.to_string())
+        }
+        (Some(key), _) => Some(key),
+        (None, start_after) => start_after.map(String::from),
+    })
+}
+
+pub fn to_continuation_token(key: &str) -> String {
+    base64::encode(format!("{}{}", TOKEN_PREFIX, key))
+}
+
+fn from_continuation_token(token: &str) -> Option<String> {
+    let decoded = String::from_utf8(base64::decode(token).ok()?).ok()?;
+    decoded.strip_prefix(TOKEN_PREFIX).map(String::from)
+}
+
+impl Version {
+    pub fn version_id(&self) -> Option<String> {
+        match self {
+            Version::Object(o) => o.version_id().map(String::from),
+            Version::DeleteMarker(d) => d.version_id().map(String::from),
+        }
+    }
+
+    /// last_modified_key is used to sort versions by time.
+    pub fn last_modified_key(&self) -> (i64, u32) {
+        let t = match self {
+            Version::Object(o) => o.last_modified(),
+            Version::DeleteMarker(d) => d.last_modified(),
+        };
+        t.map(|t| (t.secs(), t.subsec_nanos())).unwrap_or_default()
+    }
+
+    pub fn set_is_latest(&mut self, is_latest: bool) {
+        match self {
+            Version::Object(o) => o.is_latest = is_latest,
+            Version::DeleteMarker(d) => d.is_latest = is_latest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::ObjectHasher;
+    use crate::write_queue::tests::test_queue;
+    use aws_smithy_http::byte_stream::ByteStream;
+
+    fn queued(keys: &[&str], tombstones: &[&str]) -> BTreeMap<String, ObjectMd> {
+        let mut objects = BTreeMap::new();
+        for key in keys {
+            let md = ObjectMd {
+                key: key.to_string(),
+                ..ObjectMd::default()
+            };
+            objects.insert(key.to_string(), md);
+        }
+        for key in tombstones {
+            objects.insert(key.to_string(), ObjectMd::tombstone("b", key));
+        }
+        objects
+    }
+
+    fn merge_queued(_: Option<String>, md: &ObjectMd) -> Option<String> {
+        (!md.delete_marker).then(|| "queued".to_string())
+    }
+
+    /// list_all pages through a merged listing of the remote keys,
+    /// with a remote that returns `remote_max` keys per page.
+    fn list_all(
+        remote: &[&str],
+        queued: &BTreeMap<String, ObjectMd>,
+        remote_max: usize,
+        max_keys: usize,
+    ) -> Vec<String> {
+        let mut listed = vec![];
+        let mut marker = String::new();
+        for _ in 0..100 {
+            let rest = remote
+                .iter()
+                .filter(|key| **key > marker.as_str())
+                .collect::<Vec<_>>();
+            let truncated = rest.len() > remote_max;
+            let page = rest
+                .into_iter()
+                .take(remote_max)
+                .map(|key| (key.to_string(), Some("remote".to_string())))
+                .collect::<Vec<_>>();
+            let remote_next = remote_next_marker(&page, truncated);
+            let items = merge_page(page, truncated, queued, &marker, "", None, merge_queued);
+            let (items, next_marker) = cut_page(items, max_keys, remote_next);
+            assert!(items.len() <= max_keys);
+            for (key, item) in items {
+                listed.push(format!("{}={}", key, item.unwrap()));
+            }
+            match next_marker {
+                Some(next_marker) => marker = next_marker,
+                None => return listed,
+            }
+        }
+        panic!("listing did not end");
+    }
+
+    #[test]
+    fn paging() {
+        let remote = ["a", "c", "d", "e", "g", "h"];
+        let queued = queued(&["b", "d", "f", "i", "j"], &["c", "e", "g", "x"]);
+        let expected = [
+            "a=remote", "b=queued", "d=queued", "f=queued", "h=remote", "i=queued", "j=queued",
+        ];
+        for remote_max in 1..=7 {
+            for max_keys in 1..=8 {
+                assert_eq!(
+                    list_all(&remote, &queued, remote_max, max_keys),
+                    expected,
+                    "remote max {} max keys {}",
+                    remote_max,
+                    max_keys
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hidden_remote_page() {
+        // a truncated remote page whose keys are all deleted is not the end of the listing
+        let remote = ["a", "b", "c", "d"];
+        let queued = queued(&[], &["a", "b"]);
+        assert_eq!(list_all(&remote, &queued, 2, 10), ["c=remote", "d=remote"]);
+    }
+
+    #[test]
+    fn merge_with_delimiter() {
+        let remote_items = vec![
+            ("dir/".to_string(), None),
+            ("key".to_string(), Some("remote".to_string())),
+        ];
+        let queued = queued(&["dir/x", "new/y", "top"], &["gone/z", "key"]);
+        let items = merge_page(
+            remote_items,
+            false,
+            &queued,
+            "",
+            "",
+            Some("/"),
+            merge_queued,
+        );
+        assert_eq!(
+            items,
+            vec![
+                ("dir/".to_string(), None),
+                ("new/".to_string(), None),
+                ("top".to_string(), Some("queued".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn common_prefixes() {
+        assert_eq!(
+            common_prefix("a/b/c", "", Some("/")),
+            Some("a/".to_string())
+        );
+        assert_eq!(
+            common_prefix("a/b/c", "a/", Some("/")),
+            Some("a/b/".to_string())
+        );
+        assert_eq!(common_prefix("a/b", "a/", Some("/")), None);
+        assert_eq!(common_prefix("a/b", "", None), None);
+        assert_eq!(common_prefix("a/b", "", Some("")), None);
+        assert_eq!(
+            common_prefix("a--b--c", "", Some("--")),
+            Some("a--".to_string())
+        );
+        assert_eq!(common_prefix("a", "ab", Some("/")), None);
+    }
+
+    #[test]
+    fn cut() {
+        let items = || {
+            vec![
+                ("a".to_string(), Some(1)),
+                ("b/".to_string(), None),
+                ("c".to_string(), Some(3)),
+            ]
+        };
+        assert_eq!(cut_page(items(), 3, None), (items(), None));
+        assert_eq!(
+            cut_page(items(), 3, Some("d".to_string())),
+            (items(), Some("d".to_string()))
+        );
+        assert_eq!(
+            cut_page(items(), 2, Some("d".to_string())),
+            (items()[..2].to_vec(), Some("b/".to_string()))
+        );
+        assert_eq!(remote_next_marker(&items(), false), None);
+        assert_eq!(remote_next_marker(&items(), true), Some("c".to_string()));
+    }
+
+    #[test]
+    fn continuation_tokens() {
+        for key in ["", "key", "dir/ünïcode key"] {
+            assert_eq!(
+                from_continuation_token(&to_continuation_token(key)),
+                Some(key.to_string())
+            );
+        }
+        assert_eq!(from_continuation_token("not base64!"), None);
+        assert_eq!(from_continuation_token(&base64::encode("key")), None);
+    }
+
+    #[tokio::test]
+    async fn queued_objects_with_tombstones() {
+        let queue = test_queue().await;
+        for key in ["dir/a", "dir/b", "other"] {
+            let md = ObjectMd {
+                bucket: "bucket".to_string(),
+                key: key.to_string(),
+                ..ObjectMd::default()
+            };
+            let entry = queue.to_entry_name("bucket", key);
+            let mut body = ByteStream::from_static(b"data");
+            queue
+                .stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+                .await
+                .unwrap();
+        }
+        queue.queue_delete("bucket", "dir/b").await.unwrap();
+        queue.queue_delete("bucket", "dir/c").await.unwrap();
+        queue.queue_delete("other-bucket", "dir/d").await.unwrap();
+        let objects = queue.queued_objects("bucket", "dir/").await.unwrap();
+        assert_eq!(
+            objects.keys().collect::<Vec<_>>(),
+            vec!["dir/a", "dir/b", "dir/c"]
+        );
+        assert!(!objects["dir/a"].delete_marker);
+        assert!(objects["dir/b"].delete_marker);
+        assert!(objects["dir/c"].delete_marker);
+    }
+}
@@ -0,0 +1,1311 @@
+//! Write queue
+//!
+//! Objects written to s3d are first stored as files in the write queue dir,
+//! and pushed to the remote storage by a background worker.
+//!
+//! Incoming bodies are written to the staging dir, synced, and only then renamed into
+//! the queue dir, so any file in the queue dir is a complete object. The journal
+//! records every step so that a restart can resume or discard what was in flight.
+//!
+//! Every entry has a meta-data sidecar file (see `ObjectMd`) which is staged and
+//! committed together with the body, and is replayed on the request that pushes it.
+//!
+//! The queue keeps within its configured limits (see `Limits`) - new writes reserve room
+//! before they are staged, and wait for the worker to push older entries when it is full.
+//! A write that cannot find room in time is not queued, and the caller should pass it
+//! directly to the remote instead.
+//!
+//! Only the writes that match the write queue filter are queued (see `should_queue`),
+//! and other writes replace the queued write of their key, if any. The filter is
+//! evaluated again before every push, so queued objects that no longer match it
+//! because of their tags, or that are tagged `s3d.upload=false`, are held in the queue.
+//!
+//! Failed pushes are retried with exponential backoff (see `PushState`), and entries
+//! that keep failing with permanent errors are moved to the dead letter dir
+//! (see `DeadLetters`) so they do not block the queue.
+
+pub mod conditions;
+pub mod copy;
+pub mod dead_letter;
+pub mod journal;
+pub mod listing;
+pub mod multipart_push;
+pub mod push_state;
+pub mod tagging;
+pub mod tombstones;
+pub mod uploads;
+
+use crate::byte_range::ByteRange;
+use crate::checksum::{to_hex, ObjectHasher, CRC32, CRC32C, SHA1, SHA256};
+use crate::config::Limits;
+use crate::connectivity::Connectivity;
+use crate::filter::Filter;
+use crate::md_cache::MdCache;
+use crate::object_md::{md_path, ObjectMd, MD_SUFFIX};
+use crate::s3::errors::S3Error;
+use crate::utils::{
+    is_not_found, read_ranges_as_stream, sync_dir, to_internal_err, write_stream_to_file_with,
+};
+use crate::write_queue::conditions::Conditions;
+use crate::write_queue::dead_letter::DeadLetters;
+use crate::write_queue::journal::{Journal, Record, Replay};
+use crate::write_queue::multipart_push::MULTIPART_THRESHOLD;
+use crate::write_queue::push_state::{
+    classify_sdk_err, PermanentError, PushState, PUSH_STATE_SUFFIX,
+};
+use crate::write_queue::uploads::UPLOADS_DIR;
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{
+    error::{GetObjectError, HeadObjectError, NoSuchKey, NotFound, PutObjectError},
+    input::{GetObjectInput, HeadObjectInput, PutObjectInput},
+    output::{GetObjectOutput, HeadObjectOutput, PutObjectOutput},
+};
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, Notify, Semaphore};
+
+pub const STAGING_DIR: &str = ".staging";
+pub const JOURNAL_FILE: &str = ".journal";
+pub const PUSH_STATE_DIR: &str = ".push_state";
+pub const DEAD_LETTER_DIR: &str = ".dead_letter";
+pub const LOCK_FILE: &str = ".lock";
+
+/// Queued objects with this tag set to false are held in the queue and not pushed.
+pub const UPLOAD_TAG: &str = "s3d.upload";
+
+/// How long a write waits for room in a full queue before giving up on queueing.
+pub const WAIT_FOR_ROOM: Duration = Duration::from_secs(60);
+/// How often the worker wakes up when nobody pokes it.
+pub const WORKER_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the worker sweeps abandoned uploads (see `sweep_uploads`).
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Default)]
+pub struct QueueStats {
+    /// Total size of the committed entries
+    pub size: u64,
+    /// Number of committed entries
+    pub files: u64,
+    /// Size reserved by writes in progress
+    pub reserved_size: u64,
+    /// Number of writes in progress
+    pub reserved_files: u64,
+    /// Total size of the parts of multipart uploads in progress
+    pub upload_size: u64,
+    /// Number of multipart uploads in progress, which count as one file each
+    pub uploads: u64,
+}
+
+impl QueueStats {
+    pub fn has_room(&self, size: u64, files: u64, limits: &Limits) -> bool {
+        self.size + self.reserved_size + self.upload_size + size <= limits.max_size
+            && self.files + self.reserved_files + self.uploads + files <= limits.max_files
+    }
+}
+
+/// Reservation holds room in the queue for a write in progress,
+/// and releases it when dropped.
+pub struct Reservation<'a> {
+    queue: &'a WriteQueue,
+    size: AtomicU64,
+    files: u64,
+}
+
+impl Reservation<'_> {
+    /// fit makes the reservation hold room for a body of the given size.
+    /// Bodies can be longer than the room that was reserved for them (chunked bodies
+    /// have no content length), so more room is taken as they are written, without
+    /// waiting for the worker, and the write fails with SlowDown when the queue is full.
+    pub fn fit(&self, size: u64) -> Result<(), S3Error> {
+        let reserved = self.size.load(Ordering::Relaxed);
+        if size <= reserved {
+            return Ok(());
+        }
+        let mut stats = self.queue.stats.lock().unwrap();
+        if !stats.has_room(size - reserved, 0, &self.queue.limits) {
+            return Err(S3Error::new("SlowDown", "Write queue is full"));
+        }
+        stats.reserved_size += size - reserved;
+        self.size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// keep_for_upload moves the reserved files and the given size to the multipart
+    /// uploads in progress, where they stay until the upload is removed (see `remove_upload`).
+    pub fn keep_for_upload(self, size: u64) {
+        let mut stats = self.queue.stats.lock().unwrap();
+        stats.upload_size += size;
+        stats.uploads += self.files;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut stats = self.queue.stats.lock().unwrap();
+        stats.reserved_size -= *self.size.get_mut();
+        stats.reserved_files -= self.files;
+    }
+}
+
+/// EntryClaim keeps the worker from pushing an entry, see `claim_entry`.
+pub struct EntryClaim<'a> {
+    queue: &'a WriteQueue,
+    entry: String,
+    ino: Option<u64>,
+}
+
+impl EntryClaim<'_> {
+    /// replaced removes the claimed entry once the write that replaces it reached
+    /// the remote, unless a newer write of the key was queued since it was claimed.
+    pub async fn replaced(&self) -> anyhow::Result<()> {
+        match self.ino {
+            Some(ino) => self.queue.remove_pushed(&self.entry, ino).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for EntryClaim<'_> {
+    fn drop(&mut self) {
+        self.queue.in_flight.lock().unwrap().remove(&self.entry);
+    }
+}
+
+pub struct WriteQueue {
+    pub s3_client: &'static aws_sdk_s3::Client,
+    pub connectivity: &'static Connectivity,
+    /// md_cache has the remote listings, which change by every push.
+    pub md_cache: Option<&'static MdCache>,
+    pub write_queue_dir: String,
+    pub journal: Journal,
+    /// commit_lock makes the renames of a body and its sidecar atomic
+    /// with respect to readers that open both of them.
+    /// Any change to the entries of the queue dir is done while holding it.
+    pub commit_lock: Mutex<()>,
+    pub limits: Limits,
+    /// Number of entries to push concurrently
+    pub workers: usize,
+    /// Only the objects that match the filter are queued and pushed
+    pub filter: Option<Filter>,
+    pub stats: std::sync::Mutex<QueueStats>,
+    /// in_flight has the entries that are being pushed.
+    pub in_flight: std::sync::Mutex<HashSet<String>>,
+    /// wakeup pokes the worker to run before its next interval.
+    pub wakeup: Notify,
+    /// drained is notified when the worker removes entries from the queue.
+    pub drained: Notify,
+    /// dir_lock is held while the queue is open, so that other processes
+    /// do not change the queue dir under it (see `lock_queue_dir`).
+    pub dir_lock: std::fs::File,
+}
+
+impl WriteQueue {
+    pub async fn new(
+        s3_client: &'static aws_sdk_s3::Client,
+        connectivity: &'static Connectivity,
+        md_cache: Option<&'static MdCache>,
+        write_queue_dir: String,
+        limits: Limits,
+        workers: usize,
+        filter: Option<Filter>,
+    ) -> anyhow::Result<WriteQueue> {
+        for dir in [STAGING_DIR, PUSH_STATE_DIR, DEAD_LETTER_DIR, UPLOADS_DIR] {
+            tokio::fs::create_dir_all(Path::new(&write_queue_dir).join(dir)).await?;
+        }
+        let dir_lock = lock_queue_dir(&write_queue_dir)?;
+        let journal = Journal::open(&Path::new(&write_queue_dir).join(JOURNAL_FILE)).await?;
+        Ok(WriteQueue {
+            s3_client,
+            connectivity,
+            md_cache,
+            write_queue_dir,
+            journal,
+            commit_lock: Mutex::new(()),
+            limits,
+            workers: workers.max(1),
+            filter,
+            stats: std::sync::Mutex::new(QueueStats::default()),
+            in_flight: std::sync::Mutex::new(HashSet::new()),
+            wakeup: Notify::new(),
+            drained: Notify::new(),
+            dir_lock,
+        })
+    }
+
+    pub async fn start(&'static self) -> anyhow::Result<()> {
+        self.recover().await?;
+        tokio::spawn(self.worker());
+        Ok(())
+    }
+
+    /// recover brings the queue dir to a consistent state after a restart:
+    /// - staged bodies that were committed are moved into the queue (resume).
+    /// - staged bodies that were never committed are removed (discard).
+    /// - entries that were already pushed but not yet removed are removed.
+    /// - sidecars and push states without an entry are removed,
+    ///   and the multipart uploads of such push states are aborted.
+    /// - partial parts of multipart uploads are removed.
+    pub async fn recover(&self) -> anyhow::Result<()> {
+        let records = Journal::read_records(&self.journal_path()).await?;
+        let replay = Replay::new(&records);
+
+        // sidecars are renamed before bodies, so resume them first
+        // to never leave a resumed body with a missing sidecar.
+        let mut staged_files = vec![];
+        let mut staging = tokio::fs::read_dir(self.staging_dir()).await?;
+        while let Some(staged) = staging.next_entry().await? {
+            staged_files.push(staged.file_name().to_string_lossy().to_string());
+        }
+        staged_files.sort_by_key(|name| !name.ends_with(MD_SUFFIX));
+        for name in staged_files.iter() {
+            let id = name.strip_suffix(MD_SUFFIX).unwrap_or(name);
+            let resume_entry = replay.resume.get(id);
+            let staged_path = self.staging_dir().join(name);
+            match resume_entry {
+                Some(entry) => {
+                    info!("Write queue recover: resume committed {:?}", name);
+                    let mut target = self.entry_path(entry);
+                    if name.ends_with(MD_SUFFIX) {
+                        target = md_path(&target);
+                    }
+                    tokio::fs::rename(staged_path, target).await?;
+                }
+                None => {
+                    warn!("Write queue recover: discard partial {:?}", name);
+                    tokio::fs::remove_file(staged_path).await?;
+                }
+            }
+        }
+
+        for entry in replay.pushed.iter() {
+            let path = self.entry_path(entry);
+            if tokio::fs::metadata(&path).await.is_ok() {
+                info!("Write queue recover: remove pushed {:?}", entry);
+                tokio::fs::remove_file(&path).await?;
+            }
+        }
+
+        // sidecars are removed after their bodies, so a crash can leave orphans
+        let mut queue = tokio::fs::read_dir(&self.write_queue_dir).await?;
+        while let Some(item) = queue.next_entry().await? {
+            let name_os = item.file_name();
+            let name = name_os.to_string_lossy();
+            if let Some(entry) = name.strip_suffix(MD_SUFFIX) {
+                if tokio::fs::metadata(self.entry_path(entry)).await.is_err() {
+                    info!("Write queue recover: remove orphan sidecar {:?}", name);
+                    tokio::fs::remove_file(item.path()).await?;
+                }
+            }
+        }
+
+        let mut states = tokio::fs::read_dir(self.push_state_dir()).await?;
+        while let Some(item) = states.next_entry().await? {
+            let name_os = item.file_name();
+            let name = name_os.to_string_lossy();
+            let entry = name.strip_suffix(PUSH_STATE_SUFFIX).unwrap_or(&name);
+            if tokio::fs::metadata(self.entry_path(entry)).await.is_err() {
+                // the remote keeps the parts of an upload until it is aborted,
+                // and the push state has the only record of its id
+                let upload = PushState::read(&item.path()).await.upload;
+                if let (Some(upload), Ok((bucket, key))) = (upload, parse_entry_name(entry)) {
+                    info!(
+                        "Write queue recover: abort upload of orphan push state {:?}",
+                        name
+                    );
+                    self.abort_upload(&bucket, &key, &upload.upload_id).await;
+                }
+                tokio::fs::remove_file(item.path()).await?;
+            }
+        }
+
+        self.recover_uploads().await?;
+
+        sync_dir(Path::new(&self.write_queue_dir)).await?;
+        sync_dir(&self.staging_dir()).await?;
+        self.journal.compact(|_| false).await?;
+        Ok(())
+    }
+
+    /// worker dispatches the queued entries to a pool of concurrent pushes.
+    /// Pushes continue in the background while the worker scans again,
+    /// so a large entry only occupies one slot of the pool.
+    pub async fn worker(&'static self) {
+        let permits = Arc::new(Semaphore::new(self.workers));
+        let mut last_sweep = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(WORKER_INTERVAL) => {}
+                _ = self.wakeup.notified() => {}
+                _ = self.connectivity.recovered.notified() => {}
+            }
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                last_sweep = tokio::time::Instant::now();
+                if let Err(err) = self.sweep_uploads().await {
+                    warn!("Write queue sweep uploads failed: {}", err);
+                }
+            }
+            if let Err(err) = self.work(&permits).await {
+                debug!("{}", err);
+            }
+        }
+    }
+
+    /// work pushes the queued entries in order of arrival, so that entries
+    /// that exceed the max age are the first to leave the queue.
+    /// Entries that failed recently are skipped until their backoff expires,
+    /// and entries that are already being pushed are skipped too, which keeps
+    /// the pushes of the same key in order (see `push_file`).
+    /// Nothing is pushed while the remote is offline, so entries do not use up
+    /// their attempts, and the worker runs as soon as the remote is back.
+    pub async fn work(&'static self, permits: &Arc<Semaphore>) -> anyhow::Result<()> {
+        if !self.connectivity.is_online() {
+            debug!("Write queue worker waiting for the remote ...");
+            return Ok(());
+        }
+        debug!("Write queue worker running ...");
+        let entries = self.scan_entries().await?;
+        let now = SystemTime::now();
+        let overdue = entries
+            .iter()
+            .filter(|(_, _, arrived)| {
+                now.duration_since(*arrived).unwrap_or_default() > self.limits.max_age
+            })
+            .count();
+        if overdue > 0 {
+            warn!(
+                "Write queue has {} entries older than max age {:?}",
+                overdue, self.limits.max_age
+            );
+        }
+        for (entry_name, _, _) in entries.into_iter() {
+            let permit = permits.clone().acquire_owned().await?;
+            if !self.in_flight.lock().unwrap().insert(entry_name.clone()) {
+                continue;
+            }
+            let state = PushState::read(&self.push_state_path(&entry_name)).await;
+            if !state.is_due(chrono::Utc::now().timestamp_millis()) {
+                self.in_flight.lock().unwrap().remove(&entry_name);
+                continue;
+            }
+            tokio::spawn(async move {
+                match self.push_file(&entry_name, state).await {
+                    Ok(()) => {}
+                    // the entry was pushed or removed since it was scanned
+                    Err(err) if is_not_found(&err) => {}
+                    Err(err) => warn!("{}", err),
+                }
+                self.in_flight.lock().unwrap().remove(&entry_name);
+                drop(permit);
+            });
+        }
+        self.compact_journal().await
+    }
+
+    /// scan_entries lists the committed entries with their size and arrival time,
+    /// sorted from oldest to newest, and refreshes the queue stats to match.
+    /// The arrival time is the last modified time of the sidecar meta-data,
+    /// which is taken when the write request arrived (and not when its body was done).
+    pub async fn scan_entries(&self) -> anyhow::Result<Vec<(String, u64, SystemTime)>> {
+        let mut entries = vec![];
+        {
+            let _guard = self.commit_lock.lock().await;
+            let mut queue = tokio::fs::read_dir(&self.write_queue_dir).await?;
+            while let Some(entry) = queue.next_entry().await? {
+                let entry_name = entry.file_name().to_string_lossy().to_string();
+                if !is_entry_name(&entry_name) {
+                    continue;
+                }
+                let stat = entry.metadata().await?;
+                entries.push((entry_name, stat.len(), stat.modified()?));
+            }
+            let mut stats = self.stats.lock().unwrap();
+            stats.size = entries.iter().map(|(_, size, _)| size).sum();
+            stats.files = entries.len() as u64;
+        }
+        for (entry_name, _, arrived) in entries.iter_mut() {
+            let md_file = md_path(&self.entry_path(entry_name));
+            if let Ok(md) = ObjectMd::read(&md_file).await {
+                if md.last_modified > 0 {
+                    *arrived =
+                        SystemTime::UNIX_EPOCH + Duration::from_millis(md.last_modified as u64);
+                }
+            }
+        }
+        entries.sort_by_key(|(_, _, arrived)| *arrived);
+        Ok(entries)
+    }
+
+    /// reserve waits for room in the queue for a write of the given size.
+    /// Returns None if there is no room in time (or the object can never fit),
+    /// in which case the write should not be queued.
+    pub async fn reserve(&self, size: u64) -> Option<Reservation<'_>> {
+        self.reserve_room(size, 1).await
+    }
+
+    /// reserve_room is `reserve` for any number of files, which is 0 for the parts
+    /// of multipart uploads, and 1 for the uploads themselves.
+    pub async fn reserve_room(&self, size: u64, files: u64) -> Option<Reservation<'_>> {
+        if size > self.limits.max_size {
+            return None;
+        }
+        let deadline = tokio::time::Instant::now() + WAIT_FOR_ROOM;
+        loop {
+            {
+                let mut stats = self.stats.lock().unwrap();
+                if stats.has_room(size, files, &self.limits) {
+                    stats.reserved_size += size;
+                    stats.reserved_files += files;
+                    return Some(Reservation {
+                        queue: self,
+                        size: AtomicU64::new(size),
+                        files,
+                    });
+                }
+                debug!("Write queue is full {:?}", *stats);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return None;
+            }
+            self.wakeup.notify_one();
+            // re-check periodically since a notification can be missed
+            // between the check above and registering the waiter
+            tokio::time::timeout(Duration::from_secs(1), self.drained.notified())
+                .await
+                .ok();
+        }
+    }
+
+    /// compact_journal drops the records that are no longer needed for recovery,
+    /// which are all the records except those of bodies still in the staging dir.
+    pub async fn compact_journal(&self) -> anyhow::Result<()> {
+        let mut staged = HashSet::new();
+        let mut staging = tokio::fs::read_dir(self.staging_dir()).await?;
+        while let Some(entry) = staging.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = name.strip_suffix(MD_SUFFIX).unwrap_or(&name).to_string();
+            staged.insert(id);
+        }
+        self.journal
+            .compact(|rec| rec.id().map_or(false, |id| staged.contains(id)))
+            .await
+    }
+
+    /// push_file pushes one entry to the remote, and removes it from the queue.
+    ///
+    /// A newer write of the same key can replace the entry while it is being pushed,
+    /// in which case the entry is left in the queue to push the newer write next.
+    /// The versions of an entry are told apart by the inode of the body file,
+    /// which stays unique while the pushed version is kept open.
+    pub async fn push_file(&self, entry_name: &str, mut state: PushState) -> anyhow::Result<()> {
+        let (md, file) = self.open_entry(entry_name).await?;
+        if self.is_held(&md).await {
+            debug!("Write queue item held: {:?}", entry_name);
+            return Ok(());
+        }
+        let pinned = file.try_clone().await?;
+        let ino = pinned.metadata().await?.ino();
+        match self.push_object(entry_name, &md, file, ino, &mut state).await {
+            Ok(()) => self.remove_pushed(entry_name, ino).await,
+            Err(err) => self.push_failed(entry_name, ino, state, err).await,
+        }
+    }
+
+    /// is_held checks if a queued object should stay in the queue instead of being pushed,
+    /// by its upload tag and the filter, which are evaluated with its current tags.
+    /// Only the tags of a queued object can change, so an object that cannot match the
+    /// filter by any tags should have been written directly to the remote (e.g. it was
+    /// queued before the filter was changed), and is pushed instead of held forever.
+    /// Deletes are never held, since they cancel the writes that were queued before them.
+    pub async fn is_held(&self, md: &ObjectMd) -> bool {
+        if md.delete_marker {
+            return false;
+        }
+        if md.tags.get(UPLOAD_TAG).map(String::as_str) == Some("false") {
+            return true;
+        }
+        match &self.filter {
+            Some(f) => f.matches_untagged(md) && !f.matches(md).await,
+            None => false,
+        }
+    }
+
+    /// should_queue checks the filter to tell if a write should be queued,
+    /// or passed directly to the remote (see `claim_entry`).
+    pub async fn should_queue(&self, md: &ObjectMd) -> bool {
+        match &self.filter {
+            Some(f) => f.matches(md).await,
+            None => true,
+        }
+    }
+
+    /// claim_entry is taken by a write that is passed directly to the remote
+    /// while an older write of the key is queued, which must not be pushed over it.
+    /// It waits for a push of the entry that is in flight, and keeps the worker
+    /// from pushing the entry until the claim is dropped.
+    /// Returns None if the key is not queued.
+    pub async fn claim_entry(&self, bucket: &str, key: &str) -> Option<EntryClaim<'_>> {
+        let entry = self.to_entry_name(bucket, key);
+        loop {
+            tokio::fs::metadata(self.entry_path(&entry)).await.ok()?;
+            if self.in_flight.lock().unwrap().insert(entry.clone()) {
+                // the entry could be pushed and removed before it was claimed
+                let ino = tokio::fs::metadata(self.entry_path(&entry))
+                    .await
+                    .ok()
+                    .map(|stat| stat.ino());
+                return Some(EntryClaim {
+                    queue: self,
+                    entry,
+                    ino,
+                });
+            }
+            tokio::time::timeout(Duration::from_secs(1), self.drained.notified())
+                .await
+                .ok();
+        }
+    }
+
+    /// push_object sends the body and meta-data of an entry to the remote,
+    /// with a multipart upload for large entries (see `push_multipart`).
+    pub async fn push_object(
+        &self,
+        entry_name: &str,
+        md: &ObjectMd,
+        file: tokio::fs::File,
+        ino: u64,
+        state: &mut PushState,
+    ) -> anyhow::Result<()> {
+        let (bucket, key) = parse_entry_name(entry_name)?;
+        info!("Write queue item: {}/{}", bucket, key);
+        if md.delete_marker {
+            self.s3_client
+                .delete_object()
+                .bucket(&bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(classify_sdk_err)?;
+            info!("Write queue item deleted: {}/{}", bucket, key);
+            return Ok(());
+        }
+        if md.content_length.max(0) as u64 >= MULTIPART_THRESHOLD {
+            return self
+                .push_multipart(entry_name, &bucket, &key, md, file, ino, state)
+                .await;
+        }
+        let body = ByteStream::read_from().file(file).build().await?;
+        let req = self
+            .s3_client
+            .put_object()
+            .bucket(&bucket)
+            .key(&key)
+            .body(body);
+        let res = md
+            .apply_to_put_object(req)
+            .send()
+            .await
+            .map_err(classify_sdk_err)?;
+        check_pushed_e_tag(md, &res)?;
+        info!("Write queue item pushed: {}/{}", bucket, key);
+        Ok(())
+    }
+
+    /// remove_pushed removes a pushed entry, unless it was replaced since it was opened.
+    pub async fn remove_pushed(&self, entry_name: &str, ino: u64) -> anyhow::Result<()> {
+        if let (Some(md_cache), Ok((bucket, _))) = (self.md_cache, parse_entry_name(entry_name)) {
+            md_cache.invalidate(&bucket);
+        }
+        let fname = self.entry_path(entry_name);
+        {
+            let _guard = self.commit_lock.lock().await;
+            if !self.is_current(entry_name, ino).await {
+                info!("Write queue item replaced while pushing: {:?}", entry_name);
+                return Ok(());
+            }
+            self.journal
+                .append(&Record::Done {
+                    entry: entry_name.to_string(),
+                })
+                .await?;
+            let size = tokio::fs::metadata(&fname).await?.len();
+            tokio::fs::remove_file(&fname).await?;
+            tokio::fs::remove_file(md_path(&fname)).await.ok();
+            tokio::fs::remove_file(self.push_state_path(entry_name))
+                .await
+                .ok();
+            let mut stats = self.stats.lock().unwrap();
+            stats.size = stats.size.saturating_sub(size);
+            stats.files = stats.files.saturating_sub(1);
+        }
+        self.drained.notify_waiters();
+        Ok(())
+    }
+
+    /// push_failed records a failed push in the entry push state,
+    /// and moves the entry to the dead letter dir if it keeps failing permanently.
+    /// Nothing is recorded if the entry was replaced, since a new write starts over.
+    pub async fn push_failed(
+        &self,
+        entry_name: &str,
+        ino: u64,
+        mut state: PushState,
+        err: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        state.failed(&err, chrono::Utc::now().timestamp_millis());
+        if state.should_dead_letter() {
+            if let (Some(upload), Ok((bucket, key))) =
+                (state.upload.take(), parse_entry_name(entry_name))
+            {
+                self.abort_upload(&bucket, &key, &upload.upload_id).await;
+            }
+        }
+        let _guard = self.commit_lock.lock().await;
+        if !self.is_current(entry_name, ino).await {
+            return Ok(());
+        }
+        if state.should_dead_letter() {
+            warn!(
+                "Write queue item {:?} failed {} times, moving to dead letters: {:#}",
+                entry_name, state.attempts, err
+            );
+            self.dead_letter(entry_name, &state).await?;
+            drop(_guard);
+            self.drained.notify_waiters();
+            return Ok(());
+        }
+        let delay = Duration::from_millis(
+            (state.next_attempt - chrono::Utc::now().timestamp_millis()).max(0) as u64,
+        );
+        warn!(
+            "Write queue item {:?} failed (attempt {}), retry in {:?}: {:#}",
+            entry_name, state.attempts, delay, err
+        );
+        state.write(&self.push_state_path(entry_name)).await
+    }
+
+    /// dead_letter moves an entry with its sidecar and push state out of the queue,
+    /// and must be called while holding the commit lock.
+    /// The files are linked into the dead letter dir before they are removed
+    /// from the queue, so a crash in the middle leaves the entry queued.
+    async fn dead_letter(&self, entry_name: &str, state: &PushState) -> anyhow::Result<()> {
+        let dead_letters = self.dead_letters();
+        let fname = self.entry_path(entry_name);
+        let dead = dead_letters.entry_path(entry_name);
+        let size = tokio::fs::metadata(&fname).await?.len();
+        // an older dead letter of the same object is replaced
+        for path in [
+            dead.clone(),
+            md_path(&dead),
+            dead_letters.state_path(entry_name),
+        ] {
+            tokio::fs::remove_file(path).await.ok();
+        }
+        state.write(&dead_letters.state_path(entry_name)).await?;
+        if tokio::fs::metadata(md_path(&fname)).await.is_ok() {
+            tokio::fs::hard_link(md_path(&fname), md_path(&dead)).await?;
+        }
+        tokio::fs::hard_link(&fname, &dead).await?;
+        sync_dir(&dead_letters.dir()).await?;
+        tokio::fs::remove_file(&fname).await?;
+        tokio::fs::remove_file(md_path(&fname)).await.ok();
+        tokio::fs::remove_file(self.push_state_path(entry_name))
+            .await
+            .ok();
+        sync_dir(Path::new(&self.write_queue_dir)).await?;
+        let mut stats = self.stats.lock().unwrap();
+        stats.size = stats.size.saturating_sub(size);
+        stats.files = stats.files.saturating_sub(1);
+        Ok(())
+    }
+
+    /// reset_push_state starts over the push state of an entry for a new write.
+    /// A multipart upload of the previous write is kept in the state,
+    /// so that the next push aborts it (see `push_multipart`).
+    async fn reset_push_state(&self, entry: &str) {
+        let path = self.push_state_path(entry);
+        let upload = PushState::read(&path).await.upload;
+        let res = match upload {
+            Some(upload) => {
+                PushState {
+                    upload: Some(upload),
+                    ..PushState::default()
+                }
+                .write(&path)
+                .await
+            }
+            None => {
+                tokio::fs::remove_file(&path).await.ok();
+                Ok(())
+            }
+        };
+        if let Err(err) = res {
+            warn!("Write queue reset push state {:?} failed: {}", entry, err);
+        }
+    }
+
+    /// is_current checks that the entry body is still the file with the given inode.
+    async fn is_current(&self, entry_name: &str, ino: u64) -> bool {
+        tokio::fs::metadata(self.entry_path(entry_name))
+            .await
+            .map_or(false, |stat| stat.ino() == ino)
+    }
+
+    pub fn dead_letters(&self) -> DeadLetters {
+        DeadLetters::new(&self.write_queue_dir)
+    }
+
+    /// open_entry opens the body file and reads the sidecar of an entry together,
+    /// so that a concurrent commit of the same entry cannot mix the two.
+    pub async fn open_entry(&self, entry: &str) -> anyhow::Result<(ObjectMd, tokio::fs::File)> {
+        let path = self.entry_path(entry);
+        let _guard = self.commit_lock.lock().await;
+        let file = tokio::fs::File::open(&path).await?;
+        let md_file = md_path(&path);
+        // entries queued by older versions have no sidecar
+        let mut md = if tokio::fs::metadata(&md_file).await.is_ok() {
+            ObjectMd::read(&md_file).await?
+        } else {
+            let stat = file.metadata().await?;
+            let mtime: chrono::DateTime<chrono::Utc> = stat.modified()?.into();
+            ObjectMd {
+                last_modified: mtime.timestamp_millis(),
+                ..ObjectMd::default()
+            }
+        };
+        md.content_length = file.metadata().await?.len() as i64;
+        Ok((md, file))
+    }
+
+    /// read_entry_md reads the sidecar of an entry without opening its body.
+    /// Sidecars are renamed into place with the size of their body, so this takes
+    /// no commit lock, and listings of many entries do not hold up commits.
+    /// Entries queued by older versions have no sidecar and are opened instead.
+    pub async fn read_entry_md(&self, entry: &str) -> anyhow::Result<ObjectMd> {
+        match ObjectMd::read(&md_path(&self.entry_path(entry))).await {
+            Err(err) if is_not_found(&err) => Ok(self.open_entry(entry).await?.0),
+            res => res,
+        }
+    }
+
+    /// find_entry returns the meta-data and open body file of a queued object,
+    /// or None if the object is not in the queue.
+    pub async fn find_entry(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<(ObjectMd, tokio::fs::File)>> {
+        let entry = self.to_entry_name(bucket, key);
+        match self.open_entry(&entry).await {
+            Ok(found) => Ok(Some(found)),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// put_object queues the object using room that was reserved with `reserve`.
+    pub async fn put_object(
+        &self,
+        mut i: PutObjectInput,
+        reservation: Reservation<'_>,
+    ) -> Result<PutObjectOutput, PutObjectError> {
+        let entry = self.to_entry_name(i.bucket(), i.key());
+        let md = ObjectMd::from_put_object_input(&i);
+        let hasher = ObjectHasher::from_put_object_input(&i);
+        let md = self
+            .stage_and_commit(&entry, &md, &mut i.body, hasher, Some(&reservation))
+            .await
+            .map_err(to_internal_err)?;
+        Ok(PutObjectOutput::builder()
+            .set_e_tag(md.e_tag.clone())
+            .set_checksum_crc32(md.checksums.get(CRC32).cloned())
+            .set_checksum_crc32_c(md.checksums.get(CRC32C).cloned())
+            .set_checksum_sha1(md.checksums.get(SHA1).cloned())
+            .set_checksum_sha256(md.checksums.get(SHA256).cloned())
+            .build())
+    }
+
+    /// stage_and_commit writes the body and its sidecar to the staging dir
+    /// and atomically moves them into the queue.
+    /// A failed or interrupted body, or one that does not match the expected
+    /// digests, never becomes visible in the queue dir.
+    /// Returns the committed meta-data, with the size, ETag and checksums of the body.
+    /// An ETag that is already set on the meta-data (of multipart uploads) is kept.
+    /// The body is kept within the given reservation (see `Reservation::fit`).
+    pub async fn stage_and_commit(
+        &self,
+        entry: &str,
+        md: &ObjectMd,
+        body: &mut ByteStream,
+        mut hasher: ObjectHasher,
+        reservation: Option<&Reservation<'_>>,
+    ) -> anyhow::Result<ObjectMd> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let staged = self.staging_dir().join(&id);
+        let staged_md = md_path(&staged);
+        self.journal
+            .append(&Record::Begin {
+                id: id.clone(),
+                entry: entry.to_string(),
+            })
+            .await?;
+        let staged_res = async {
+            let mut size = 0;
+            let num_bytes = write_stream_to_file_with(staged.to_str().unwrap(), body, |buf| {
+                size += buf.len() as u64;
+                if let Some(reservation) = reservation {
+                    reservation.fit(size)?;
+                }
+                hasher.update(buf);
+                Ok(())
+            })
+            .await?;
+            let sums = hasher.finish()?;
+            let mut md = md.clone();
+            md.content_length = num_bytes as i64;
+            md.e_tag = md.e_tag.or_else(|| Some(sums.e_tag()));
+            md.content_md5 = Some(sums.content_md5());
+            md.checksums = sums.checksums;
+            md.write(&staged_md).await?;
+            anyhow::Ok(md)
+        }
+        .await;
+        let md = match staged_res {
+            Ok(md) => md,
+            Err(err) => {
+                tokio::fs::remove_file(&staged).await.ok();
+                tokio::fs::remove_file(&staged_md).await.ok();
+                return Err(err);
+            }
+        };
+        let path = self.entry_path(entry);
+        {
+            // the commit record is appended under the lock to keep it ordered
+            // with the done records of pushes of the same entry
+            let _guard = self.commit_lock.lock().await;
+            self.journal
+                .append(&Record::Commit {
+                    id,
+                    entry: entry.to_string(),
+                })
+                .await?;
+            let replaced = tokio::fs::metadata(&path).await.ok().map(|m| m.len());
+            tokio::fs::rename(&staged_md, md_path(&path)).await?;
+            tokio::fs::rename(&staged, &path).await?;
+            self.reset_push_state(entry).await;
+            let mut stats = self.stats.lock().unwrap();
+            stats.size =
+                (stats.size + md.content_length as u64).saturating_sub(replaced.unwrap_or(0));
+            if replaced.is_none() {
+                stats.files += 1;
+            }
+        }
+        sync_dir(Path::new(&self.write_queue_dir)).await?;
+        Ok(md)
+    }
+
+    /// get_object returns NoSuchKey when the object is not queued,
+    /// which the caller should take as a hint to read from the remote.
+    /// Objects with a queued delete are not found either, but the error is
+    /// returned as an S3Error so that the caller does not read them from the remote.
+    /// Requests for a version are hinted to the remote too, since queued objects
+    /// have no version yet. Conditional requests are checked against the queued object,
+    /// and a byte range or a part of the object is served as a partial response.
+    pub async fn get_object(&self, i: GetObjectInput) -> Result<GetObjectOutput, GetObjectError> {
+        if i.version_id().is_some() {
+            return Err(NoSuchKey::builder().build().into());
+        }
+        let (md, file) = self
+            .find_entry(i.bucket(), i.key())
+            .await
+            .map_err(to_internal_err)?
+            .ok_or_else(|| NoSuchKey::builder().build())?;
+        if md.delete_marker {
+            return Err(to_internal_err(no_such_key_deleted()));
+        }
+        Conditions::from_get_object_input(&i)
+            .check(&md)
+            .map_err(to_internal_err)?;
+        if i.part_number() != 0 {
+            if i.range().is_some() {
+                return Err(to_internal_err(S3Error::new(
+                    "InvalidRequest",
+                    "Cannot specify both Range header and partNumber query parameter",
+                )));
+            }
+            if let Some((first, last)) = md.part_range(i.part_number()).map_err(to_internal_err)? {
+                let body = read_ranges_as_stream(vec![(file, first, last - first + 1)]);
+                return Ok(md.to_get_object_part_output(body, first, last));
+            }
+        }
+        if let Some(range) = i.range().and_then(ByteRange::parse) {
+            let (first, last) = range
+                .resolve(md.content_length as u64)
+                .map_err(to_internal_err)?;
+            let body = read_ranges_as_stream(vec![(file, first, last - first + 1)]);
+            return Ok(md.to_get_object_range_output(body, first, last));
+        }
+        let body = ByteStream::read_from()
+            .file(file)
+            .build()
+            .await
+            .map_err(to_internal_err)?;
+        Ok(md.to_get_object_output(body))
+    }
+
+    /// head_object returns NotFound when the object is not queued,
+    /// which the caller should take as a hint to read from the remote,
+    /// and an S3Error for objects with a queued delete (see `get_object`).
+    pub async fn head_object(
+        &self,
+        i: HeadObjectInput,
+    ) -> Result<HeadObjectOutput, HeadObjectError> {
+        if i.version_id().is_some() {
+            return Err(NotFound::builder().build().into());
+        }
+        let (md, _) = self
+            .find_entry(i.bucket(), i.key())
+            .await
+            .map_err(to_internal_err)?
+            .ok_or_else(|| NotFound::builder().build())?;
+        if md.delete_marker {
+            return Err(to_internal_err(no_such_key_deleted()));
+        }
+        Conditions::from_head_object_input(&i)
+            .check(&md)
+            .map_err(to_internal_err)?;
+        if i.part_number() != 0 {
+            if let Some((first, last)) = md.part_range(i.part_number()).map_err(to_internal_err)? {
+                return Ok(md.to_head_object_part_output(first, last));
+            }
+        }
+        Ok(md.to_head_object_output())
+    }
+
+    pub fn to_entry_name(&self, bucket: &str, key: &str) -> String {
+        urlencoding::encode(&format!("{}/{}", bucket, key)).into_owned()
+    }
+
+    pub fn to_file_name(&self, bucket: &str, key: &str) -> String {
+        format!("{}/{}", self.write_queue_dir, self.to_entry_name(bucket, key))
+    }
+
+    pub fn entry_path(&self, entry: &str) -> PathBuf {
+        Path::new(&self.write_queue_dir).join(entry)
+    }
+
+    pub fn staging_dir(&self) -> PathBuf {
+        Path::new(&self.write_queue_dir).join(STAGING_DIR)
+    }
+
+    pub fn journal_path(&self) -> PathBuf {
+        Path::new(&self.write_queue_dir).join(JOURNAL_FILE)
+    }
+
+    pub fn push_state_dir(&self) -> PathBuf {
+        Path::new(&self.write_queue_dir).join(PUSH_STATE_DIR)
+    }
+
+    pub fn push_state_path(&self, entry: &str) -> PathBuf {
+        self.push_state_dir()
+            .join(format!("{}{}", entry, PUSH_STATE_SUFFIX))
+    }
+}
+
+/// check_pushed_e_tag verifies that the remote stored the same data that was queued.
+/// Objects encrypted with SSE-KMS have ETags which are not the MD5 of the data,
+/// so those are not verified. Objects that were uploaded to s3d in parts have
+/// a composite ETag, but are pushed with a single put, so their MD5 is compared instead.
+pub fn check_pushed_e_tag(
+    md: &ObjectMd,
+    res: &aws_sdk_s3::output::PutObjectOutput,
+) -> anyhow::Result<()> {
+    let kms = matches!(
+        res.server_side_encryption(),
+        Some(aws_sdk_s3::model::ServerSideEncryption::AwsKms)
+    );
+    let expected = match md.e_tag.as_deref() {
+        Some(e_tag) if e_tag.contains('-') => md
+            .content_md5
+            .as_deref()
+            .and_then(|md5| base64::decode(md5).ok())
+            .map(|md5| format!("\"{}\"", to_hex(&md5))),
+        e_tag => e_tag.map(String::from),
+    };
+    if let (Some(expected), Some(actual)) = (expected.as_deref(), res.e_tag()) {
+        if !kms && expected != actual {
+            anyhow::bail!(
+                "Pushed ETag mismatch for {}/{}: expected {} got {}",
+                md.bucket,
+                md.key,
+                expected,
+                actual
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn no_such_key_deleted() -> S3Error {
+    S3Error::new("NoSuchKey", "The specified key does not exist.")
+}
+
+/// parse_entry_name decodes an entry name to bucket and key.
+pub fn parse_entry_name(entry_name: &str) -> anyhow::Result<(String, String)> {
+    let bucket_path = urlencoding::decode(entry_name)
+        .map_err(|err| PermanentError(format!("Bad entry name {:?} {}", entry_name, err)))?;
+    let (bucket, key) = bucket_path
+        .split_once('/')
+        .ok_or_else(|| PermanentError(format!("Bad entry name {:?}", entry_name)))?;
+    Ok((bucket.to_string(), key.to_string()))
+}
+
+/// lock_queue_dir takes an exclusive lock on the queue dir, which fails when
+/// another process has it locked, and is released when the file is closed.
+pub fn lock_queue_dir(write_queue_dir: &str) -> anyhow::Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(Path::new(write_queue_dir).join(LOCK_FILE))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            anyhow::bail!("Write queue dir {} is in use by s3d", write_queue_dir);
+        }
+        return Err(err.into());
+    }
+    Ok(file)
+}
+
+/// is_entry_name returns false for the internal files and dirs of the queue,
+/// which all start with a dot, while entry names are urlencoded `bucket/key`
+/// and bucket names cannot start with a dot. Sidecar files are skipped too,
+/// by their suffix which is never part of an urlencoded name (see `MD_SUFFIX`).
+pub fn is_entry_name(name: &str) -> bool {
+    !name.starts_with('.') && !name.ends_with(MD_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_queue::push_state::UploadProgress;
+
+    pub fn test_client() -> &'static aws_sdk_s3::Client {
+        crate::utils::staticify(aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .region(aws_sdk_s3::Region::new("s3d"))
+                .build(),
+        ))
+    }
+
+    /// test_queue creates an empty queue in a new temp dir, without starting its worker.
+    pub async fn test_queue() -> WriteQueue {
+        let dir = std::env::temp_dir().join(format!("s3d-test-{}", uuid::Uuid::new_v4()));
+        let limits = Limits {
+            max_size: 1 << 30,
+            max_files: 1000,
+            max_age: Duration::from_secs(3600),
+        };
+        WriteQueue::new(
+            test_client(),
+            crate::utils::staticify(Connectivity::new(test_client())),
+            None,
+            dir.to_str().unwrap().to_string(),
+            limits,
+            1,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn entry_names() {
+        for key in [
+            "key",
+            "dir/sub dir/a+b%20c?d#e",
+            "dir/",
+            "ünïcode/日本",
+            &format!("key{}", MD_SUFFIX),
+        ] {
+            let entry = DeadLetters::to_entry_name("bucket", key);
+            assert!(!entry.contains('/'), "{:?}", entry);
+            assert!(is_entry_name(&entry), "{:?}", entry);
+            assert!(!is_entry_name(&format!("{}{}", entry, MD_SUFFIX)));
+            assert_eq!(
+                parse_entry_name(&entry).unwrap(),
+                ("bucket".to_string(), key.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn internal_names() {
+        for name in [
+            STAGING_DIR,
+            JOURNAL_FILE,
+            PUSH_STATE_DIR,
+            DEAD_LETTER_DIR,
+            UPLOADS_DIR,
+        ] {
+            assert!(!is_entry_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn bad_entry_names() {
+        assert!(parse_entry_name("bucket").is_err());
+        assert!(parse_entry_name("bucket%2Fkey%FF").is_err());
+    }
+
+    fn tagged(tags: &[(&str, &str)]) -> ObjectMd {
+        ObjectMd {
+            bucket: "bucket".to_string(),
+            key: "key".to_string(),
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..ObjectMd::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_holds_and_queues() {
+        let mut queue = test_queue().await;
+        assert!(queue.should_queue(&tagged(&[])).await);
+        assert!(!queue.is_held(&tagged(&[])).await);
+        assert!(queue.is_held(&tagged(&[(UPLOAD_TAG, "false")])).await);
+
+        queue.filter = Some(Filter::parse("bucket[tag:class=hot]").unwrap());
+        assert!(queue.should_queue(&tagged(&[("class", "hot")])).await);
+        assert!(!queue.should_queue(&tagged(&[("class", "cold")])).await);
+        assert!(!queue.is_held(&tagged(&[("class", "hot")])).await);
+        assert!(queue.is_held(&tagged(&[("class", "cold")])).await);
+        assert!(
+            queue
+                .is_held(&tagged(&[("class", "hot"), (UPLOAD_TAG, "false")]))
+                .await
+        );
+        // objects that cannot match by any tags are pushed
+        let mut other = tagged(&[("class", "cold")]);
+        other.bucket = "other".to_string();
+        assert!(!queue.is_held(&other).await);
+        // deletes are never held
+        let mut tombstone = tagged(&[("class", "cold")]);
+        tombstone.delete_marker = true;
+        assert!(!queue.is_held(&tombstone).await);
+    }
+
+    #[tokio::test]
+    async fn read_entry_md_without_body() {
+        let queue = test_queue().await;
+        let entry = queue.to_entry_name("bucket", "key");
+        let mut body = ByteStream::from_static(b"hello");
+        queue
+            .stage_and_commit(
+                &entry,
+                &tagged(&[]),
+                &mut body,
+                ObjectHasher::new(&[]),
+                None,
+            )
+            .await
+            .unwrap();
+        let md = queue.read_entry_md(&entry).await.unwrap();
+        assert_eq!((md.key.as_str(), md.content_length), ("key", 5));
+
+        // entries queued by older versions are sized by their body
+        std::fs::remove_file(md_path(&queue.entry_path(&entry))).unwrap();
+        let md = queue.read_entry_md(&entry).await.unwrap();
+        assert_eq!(md.content_length, 5);
+
+        std::fs::remove_file(queue.entry_path(&entry)).unwrap();
+        let err = queue.read_entry_md(&entry).await.unwrap_err();
+        assert!(is_not_found(&err));
+    }
+
+    #[tokio::test]
+    async fn reservations_fit_bodies() {
+        let mut queue = test_queue().await;
+        queue.limits.max_size = 10;
+        let reservation = queue.reserve(0).await.unwrap();
+        assert!(reservation.fit(4).is_ok());
+        assert_eq!(queue.stats.lock().unwrap().reserved_size, 4);
+        assert_eq!(reservation.fit(11).unwrap_err().code, "SlowDown");
+
+        // a body longer than the room in the queue is not committed
+        let md = tagged(&[]);
+        let entry = queue.to_entry_name("bucket", "key");
+        let mut body = ByteStream::from_static(b"hello world");
+        let res = queue
+            .stage_and_commit(
+                &entry,
+                &md,
+                &mut body,
+                ObjectHasher::new(&[]),
+                Some(&reservation),
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(queue.find_entry("bucket", "key").await.unwrap().is_none());
+        drop(reservation);
+        assert_eq!(queue.stats.lock().unwrap().reserved_size, 0);
+    }
+
+    #[tokio::test]
+    async fn queue_dir_lock() {
+        let queue = test_queue().await;
+        let dir = queue.write_queue_dir.clone();
+        assert!(lock_queue_dir(&dir).is_err());
+        drop(queue);
+        assert!(lock_queue_dir(&dir).is_ok());
+    }
+
+    #[tokio::test]
+    async fn recover_removes_orphan_push_states() {
+        let queue = test_queue().await;
+        let entry = queue.to_entry_name("bucket", "key");
+        let state = PushState {
+            upload: Some(UploadProgress {
+                upload_id: "upload".to_string(),
+                ..UploadProgress::default()
+            }),
+            ..PushState::default()
+        };
+        state.write(&queue.push_state_path(&entry)).await.unwrap();
+        // the abort of the upload is best effort, and fails without a remote
+        queue.recover().await.unwrap();
+        assert!(!queue.push_state_path(&entry).exists());
+    }
+
+    #[tokio::test]
+    async fn remove_pushed_keeps_replaced_entry() {
+        let queue = test_queue().await;
+        let entry = queue.to_entry_name("bucket", "key");
+        let md = tagged(&[]);
+        let mut body = ByteStream::from_static(b"old");
+        queue
+            .stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+            .await
+            .unwrap();
+        // the pushed file is kept open like in push_file, so its inode is not reused
+        let (_, pushed) = queue.open_entry(&entry).await.unwrap();
+        let pushed_ino = pushed.metadata().await.unwrap().ino();
+        let mut body = ByteStream::from_static(b"newer");
+        queue
+            .stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+            .await
+            .unwrap();
+        let (_, newer) = queue.open_entry(&entry).await.unwrap();
+        let newer_ino = newer.metadata().await.unwrap().ino();
+        queue.remove_pushed(&entry, pushed_ino).await.unwrap();
+        assert!(queue.find_entry("bucket", "key").await.unwrap().is_some());
+        queue.remove_pushed(&entry, newer_ino).await.unwrap();
+        assert!(queue.find_entry("bucket", "key").await.unwrap().is_none());
+    }
+}
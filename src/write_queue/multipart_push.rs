@@ -0,0 +1,301 @@
+//! Multipart push of large write queue entries.
+//!
+//! A single put is limited to 5 GiB and starts over from zero when interrupted,
+//! so entries of `MULTIPART_THRESHOLD` or more are pushed with a multipart upload.
+//! The upload id and the completed parts are saved in the push state of the entry
+//! (see `UploadProgress`) after every part, so a push that failed or was cut by
+//! a restart resumes from the next part instead of sending the whole object again.
+
+use crate::checksum::{multipart_e_tag, to_hex};
+use crate::object_md::ObjectMd;
+use crate::utils::read_ranges_as_stream;
+use crate::write_queue::push_state::{classify_sdk_err, PushState, UploadProgress, UploadedPart};
+use crate::write_queue::WriteQueue;
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart, ServerSideEncryption};
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use md5::{Digest, Md5};
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Entries of this size or more are pushed with a multipart upload.
+pub const MULTIPART_THRESHOLD: u64 = 64 * MIB;
+/// Parts are at least this size, and bigger for objects that need more than `MAX_PARTS`.
+pub const MIN_PART_SIZE: u64 = 16 * MIB;
+pub const MAX_PARTS: u64 = 10000;
+
+/// part_size_for returns the part size for an object, rounded up to whole MiBs.
+pub fn part_size_for(size: u64) -> u64 {
+    let min_for_size = (size + MAX_PARTS - 1) / MAX_PARTS;
+    MIN_PART_SIZE.max((min_for_size + MIB - 1) / MIB * MIB)
+}
+
+impl WriteQueue {
+    /// push_multipart uploads the entry in parts, resuming the upload in the push state
+    /// if it belongs to the same write. Parts are streamed from the body file,
+    /// after a first read that computes their Content-MD5, so memory use does not
+    /// grow with the part size and the number of concurrent pushes.
+    /// If the entry is replaced by a newer write during the upload, the upload is aborted
+    /// and the push ends early without an error, which leaves the newer write in the queue
+    /// since `remove_pushed` only removes the version that was pushed (by its inode).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn push_multipart(
+        &self,
+        entry_name: &str,
+        bucket: &str,
+        key: &str,
+        md: &ObjectMd,
+        mut file: tokio::fs::File,
+        ino: u64,
+        state: &mut PushState,
+    ) -> anyhow::Result<()> {
+        let size = md.content_length.max(0) as u64;
+
+        // an upload of an older write of the entry is abandoned
+        if let Some(upload) = state.upload.as_ref() {
+            if upload.e_tag != md.e_tag {
+                self.abort_upload(bucket, key, &upload.upload_id).await;
+                state.upload = None;
+            }
+        }
+
+        if state.upload.is_none() {
+            let req = self
+                .s3_client
+                .create_multipart_upload()
+                .bucket(bucket)
+                .key(key);
+            let res = md
+                .apply_to_create_multipart_upload(req)
+                .send()
+                .await
+                .map_err(classify_sdk_err)?;
+            let upload_id = res
+                .upload_id()
+                .ok_or_else(|| anyhow::anyhow!("CreateMultipartUpload returned no upload id"))?;
+            info!(
+                "Write queue item multipart upload started: {:?} {}",
+                entry_name, upload_id
+            );
+            state.upload = Some(UploadProgress {
+                upload_id: upload_id.to_string(),
+                e_tag: md.e_tag.clone(),
+                part_size: part_size_for(size),
+                parts: vec![],
+            });
+            if !self.save_progress(entry_name, ino, state).await? {
+                self.abort_upload(bucket, key, upload_id).await;
+                return Ok(());
+            }
+        }
+
+        let upload_id = state.upload.as_ref().unwrap().upload_id.clone();
+        let part_size = state.upload.as_ref().unwrap().part_size;
+        let num_parts = (size + part_size - 1) / part_size;
+        loop {
+            let completed = state.upload.as_ref().unwrap().parts.len() as u64;
+            if completed >= num_parts {
+                break;
+            }
+            let part_number = completed as i32 + 1;
+            let offset = completed * part_size;
+            let len = part_size.min(size - offset);
+            let (read, md5) = part_md5(&mut file, offset, len).await?;
+            if read != len {
+                anyhow::bail!(
+                    "Write queue item {:?} short read of part {}",
+                    entry_name,
+                    part_number
+                );
+            }
+            let body = read_ranges_as_stream(vec![(file.try_clone().await?, offset, len)]);
+            let res = self
+                .s3_client
+                .upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .content_length(len as i64)
+                .content_md5(base64::encode(&md5))
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| upload_err(state, err))?;
+            let e_tag = res
+                .e_tag()
+                .map(String::from)
+                .unwrap_or_else(|| format!("\"{}\"", to_hex(&md5)));
+            debug!(
+                "Write queue item {:?} part {}/{} {}",
+                entry_name, part_number, num_parts, e_tag
+            );
+            state
+                .upload
+                .as_mut()
+                .unwrap()
+                .parts
+                .push(UploadedPart { part_number, e_tag });
+            if !self.save_progress(entry_name, ino, state).await? {
+                self.abort_upload(bucket, key, &upload_id).await;
+                return Ok(());
+            }
+        }
+
+        let parts = state.upload.as_ref().unwrap().parts.clone();
+        let completed_parts = parts
+            .iter()
+            .map(|p| {
+                CompletedPart::builder()
+                    .part_number(p.part_number)
+                    .e_tag(&p.e_tag)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let res = self
+            .s3_client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|err| upload_err(state, err))?;
+        state.upload = None;
+
+        let kms = matches!(
+            res.server_side_encryption(),
+            Some(ServerSideEncryption::AwsKms)
+        );
+        let part_e_tags = parts.iter().map(|p| p.e_tag.as_str()).collect::<Vec<_>>();
+        if let (Some(expected), Some(actual)) = (multipart_e_tag(&part_e_tags), res.e_tag()) {
+            if !kms && expected != actual {
+                anyhow::bail!(
+                    "Pushed multipart ETag mismatch for {}/{}: expected {} got {}",
+                    bucket,
+                    key,
+                    expected,
+                    actual
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// save_progress saves the push state with the upload progress,
+    /// unless the entry was replaced by a newer write, which returns false.
+    pub async fn save_progress(
+        &self,
+        entry_name: &str,
+        ino: u64,
+        state: &PushState,
+    ) -> anyhow::Result<bool> {
+        let _guard = self.commit_lock.lock().await;
+        if !self.is_current(entry_name, ino).await {
+            return Ok(false);
+        }
+        state.write(&self.push_state_path(entry_name)).await?;
+        Ok(true)
+    }
+
+    /// abort_upload is best effort, since the remote will also drop
+    /// abandoned uploads according to its lifecycle rules.
+    pub async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) {
+        let res = self
+            .s3_client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+        if let Err(err) = res {
+            warn!(
+                "Write queue abort multipart upload {}/{} {} failed: {}",
+                bucket, key, upload_id, err
+            );
+        }
+    }
+}
+
+/// part_md5 reads a part of the body file to compute its MD5,
+/// and returns it with the number of bytes that were read.
+async fn part_md5(
+    file: &mut tokio::fs::File,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<(u64, Vec<u8>)> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut part = file.take(len);
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut read = 0;
+    loop {
+        let n = part.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    Ok((read, hasher.finalize().to_vec()))
+}
+
+/// upload_err classifies errors of upload calls, and forgets the upload
+/// if the remote no longer has it, so that the next attempt starts a new one.
+fn upload_err<E>(state: &mut PushState, err: SdkError<E>) -> anyhow::Error
+where
+    E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+{
+    if let SdkError::ServiceError { err: ref e, .. } = err {
+        if e.code() == Some("NoSuchUpload") {
+            state.upload = None;
+            return anyhow::Error::new(err);
+        }
+    }
+    classify_sdk_err(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_sizes() {
+        assert_eq!(part_size_for(0), MIN_PART_SIZE);
+        assert_eq!(part_size_for(MULTIPART_THRESHOLD), MIN_PART_SIZE);
+        assert_eq!(part_size_for(MIN_PART_SIZE * MAX_PARTS), MIN_PART_SIZE);
+        assert_eq!(part_size_for(MIN_PART_SIZE * MAX_PARTS + 1), 17 * MIB);
+        // 5 TiB, the maximum object size
+        assert_eq!(part_size_for(5 * 1024 * 1024 * MIB), 525 * MIB);
+        for size in [1, 100 * MIB, 160 * 1024 * MIB, 5 * 1024 * 1024 * MIB] {
+            let part_size = part_size_for(size);
+            assert_eq!(part_size % MIB, 0);
+            assert!((size + part_size - 1) / part_size <= MAX_PARTS, "{}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn part_digests() {
+        let path = std::env::temp_dir().join(format!("s3d-test-{}", uuid::Uuid::new_v4()));
+        let data = (0..3 * MIB).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        tokio::fs::write(&path, &data).await.unwrap();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        let (offset, len) = (MIB - 7, MIB + 14);
+        let (read, md5) = part_md5(&mut file, offset, len).await.unwrap();
+        assert_eq!(read, len);
+        let part = &data[offset as usize..(offset + len) as usize];
+        assert_eq!(md5, Md5::digest(part).to_vec());
+        // a part beyond the end of the file is short
+        let (read, _) = part_md5(&mut file, 2 * MIB, 2 * MIB).await.unwrap();
+        assert_eq!(read, MIB);
+        tokio::fs::remove_file(&path).await.ok();
+    }
+}
@@ -0,0 +1,284 @@
+//! Push state of write queue entries.
+//!
+//! Every entry that failed to push has a state file in the push state dir,
+//! which counts the attempts and holds the entry back until its next attempt time.
+//! Failures are classified as retryable (connection errors, throttling, 5xx)
+//! or permanent (access denied, no such bucket, bad request) - retryable failures
+//! are retried forever with exponential backoff, while entries that fail
+//! permanently are moved to the dead letter dir.
+//!
+//! Entries that are pushed with a multipart upload also keep the upload progress
+//! in their state, which is saved after every part so a restart resumes the upload.
+
+use crate::utils::{read_yaml_file, write_yaml_file};
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// Push states are kept next to dead letters, so the suffix cannot appear
+/// in an urlencoded entry name, like `MD_SUFFIX`.
+pub const PUSH_STATE_SUFFIX: &str = "@s3d-push-state.yaml";
+
+/// Backoff of the first retry, doubled on every attempt.
+pub const BACKOFF_BASE: Duration = Duration::from_secs(5);
+/// Maximum backoff between attempts.
+pub const BACKOFF_MAX: Duration = Duration::from_secs(600);
+/// Permanent failures are retried a few times before giving up,
+/// in case they were classified wrong (e.g a credentials refresh race).
+pub const MAX_PERMANENT_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushState {
+    pub attempts: u32,
+    /// Number of the attempts that failed with a permanent error
+    pub permanent_failures: u32,
+    /// Milliseconds since epoch
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+    /// Multipart upload in progress
+    pub upload: Option<UploadProgress>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadProgress {
+    pub upload_id: String,
+    /// ETag of the queued write that is being uploaded,
+    /// to tell if the entry was replaced by a newer write since the upload started.
+    pub e_tag: Option<String>,
+    pub part_size: u64,
+    /// Completed parts in order of part number
+    pub parts: Vec<UploadedPart>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub e_tag: String,
+}
+
+impl PushState {
+    /// read returns the default state when there is no state file,
+    /// or when it is unreadable since it only affects the scheduling of retries.
+    pub async fn read(path: &Path) -> PushState {
+        if tokio::fs::metadata(path).await.is_err() {
+            return PushState::default();
+        }
+        match read_yaml_file(path).await {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("Push state unreadable {:?} {}", path, err);
+                PushState::default()
+            }
+        }
+    }
+
+    pub async fn write(&self, path: &Path) -> anyhow::Result<()> {
+        write_yaml_file(path, self).await
+    }
+
+    /// should_dead_letter is true once an entry failed permanently enough times.
+    pub fn should_dead_letter(&self) -> bool {
+        self.permanent_failures >= MAX_PERMANENT_ATTEMPTS
+    }
+
+    pub fn is_due(&self, now_millis: i64) -> bool {
+        self.next_attempt <= now_millis
+    }
+
+    /// failed records a failed attempt and schedules the next one
+    /// with exponential backoff and jitter.
+    pub fn failed(&mut self, err: &anyhow::Error, now_millis: i64) {
+        self.attempts += 1;
+        if is_permanent(err) {
+            self.permanent_failures += 1;
+        }
+        self.last_error = Some(format!("{:#}", err));
+        self.next_attempt = now_millis + backoff(self.attempts).as_millis() as i64;
+    }
+}
+
+/// backoff returns the delay before the next attempt, using "equal jitter" -
+/// half of the exponential delay is fixed and the other half is random,
+/// which spreads the retries of many entries that failed together.
+pub fn backoff(attempts: u32) -> Duration {
+    let exp = BACKOFF_BASE
+        .checked_mul(1u32 << attempts.saturating_sub(1).min(16))
+        .unwrap_or(BACKOFF_MAX)
+        .min(BACKOFF_MAX);
+    let half = exp / 2;
+    half + half.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+}
+
+/// PermanentError marks a push failure that will not succeed by retrying.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl std::fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Permanent error: {}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<PermanentError>().is_some()
+}
+
+/// classify_sdk_err converts an error of a remote call to anyhow,
+/// wrapping errors that are not worth retrying as `PermanentError`.
+pub fn classify_sdk_err<E>(err: SdkError<E>) -> anyhow::Error
+where
+    E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+{
+    let permanent = match &err {
+        SdkError::ConstructionFailure(_) => true,
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => false,
+        SdkError::ResponseError { .. } => false,
+        SdkError::ServiceError {
+            err: service_err,
+            raw,
+        } => {
+            let status = raw.http().status().as_u16();
+            let retryable_code = matches!(
+                service_err.code(),
+                Some(
+                    "RequestTimeout"
+                        | "RequestTimeTooSkewed"
+                        | "ExpiredToken"
+                        | "SlowDown"
+                        | "InternalError"
+                        | "OperationAborted"
+                )
+            );
+            let retryable = service_err.retryable_error_kind().is_some()
+                || retryable_code
+                || status >= 500
+                || status == 408
+                || status == 429;
+            !retryable && (400..500).contains(&status)
+        }
+    };
+    if permanent {
+        anyhow::Error::new(PermanentError(err.to_string()))
+    } else {
+        err.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_smithy_http::body::SdkBody;
+    use aws_smithy_http::operation;
+    use aws_smithy_types::retry::ErrorKind;
+
+    #[derive(Debug)]
+    struct TestError {
+        code: Option<&'static str>,
+        kind: Option<ErrorKind>,
+    }
+
+    impl std::fmt::Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.code)
+        }
+    }
+
+    impl std::error::Error for TestError {}
+
+    impl ProvideErrorKind for TestError {
+        fn retryable_error_kind(&self) -> Option<ErrorKind> {
+            self.kind
+        }
+        fn code(&self) -> Option<&str> {
+            self.code
+        }
+    }
+
+    fn service_err(
+        status: u16,
+        code: Option<&'static str>,
+        kind: Option<ErrorKind>,
+    ) -> SdkError<TestError> {
+        let raw = hyper::Response::builder()
+            .status(status)
+            .body(SdkBody::empty())
+            .unwrap();
+        SdkError::ServiceError {
+            err: TestError { code, kind },
+            raw: operation::Response::new(raw),
+        }
+    }
+
+    fn classify(err: SdkError<TestError>) -> bool {
+        is_permanent(&classify_sdk_err(err))
+    }
+
+    #[test]
+    fn classify_errors() {
+        assert!(classify(service_err(403, Some("AccessDenied"), None)));
+        assert!(classify(service_err(404, Some("NoSuchBucket"), None)));
+        assert!(classify(service_err(400, Some("InvalidArgument"), None)));
+        assert!(classify(SdkError::ConstructionFailure("bad input".into())));
+
+        assert!(!classify(service_err(400, Some("RequestTimeout"), None)));
+        assert!(!classify(service_err(400, Some("ExpiredToken"), None)));
+        assert!(!classify(service_err(
+            403,
+            None,
+            Some(ErrorKind::ThrottlingError)
+        )));
+        assert!(!classify(service_err(408, None, None)));
+        assert!(!classify(service_err(429, None, None)));
+        assert!(!classify(service_err(500, Some("InternalError"), None)));
+        assert!(!classify(service_err(503, Some("SlowDown"), None)));
+        assert!(!classify(service_err(301, Some("PermanentRedirect"), None)));
+        assert!(!classify(SdkError::TimeoutError("timeout".into())));
+    }
+
+    #[test]
+    fn backoff_range() {
+        for (attempts, exp) in [
+            (0, 5),
+            (1, 5),
+            (2, 10),
+            (3, 20),
+            (7, 320),
+            (8, 600),
+            (100, 600),
+        ] {
+            let exp = Duration::from_secs(exp);
+            for _ in 0..100 {
+                let delay = backoff(attempts);
+                assert!(delay >= exp / 2 && delay <= exp, "{} {:?}", attempts, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_attempts() {
+        let mut state = PushState::default();
+        let now = 1_000_000;
+        assert!(state.is_due(now));
+        state.failed(&anyhow::anyhow!("connection reset"), now);
+        assert_eq!((state.attempts, state.permanent_failures), (1, 0));
+        assert!(!state.is_due(now));
+        assert!(state.is_due(now + BACKOFF_BASE.as_millis() as i64));
+        for _ in 0..MAX_PERMANENT_ATTEMPTS {
+            assert!(!state.should_dead_letter());
+            state.failed(&PermanentError("access denied".to_string()).into(), now);
+        }
+        assert!(state.should_dead_letter());
+        assert_eq!(
+            state.last_error.as_deref(),
+            Some("Permanent error: access denied")
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Object tagging with the write queue.
+//!
+//! The remote cannot tag objects that were not pushed yet, so the tags of a queued
+//! object are read and changed on its sidecar meta-data, and are sent with the push.
+//! Tag changes wake up the worker, which evaluates the filter again with the new tags,
+//! so tagging can hold a queued object or release it (see `is_held`).
+//! Keys that are not queued are left to the remote.
+
+use crate::object_md::{md_path, ObjectMd};
+use crate::s3::errors::S3Error;
+use crate::utils::{is_not_found, sync_dir, to_internal_err};
+use crate::write_queue::{no_such_key_deleted, WriteQueue};
+use s3d_smithy_codegen_server_s3::{
+    error::{DeleteObjectTaggingError, GetObjectTaggingError, PutObjectTaggingError},
+    input::{DeleteObjectTaggingInput, GetObjectTaggingInput, PutObjectTaggingInput},
+    model::Tag,
+    output::{DeleteObjectTaggingOutput, GetObjectTaggingOutput, PutObjectTaggingOutput},
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const MAX_TAGS: usize = 10;
+pub const MAX_TAG_KEY_LEN: usize = 128;
+pub const MAX_TAG_VALUE_LEN: usize = 256;
+
+impl WriteQueue {
+    /// get_object_tagging returns the tags of a queued object,
+    /// or None for the caller to read them from the remote.
+    pub async fn get_object_tagging(
+        &self,
+        i: &GetObjectTaggingInput,
+    ) -> Result<Option<GetObjectTaggingOutput>, GetObjectTaggingError> {
+        if i.version_id().is_some() {
+            return Ok(None);
+        }
+        let md = match self
+            .find_entry(i.bucket(), i.key())
+            .await
+            .map_err(to_internal_err)?
+        {
+            Some((md, _)) => md,
+            None => return Ok(None),
+        };
+        if md.delete_marker {
+            return Err(to_internal_err(no_such_key_deleted()));
+        }
+        Ok(Some(
+            GetObjectTaggingOutput::builder()
+                .set_tag_set(Some(to_tag_set(&md.tags)))
+                .build(),
+        ))
+    }
+
+    /// put_object_tagging replaces the tags of a queued object,
+    /// or returns None for the caller to tag the object on the remote.
+    pub async fn put_object_tagging(
+        &self,
+        i: &PutObjectTaggingInput,
+    ) -> Result<Option<PutObjectTaggingOutput>, PutObjectTaggingError> {
+        if i.version_id().is_some() {
+            return Ok(None);
+        }
+        let tags = parse_tag_set(i.tagging().tag_set()).map_err(to_internal_err)?;
+        let updated = self
+            .update_tags(i.bucket(), i.key(), tags)
+            .await
+            .map_err(to_internal_err)?;
+        Ok(updated.map(|_| PutObjectTaggingOutput::builder().build()))
+    }
+
+    /// delete_object_tagging removes the tags of a queued object,
+    /// or returns None for the caller to untag the object on the remote.
+    pub async fn delete_object_tagging(
+        &self,
+        i: &DeleteObjectTaggingInput,
+    ) -> Result<Option<DeleteObjectTaggingOutput>, DeleteObjectTaggingError> {
+        if i.version_id().is_some() {
+            return Ok(None);
+        }
+        let updated = self
+            .update_tags(i.bucket(), i.key(), BTreeMap::new())
+            .await
+            .map_err(to_internal_err)?;
+        Ok(updated.map(|_| DeleteObjectTaggingOutput::builder().build()))
+    }
+
+    /// update_tags replaces the tags on the sidecar of a queued object.
+    /// The entry is claimed first, which waits for a push that is in flight,
+    /// since it sends the old tags, and keeps the worker from pushing it meanwhile.
+    /// Returns the updated meta-data, or None if the key is not queued.
+    pub async fn update_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: BTreeMap<String, String>,
+    ) -> anyhow::Result<Option<ObjectMd>> {
+        let _claim = match self.claim_entry(bucket, key).await {
+            Some(claim) => claim,
+            None => return Ok(None),
+        };
+        let entry = self.to_entry_name(bucket, key);
+        let path = self.entry_path(&entry);
+        let md = {
+            let _guard = self.commit_lock.lock().await;
+            let stat = match tokio::fs::metadata(&path).await {
+                Ok(stat) => stat,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            // entries queued by older versions have no sidecar
+            let mut md = match ObjectMd::read(&md_path(&path)).await {
+                Ok(md) => md,
+                Err(err) if is_not_found(&err) => {
+                    let mtime: chrono::DateTime<chrono::Utc> = stat.modified()?.into();
+                    ObjectMd {
+                        bucket: bucket.to_string(),
+                        key: key.to_string(),
+                        content_length: stat.len() as i64,
+                        last_modified: mtime.timestamp_millis(),
+                        ..ObjectMd::default()
+                    }
+                }
+                Err(err) => return Err(err),
+            };
+            if md.delete_marker {
+                return Err(no_such_key_deleted().into());
+            }
+            md.tags = tags;
+            // the sidecar is staged and renamed over the old one, and a staged
+            // sidecar that is left by a crash is discarded by `recover`
+            let staged_md = md_path(&self.staging_dir().join(uuid::Uuid::new_v4().to_string()));
+            if let Err(err) = md.write(&staged_md).await {
+                tokio::fs::remove_file(&staged_md).await.ok();
+                return Err(err);
+            }
+            tokio::fs::rename(&staged_md, md_path(&path)).await?;
+            md
+        };
+        sync_dir(Path::new(&self.write_queue_dir)).await?;
+        info!("Write queue tags updated: {}/{} {:?}", bucket, key, md.tags);
+        self.wakeup.notify_one();
+        Ok(Some(md))
+    }
+}
+
+/// parse_tag_set validates a tag set by the limits of S3 object tagging.
+pub fn parse_tag_set(tag_set: &[Tag]) -> Result<BTreeMap<String, String>, S3Error> {
+    if tag_set.len() > MAX_TAGS {
+        return Err(S3Error::new(
+            "BadRequest",
+            format!("Object tags cannot be greater than {}", MAX_TAGS),
+        ));
+    }
+    let mut tags = BTreeMap::new();
+    for tag in tag_set {
+        if tag.key().is_empty() || tag.key().len() > MAX_TAG_KEY_LEN {
+            return Err(S3Error::new(
+                "InvalidTag",
+                "The TagKey you have provided is invalid",
+            ));
+        }
+        if tag.value().len() > MAX_TAG_VALUE_LEN {
+            return Err(S3Error::new(
+                "InvalidTag",
+                "The TagValue you have provided is invalid",
+            ));
+        }
+        if tags
+            .insert(tag.key().to_string(), tag.value().to_string())
+            .is_some()
+        {
+            return Err(S3Error::new(
+                "InvalidTag",
+                "Cannot provide multiple Tags with the same key",
+            ));
+        }
+    }
+    Ok(tags)
+}
+
+pub fn to_tag_set(tags: &BTreeMap<String, String>) -> Vec<Tag> {
+    tags.iter()
+        .map(|(k, v)| Tag::builder().key(k).value(v).build())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::ObjectHasher;
+    use crate::write_queue::tests::test_queue;
+    use aws_smithy_http::byte_stream::ByteStream;
+
+    fn tag(key: &str, value: &str) -> Tag {
+        Tag::builder().key(key).value(value).build()
+    }
+
+    #[test]
+    fn tag_sets() {
+        let tag_set = vec![tag("b", "2"), tag("a", "")];
+        let tags = parse_tag_set(&tag_set).unwrap();
+        assert_eq!(tags.get("a").map(String::as_str), Some(""));
+        assert_eq!(tags.get("b").map(String::as_str), Some("2"));
+        assert_eq!(to_tag_set(&tags), vec![tag("a", ""), tag("b", "2")]);
+        assert!(parse_tag_set(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tag_limits() {
+        let code = |tag_set: &[Tag]| parse_tag_set(tag_set).unwrap_err().code;
+        let many = (0..=MAX_TAGS)
+            .map(|n| tag(&n.to_string(), ""))
+            .collect::<Vec<_>>();
+        assert_eq!(code(&many), "BadRequest");
+        assert!(parse_tag_set(&many[..MAX_TAGS]).is_ok());
+        assert_eq!(code(&[tag("", "v")]), "InvalidTag");
+        let long_key = "k".repeat(MAX_TAG_KEY_LEN + 1);
+        assert_eq!(code(&[tag(&long_key, "v")]), "InvalidTag");
+        let long_value = "v".repeat(MAX_TAG_VALUE_LEN + 1);
+        assert_eq!(code(&[tag("k", &long_value)]), "InvalidTag");
+        assert_eq!(code(&[tag("k", "1"), tag("k", "2")]), "InvalidTag");
+    }
+
+    #[tokio::test]
+    async fn update_queued_tags() {
+        let queue = test_queue().await;
+        let tags = BTreeMap::from([("class".to_string(), "hot".to_string())]);
+        let updated = queue.update_tags("bucket", "key", tags.clone()).await;
+        assert!(updated.unwrap().is_none());
+        let md = ObjectMd {
+            bucket: "bucket".to_string(),
+            key: "key".to_string(),
+            ..ObjectMd::default()
+        };
+        let entry = queue.to_entry_name("bucket", "key");
+        let mut body = ByteStream::from_static(b"hello");
+        queue
+            .stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+            .await
+            .unwrap();
+        let updated = queue.update_tags("bucket", "key", tags.clone()).await;
+        assert_eq!(updated.unwrap().unwrap().tags, tags);
+        let (md, _) = queue.find_entry("bucket", "key").await.unwrap().unwrap();
+        assert_eq!(md.tags, tags);
+        assert_eq!(md.content_length, 5);
+        queue.queue_delete("bucket", "key").await.unwrap();
+        let err = queue.update_tags("bucket", "key", tags).await.unwrap_err();
+        assert!(err.to_string().contains("NoSuchKey"), "{}", err);
+    }
+}
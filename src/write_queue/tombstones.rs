@@ -0,0 +1,155 @@
+//! Deletes in the write queue.
+//!
+//! A delete is queued as a tombstone - an entry with an empty body and a sidecar
+//! marked as `delete_marker`. It is committed like any write, so it replaces
+//! (and cancels) a pending write of the same key, and it is pushed in arrival order
+//! like any other entry, by deleting the object on the remote.
+//! While the tombstone is queued, the object is not found through s3d.
+//!
+//! Tombstones do not wait for room in the queue, since a delete that would pass
+//! to the remote directly could be followed by the push of an older write.
+//!
+//! Deletes of a specific version are not queued, since a tombstone is pushed as a
+//! delete of the latest version. They are passed to the remote with their version id
+//! (and bypass-governance), and do not affect the queued write of the key, if any,
+//! which is newer than any version of the remote.
+
+use crate::checksum::ObjectHasher;
+use crate::object_md::ObjectMd;
+use crate::utils::to_internal_err;
+use crate::write_queue::WriteQueue;
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{
+    error::{DeleteObjectError, DeleteObjectsError},
+    input::{DeleteObjectInput, DeleteObjectsInput},
+    model::{DeletedObject, Error},
+    output::{DeleteObjectOutput, DeleteObjectsOutput},
+};
+
+impl WriteQueue {
+    /// queue_delete commits a tombstone for the object.
+    pub async fn queue_delete(&self, bucket: &str, key: &str) -> anyhow::Result<()> {
+        let entry = self.to_entry_name(bucket, key);
+        let md = ObjectMd::tombstone(bucket, key);
+        let mut body = ByteStream::from_static(b"");
+        self.stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+            .await?;
+        self.wakeup.notify_one();
+        info!("Write queue delete: {}/{}", bucket, key);
+        Ok(())
+    }
+
+    /// delete_object queues a tombstone, for deletes without a version id
+    /// (see `is_versioned_delete`).
+    pub async fn delete_object(
+        &self,
+        i: DeleteObjectInput,
+    ) -> Result<DeleteObjectOutput, DeleteObjectError> {
+        self.queue_delete(i.bucket(), i.key())
+            .await
+            .map_err(to_internal_err)?;
+        Ok(DeleteObjectOutput::builder().build())
+    }
+
+    /// delete_objects queues a tombstone for every key, and reports
+    /// the keys that failed in the errors of the response.
+    /// The objects with a version id should be split out (see `split_versioned_deletes`).
+    pub async fn delete_objects(
+        &self,
+        i: DeleteObjectsInput,
+    ) -> Result<DeleteObjectsOutput, DeleteObjectsError> {
+        let mut deleted = vec![];
+        let mut errors = vec![];
+        for obj in i.delete().objects() {
+            match self.queue_delete(i.bucket(), obj.key()).await {
+                Ok(()) => deleted.push(DeletedObject::builder().key(obj.key()).build()),
+                Err(err) => errors.push(
+                    Error::builder()
+                        .key(obj.key())
+                        .code("InternalError")
+                        .message(err.to_string())
+                        .build(),
+                ),
+            }
+        }
+        let quiet = i.delete().quiet();
+        Ok(DeleteObjectsOutput::builder()
+            .set_deleted(if quiet { None } else { Some(deleted) })
+            .set_errors(if errors.is_empty() {
+                None
+            } else {
+                Some(errors)
+            })
+            .build())
+    }
+}
+
+/// is_versioned_delete tells if a DeleteObject should be passed to the remote.
+pub fn is_versioned_delete(i: &DeleteObjectInput) -> bool {
+    i.version_id().is_some()
+}
+
+/// split_versioned_deletes moves the objects with a version id out of a DeleteObjects
+/// request, to a request for the remote with the same options, if there are any.
+pub fn split_versioned_deletes(i: &mut DeleteObjectsInput) -> Option<DeleteObjectsInput> {
+    let (versioned, queued): (Vec<_>, Vec<_>) = i
+        .delete
+        .objects
+        .drain(..)
+        .partition(|o| o.version_id().is_some());
+    i.delete.objects = queued;
+    if versioned.is_empty() {
+        return None;
+    }
+    let mut remote = i.clone();
+    remote.delete.objects = versioned;
+    Some(remote)
+}
+
+/// merge_delete_objects_outputs adds the results of the remote deletes of versions
+/// to the results of the queued deletes.
+pub fn merge_delete_objects_outputs(
+    queued: DeleteObjectsOutput,
+    remote: DeleteObjectsOutput,
+) -> DeleteObjectsOutput {
+    DeleteObjectsOutput::builder()
+        .set_deleted(concat(queued.deleted, remote.deleted))
+        .set_errors(concat(queued.errors, remote.errors))
+        .set_request_charged(remote.request_charged)
+        .build()
+}
+
+fn concat<T>(a: Option<Vec<T>>, b: Option<Vec<T>>) -> Option<Vec<T>> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.into_iter().chain(b).flatten().collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_queue::tests::test_queue;
+
+    #[tokio::test]
+    async fn delete_replaces_queued_write() {
+        let queue = test_queue().await;
+        let md = ObjectMd {
+            bucket: "bucket".to_string(),
+            key: "key".to_string(),
+            ..ObjectMd::default()
+        };
+        let entry = queue.to_entry_name("bucket", "key");
+        let mut body = ByteStream::from_static(b"hello");
+        queue
+            .stage_and_commit(&entry, &md, &mut body, ObjectHasher::new(&[]), None)
+            .await
+            .unwrap();
+        queue.queue_delete("bucket", "key").await.unwrap();
+        let (md, _) = queue.find_entry("bucket", "key").await.unwrap().unwrap();
+        assert!(md.delete_marker);
+        assert_eq!(md.content_length, 0);
+        let stats = queue.stats.lock().unwrap();
+        assert_eq!((stats.size, stats.files), (0, 1));
+    }
+}
@@ -0,0 +1,993 @@
+//! Multipart uploads hosted by the write queue.
+//!
+//! Multipart uploads of clients are kept in the uploads dir of the queue,
+//! one dir per upload id, with the upload meta-data (headers of the create request)
+//! and the parts as body and sidecar files, just like queue entries.
+//! Completing an upload assembles the parts and commits the object to the queue
+//! with a composite ETag (see `multipart_e_tag`), so it is pushed like any other write.
+//!
+//! Uploads in progress count in the queue limits - every upload counts as one file,
+//! and its parts count in the queue size. Creating an upload and storing a part wait
+//! for room in the queue like a put, and the room is released when the upload is
+//! removed (on complete or abort). The assembled object reserves room of its own,
+//! since the parts stay until it is committed. Uploads that are neither completed
+//! nor aborted are swept after `MAX_UPLOAD_AGE`.
+
+use crate::checksum::{multipart_e_tag, ObjectHasher, CRC32, CRC32C, SHA1, SHA256};
+use crate::object_md::{md_path, ObjectMd};
+use crate::s3::errors::S3Error;
+use crate::utils::{
+    read_files_as_stream, read_ranges_as_stream, to_internal_err, write_stream_to_file_with,
+};
+use crate::write_queue::conditions::Conditions;
+use crate::write_queue::copy::{parse_copy_source, parse_copy_source_range};
+use crate::write_queue::listing::common_prefix;
+use crate::write_queue::{no_such_key_deleted, Reservation, WriteQueue};
+use aws_smithy_http::byte_stream::ByteStream;
+use s3d_smithy_codegen_server_s3::{
+    error::{
+        AbortMultipartUploadError, CompleteMultipartUploadError, CreateMultipartUploadError,
+        ListMultipartUploadsError, ListPartsError, NoSuchUpload, UploadPartCopyError,
+        UploadPartError,
+    },
+    input::{
+        AbortMultipartUploadInput, CompleteMultipartUploadInput, CreateMultipartUploadInput,
+        ListMultipartUploadsInput, ListPartsInput, UploadPartCopyInput, UploadPartInput,
+    },
+    model::{CommonPrefix, CopyPartResult, MultipartUpload, Part, StorageClass},
+    output::{
+        AbortMultipartUploadOutput, CompleteMultipartUploadOutput, CreateMultipartUploadOutput,
+        ListMultipartUploadsOutput, ListPartsOutput, UploadPartCopyOutput, UploadPartOutput,
+    },
+};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const UPLOADS_DIR: &str = ".uploads";
+const UPLOAD_MD_NAME: &str = "upload";
+const MIN_PART_SIZE: i64 = 5 * 1024 * 1024;
+const MAX_PART_NUMBER: i32 = 10000;
+const DEFAULT_MAX_ITEMS: i32 = 1000;
+
+/// MAX_UPLOAD_AGE is the age of uploads that were never completed or aborted,
+/// after which they are swept from the queue (see `sweep_uploads`).
+pub const MAX_UPLOAD_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+impl WriteQueue {
+    pub fn uploads_dir(&self) -> PathBuf {
+        Path::new(&self.write_queue_dir).join(UPLOADS_DIR)
+    }
+
+    /// upload_dir returns None for ids that were not made by s3d,
+    /// which also keeps ids from escaping the uploads dir.
+    pub fn upload_dir(&self, upload_id: &str) -> Option<PathBuf> {
+        uuid::Uuid::parse_str(upload_id)
+            .ok()
+            .map(|_| self.uploads_dir().join(upload_id))
+    }
+
+    pub fn part_path(&self, upload_id: &str, part_number: i32) -> Option<PathBuf> {
+        self.upload_dir(upload_id)
+            .map(|dir| dir.join(format!("{:05}", part_number)))
+    }
+
+    /// has_upload checks if the upload is hosted locally, otherwise
+    /// the caller should pass the request to the remote.
+    pub async fn has_upload(&self, upload_id: &str) -> bool {
+        match self.upload_dir(upload_id) {
+            Some(dir) => tokio::fs::metadata(md_path(&dir.join(UPLOAD_MD_NAME)))
+                .await
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// read_upload returns the meta-data of an upload of the given object.
+    pub async fn read_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> anyhow::Result<ObjectMd> {
+        let no_such_upload =
+            || S3Error::new("NoSuchUpload", "The specified upload does not exist.");
+        let dir = self.upload_dir(upload_id).ok_or_else(no_such_upload)?;
+        let md = ObjectMd::read(&md_path(&dir.join(UPLOAD_MD_NAME)))
+            .await
+            .map_err(|_| no_such_upload())?;
+        if md.bucket != bucket || md.key != key {
+            return Err(no_such_upload().into());
+        }
+        Ok(md)
+    }
+
+    /// read_parts returns the meta-data of the uploaded parts by part number.
+    pub async fn read_parts(&self, upload_id: &str) -> anyhow::Result<Vec<(i32, ObjectMd)>> {
+        let mut parts = vec![];
+        let dir = match self.upload_dir(upload_id) {
+            Some(dir) => dir,
+            None => return Ok(parts),
+        };
+        let mut items = tokio::fs::read_dir(&dir).await?;
+        while let Some(item) = items.next_entry().await? {
+            let name = item.file_name().to_string_lossy().to_string();
+            if let Ok(part_number) = name.parse::<i32>() {
+                if let Ok(md) = ObjectMd::read(&md_path(&item.path())).await {
+                    parts.push((part_number, md));
+                }
+            }
+        }
+        parts.sort_by_key(|(part_number, _)| *part_number);
+        Ok(parts)
+    }
+
+    /// create_multipart_upload hosts the upload using room that was reserved
+    /// with `reserve_room` for one file.
+    pub async fn create_multipart_upload(
+        &self,
+        i: CreateMultipartUploadInput,
+        reservation: Reservation<'_>,
+    ) -> Result<CreateMultipartUploadOutput, CreateMultipartUploadError> {
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let dir = self.upload_dir(&upload_id).unwrap();
+        let md = ObjectMd::from_create_multipart_upload_input(&i);
+        async {
+            tokio::fs::create_dir_all(&dir).await?;
+            md.write(&md_path(&dir.join(UPLOAD_MD_NAME))).await
+        }
+        .await
+        .map_err(to_internal_err)?;
+        reservation.keep_for_upload(0);
+        info!(
+            "Write queue multipart upload created: {}/{} {}",
+            md.bucket, md.key, upload_id
+        );
+        Ok(CreateMultipartUploadOutput::builder()
+            .bucket(i.bucket())
+            .key(i.key())
+            .upload_id(upload_id)
+            .build())
+    }
+
+    /// upload_part stores a part of a local upload (see `store_part`).
+    pub async fn upload_part(
+        &self,
+        mut i: UploadPartInput,
+    ) -> Result<UploadPartOutput, UploadPartError> {
+        self.read_upload(i.bucket(), i.key(), i.upload_id())
+            .await
+            .map_err(to_internal_err)?;
+        check_part_number(i.part_number()).map_err(to_internal_err)?;
+        let (upload_id, part_number) = (i.upload_id().to_string(), i.part_number());
+        let part = ObjectMd {
+            bucket: i.bucket().to_string(),
+            key: i.key().to_string(),
+            content_length: i.content_length(),
+            ..ObjectMd::default()
+        };
+        let hasher = ObjectHasher::from_upload_part_input(&i);
+        let md = self
+            .store_part(&upload_id, part_number, part, &mut i.body, hasher)
+            .await
+            .map_err(to_internal_err)?;
+        Ok(UploadPartOutput::builder()
+            .set_e_tag(md.e_tag.clone())
+            .set_checksum_crc32(md.checksums.get(CRC32).cloned())
+            .set_checksum_crc32_c(md.checksums.get(CRC32C).cloned())
+            .set_checksum_sha1(md.checksums.get(SHA1).cloned())
+            .set_checksum_sha256(md.checksums.get(SHA256).cloned())
+            .build())
+    }
+
+    /// upload_part_copy stores a part that is copied from an object, or from a range
+    /// of it, where the source is read from the queue or from the remote like the
+    /// source of a local `copy_object`.
+    pub async fn upload_part_copy(
+        &self,
+        i: UploadPartCopyInput,
+    ) -> Result<UploadPartCopyOutput, UploadPartCopyError> {
+        self.read_upload(i.bucket(), i.key(), i.upload_id())
+            .await
+            .map_err(to_internal_err)?;
+        check_part_number(i.part_number()).map_err(to_internal_err)?;
+        let (src_bucket, src_key, src_version_id) =
+            parse_copy_source(i.copy_source()).map_err(to_internal_err)?;
+        let range = match i.copy_source_range() {
+            Some(header) => Some(parse_copy_source_range(header).map_err(to_internal_err)?),
+            None => None,
+        };
+
+        // a specific version of the source can only be on the remote
+        let src_queued = match src_version_id {
+            Some(_) => None,
+            None => self
+                .find_entry(&src_bucket, &src_key)
+                .await
+                .map_err(to_internal_err)?,
+        };
+        let (src_md, size, mut body) = match src_queued {
+            Some((md, file)) => {
+                if md.delete_marker {
+                    return Err(to_internal_err(no_such_key_deleted()));
+                }
+                let src_size = md.content_length.max(0) as u64;
+                let (first, len) = match range {
+                    Some((first, last)) if last < src_size => (first, last - first + 1),
+                    Some(_) => {
+                        return Err(to_internal_err(S3Error::new(
+                            "InvalidRequest",
+                            format!(
+                                "Range specified is not valid for source object of size: {}",
+                                src_size
+                            ),
+                        )))
+                    }
+                    None => (0, src_size),
+                };
+                (md, len, read_ranges_as_stream(vec![(file, first, len)]))
+            }
+            None => {
+                let (md, body) = self
+                    .get_remote_source::<UploadPartCopyError>(
+                        &src_bucket,
+                        &src_key,
+                        src_version_id.as_deref(),
+                        range.map(|(first, last)| format!("bytes={}-{}", first, last)),
+                    )
+                    .await?;
+                // the remote returns the length of the range
+                let size = md.content_length.max(0) as u64;
+                (md, size, body)
+            }
+        };
+        Conditions::from_upload_part_copy_input(&i)
+            .check_copy_source(&src_md)
+            .map_err(to_internal_err)?;
+
+        let part = ObjectMd {
+            bucket: i.bucket().to_string(),
+            key: i.key().to_string(),
+            content_length: size as i64,
+            ..ObjectMd::default()
+        };
+        let md = self
+            .store_part(
+                i.upload_id(),
+                i.part_number(),
+                part,
+                &mut body,
+                ObjectHasher::new(&[]),
+            )
+            .await
+            .map_err(to_internal_err)?;
+        info!(
+            "Write queue multipart upload part copy: {}/{} to {}/{} {} part {}",
+            src_bucket,
+            src_key,
+            md.bucket,
+            md.key,
+            i.upload_id(),
+            i.part_number()
+        );
+        Ok(UploadPartCopyOutput::builder()
+            .set_copy_source_version_id(src_version_id)
+            .copy_part_result(
+                CopyPartResult::builder()
+                    .set_e_tag(md.e_tag.clone())
+                    .last_modified(md.last_modified_time())
+                    .build(),
+            )
+            .build())
+    }
+
+    /// store_part stores a part in the upload dir, replacing a previous upload of the
+    /// same part number, using room in the queue for the size of the given part md.
+    /// The part is written to a temporary file first and renamed into place when it
+    /// is complete and verified. Returns the part md with the size and digests of the body.
+    async fn store_part(
+        &self,
+        upload_id: &str,
+        part_number: i32,
+        part: ObjectMd,
+        body: &mut ByteStream,
+        mut hasher: ObjectHasher,
+    ) -> anyhow::Result<ObjectMd> {
+        let reservation = self
+            .reserve_room(part.content_length.max(0) as u64, 0)
+            .await
+            .ok_or_else(|| S3Error::new("SlowDown", "Write queue is full"))?;
+        let part_path = self.part_path(upload_id, part_number).unwrap();
+        let tmp_path = part_path.with_file_name(format!(".tmp-{}", uuid::Uuid::new_v4()));
+        let res = async {
+            let mut size = 0;
+            let num_bytes = write_stream_to_file_with(tmp_path.to_str().unwrap(), body, |buf| {
+                size += buf.len() as u64;
+                reservation.fit(size)?;
+                hasher.update(buf);
+                Ok(())
+            })
+            .await?;
+            let sums = hasher.finish()?;
+            let md = ObjectMd {
+                content_length: num_bytes as i64,
+                last_modified: chrono::Utc::now().timestamp_millis(),
+                e_tag: Some(sums.e_tag()),
+                content_md5: Some(sums.content_md5()),
+                checksums: sums.checksums,
+                ..part
+            };
+            md.write(&md_path(&tmp_path)).await?;
+            let replaced = tokio::fs::metadata(&part_path).await.ok().map(|m| m.len());
+            tokio::fs::rename(md_path(&tmp_path), md_path(&part_path)).await?;
+            tokio::fs::rename(&tmp_path, &part_path).await?;
+            anyhow::Ok((md, replaced))
+        }
+        .await;
+        match res {
+            Ok((md, replaced)) => {
+                reservation.keep_for_upload(md.content_length as u64);
+                if let Some(replaced) = replaced {
+                    self.release_upload_room(replaced, 0);
+                }
+                Ok(md)
+            }
+            Err(err) => {
+                tokio::fs::remove_file(&tmp_path).await.ok();
+                tokio::fs::remove_file(md_path(&tmp_path)).await.ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// complete_multipart_upload assembles the listed parts into a queue entry,
+    /// and removes the upload.
+    pub async fn complete_multipart_upload(
+        &self,
+        i: CompleteMultipartUploadInput,
+    ) -> Result<CompleteMultipartUploadOutput, CompleteMultipartUploadError> {
+        let md = self.complete_upload(&i).await.map_err(to_internal_err)?;
+        Ok(CompleteMultipartUploadOutput::builder()
+            .bucket(i.bucket())
+            .key(i.key())
+            .set_e_tag(md.e_tag)
+            .build())
+    }
+
+    async fn complete_upload(&self, i: &CompleteMultipartUploadInput) -> anyhow::Result<ObjectMd> {
+        let upload_md = self.read_upload(i.bucket(), i.key(), i.upload_id()).await?;
+        let uploaded = self.read_parts(i.upload_id()).await?;
+        let requested = i
+            .multipart_upload()
+            .and_then(|u| u.parts())
+            .unwrap_or_default();
+        if requested.is_empty() {
+            return Err(S3Error::new("MalformedXML", "You must specify at least one part.").into());
+        }
+
+        let mut paths = vec![];
+        let mut part_e_tags = vec![];
+        let mut part_sizes = vec![];
+        let mut size = 0;
+        let mut last_part_number = 0;
+        for (index, part) in requested.iter().enumerate() {
+            let part_number = part.part_number();
+            if part_number <= last_part_number {
+                return Err(S3Error::new(
+                    "InvalidPartOrder",
+                    "The list of parts was not in ascending order.",
+                )
+                .into());
+            }
+            last_part_number = part_number;
+            let invalid_part = || {
+                S3Error::new(
+                    "InvalidPart",
+                    format!(
+                        "Part {} could not be found or its ETag did not match.",
+                        part_number
+                    ),
+                )
+            };
+            let (_, part_md) = uploaded
+                .iter()
+                .find(|(n, _)| *n == part_number)
+                .ok_or_else(invalid_part)?;
+            let part_e_tag = part_md.e_tag.clone().unwrap_or_default();
+            if let Some(e_tag) = part.e_tag() {
+                if e_tag.trim_matches('"') != part_e_tag.trim_matches('"') {
+                    return Err(invalid_part().into());
+                }
+            }
+            let is_last = index + 1 == requested.len();
+            if !is_last && part_md.content_length < MIN_PART_SIZE {
+                return Err(S3Error::new(
+                    "EntityTooSmall",
+                    format!(
+                        "Part {} is smaller than the minimum allowed size.",
+                        part_number
+                    ),
+                )
+                .into());
+            }
+            size += part_md.content_length.max(0) as u64;
+            part_sizes.push(part_md.content_length.max(0) as u64);
+            paths.push(self.part_path(i.upload_id(), part_number).unwrap());
+            part_e_tags.push(part_e_tag);
+        }
+
+        let md = ObjectMd {
+            content_length: size as i64,
+            part_sizes,
+            last_modified: chrono::Utc::now().timestamp_millis(),
+            e_tag: multipart_e_tag(&part_e_tags),
+            ..upload_md
+        };
+        // the parts stay in the queue until the object is committed,
+        // so the object takes room of its own while it is assembled.
+        let reservation = self
+            .reserve(size)
+            .await
+            .ok_or_else(|| S3Error::new("SlowDown", "Write queue is full"))?;
+        let entry = self.to_entry_name(i.bucket(), i.key());
+        let mut body = read_files_as_stream(paths);
+        let md = self
+            .stage_and_commit(
+                &entry,
+                &md,
+                &mut body,
+                ObjectHasher::new(&[]),
+                Some(&reservation),
+            )
+            .await?;
+        self.remove_upload(i.upload_id()).await;
+        info!(
+            "Write queue multipart upload completed: {}/{} {}",
+            md.bucket,
+            md.key,
+            i.upload_id()
+        );
+        Ok(md)
+    }
+
+    pub async fn abort_multipart_upload(
+        &self,
+        i: AbortMultipartUploadInput,
+    ) -> Result<AbortMultipartUploadOutput, AbortMultipartUploadError> {
+        if self
+            .read_upload(i.bucket(), i.key(), i.upload_id())
+            .await
+            .is_err()
+        {
+            return Err(NoSuchUpload::builder().build().into());
+        }
+        self.remove_upload(i.upload_id()).await;
+        info!(
+            "Write queue multipart upload aborted: {}/{} {}",
+            i.bucket(),
+            i.key(),
+            i.upload_id()
+        );
+        Ok(AbortMultipartUploadOutput::builder().build())
+    }
+
+    pub async fn list_parts(&self, i: ListPartsInput) -> Result<ListPartsOutput, ListPartsError> {
+        let upload_md = self
+            .read_upload(i.bucket(), i.key(), i.upload_id())
+            .await
+            .map_err(to_internal_err)?;
+        let marker = i
+            .part_number_marker()
+            .and_then(|m| m.parse::<i32>().ok())
+            .unwrap_or(0);
+        let max_parts = if i.max_parts() > 0 {
+            i.max_parts()
+        } else {
+            DEFAULT_MAX_ITEMS
+        };
+        let mut parts = self
+            .read_parts(i.upload_id())
+            .await
+            .map_err(to_internal_err)?
+            .into_iter()
+            .filter(|(part_number, _)| *part_number > marker)
+            .collect::<Vec<_>>();
+        let is_truncated = parts.len() > max_parts as usize;
+        parts.truncate(max_parts as usize);
+        let next_marker = parts.last().map(|(part_number, _)| part_number.to_string());
+        Ok(ListPartsOutput::builder()
+            .bucket(i.bucket())
+            .key(i.key())
+            .upload_id(i.upload_id())
+            .set_part_number_marker(i.part_number_marker().map(String::from))
+            .set_next_part_number_marker(if is_truncated { next_marker } else { None })
+            .max_parts(max_parts)
+            .is_truncated(is_truncated)
+            .set_storage_class(upload_md.storage_class.as_deref().map(StorageClass::from))
+            .set_parts(Some(
+                parts
+                    .iter()
+                    .map(|(part_number, md)| {
+                        Part::builder()
+                            .part_number(*part_number)
+                            .last_modified(md.last_modified_time())
+                            .set_e_tag(md.e_tag.clone())
+                            .size(md.content_length)
+                            .build()
+                    })
+                    .collect(),
+            ))
+            .build())
+    }
+
+    /// list_multipart_uploads_remote_input lists the remote from the same markers,
+    /// except that an upload id marker of a local upload is dropped, since the remote
+    /// uploads of its key were listed before it (see `merge_uploads_page`).
+    pub async fn list_multipart_uploads_remote_input(
+        &self,
+        i: &ListMultipartUploadsInput,
+    ) -> ListMultipartUploadsInput {
+        let mut remote = i.clone();
+        remote.encoding_type = None;
+        if let Some(id_marker) = i.upload_id_marker() {
+            if self.has_upload(id_marker).await {
+                remote.upload_id_marker = None;
+            }
+        }
+        remote
+    }
+
+    /// merge_list_multipart_uploads merges the uploads that are hosted locally
+    /// into a page of the uploads of the remote.
+    pub async fn merge_list_multipart_uploads(
+        &self,
+        i: &ListMultipartUploadsInput,
+        remote: ListMultipartUploadsOutput,
+    ) -> Result<ListMultipartUploadsOutput, ListMultipartUploadsError> {
+        let mut local = self
+            .scan_uploads(i.bucket())
+            .await
+            .map_err(to_internal_err)?;
+        local.sort_by(|(a_id, a), (b_id, b)| {
+            (&a.key, a.last_modified, a_id).cmp(&(&b.key, b.last_modified, b_id))
+        });
+        let max_uploads = if i.max_uploads() > 0 {
+            i.max_uploads()
+        } else {
+            DEFAULT_MAX_ITEMS
+        } as usize;
+        let query = UploadsQuery {
+            prefix: i.prefix().unwrap_or(""),
+            delimiter: i.delimiter(),
+            key_marker: i.key_marker().unwrap_or(""),
+            upload_id_marker: i.upload_id_marker(),
+            max_uploads,
+        };
+        let page = merge_uploads_page(&query, &local, &remote);
+        let (next_key_marker, next_upload_id_marker) = match page.next.clone() {
+            Some((key, upload_id)) => (Some(key), upload_id),
+            None => (None, None),
+        };
+        Ok(ListMultipartUploadsOutput::builder()
+            .bucket(i.bucket())
+            .set_prefix(i.prefix().map(String::from))
+            .set_delimiter(i.delimiter().map(String::from))
+            .set_key_marker(i.key_marker().map(String::from))
+            .set_upload_id_marker(i.upload_id_marker().map(String::from))
+            .set_next_key_marker(next_key_marker)
+            .set_next_upload_id_marker(next_upload_id_marker)
+            .max_uploads(max_uploads as i32)
+            .is_truncated(page.next.is_some())
+            .set_uploads(Some(page.uploads))
+            .set_common_prefixes(Some(
+                page.common_prefixes
+                    .into_iter()
+                    .map(|cp| CommonPrefix::builder().prefix(cp).build())
+                    .collect(),
+            ))
+            .build())
+    }
+
+    /// scan_uploads returns the ids and meta-data of the local uploads of a bucket.
+    pub async fn scan_uploads(&self, bucket: &str) -> anyhow::Result<Vec<(String, ObjectMd)>> {
+        let mut uploads = vec![];
+        let mut dirs = tokio::fs::read_dir(self.uploads_dir()).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            let upload_id = dir.file_name().to_string_lossy().to_string();
+            if let Ok(md) = ObjectMd::read(&md_path(&dir.path().join(UPLOAD_MD_NAME))).await {
+                if md.bucket == bucket {
+                    uploads.push((upload_id, md));
+                }
+            }
+        }
+        Ok(uploads)
+    }
+
+    /// remove_upload removes the upload meta-data first, so that a failure
+    /// in the middle never leaves an upload with some of its parts missing,
+    /// and releases the room of the upload in the queue.
+    pub async fn remove_upload(&self, upload_id: &str) {
+        if let Some(dir) = self.upload_dir(upload_id) {
+            let size = self.parts_size(upload_id).await;
+            if tokio::fs::remove_file(md_path(&dir.join(UPLOAD_MD_NAME)))
+                .await
+                .is_ok()
+            {
+                self.release_upload_room(size, 1);
+            }
+            if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+                warn!("Write queue remove upload {:?} failed: {}", dir, err);
+            }
+        }
+    }
+
+    /// parts_size returns the total size of the uploaded parts of an upload.
+    async fn parts_size(&self, upload_id: &str) -> u64 {
+        self.read_parts(upload_id)
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|(_, md)| md.content_length.max(0) as u64)
+            .sum()
+    }
+
+    /// release_upload_room releases the room of removed parts and uploads,
+    /// and lets the writes that wait for room check again.
+    pub fn release_upload_room(&self, size: u64, uploads: u64) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.upload_size = stats.upload_size.saturating_sub(size);
+            stats.uploads = stats.uploads.saturating_sub(uploads);
+        }
+        self.drained.notify_waiters();
+    }
+
+    /// recover_uploads removes the temporary files of parts that were being
+    /// written, and the leftovers of uploads that were being removed,
+    /// and counts the remaining uploads in the queue stats before sweeping them.
+    pub async fn recover_uploads(&self) -> anyhow::Result<()> {
+        let mut uploads = 0;
+        let mut upload_size = 0;
+        let mut dirs = tokio::fs::read_dir(self.uploads_dir()).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            if tokio::fs::metadata(md_path(&dir.path().join(UPLOAD_MD_NAME)))
+                .await
+                .is_err()
+            {
+                info!(
+                    "Write queue recover: remove upload leftovers {:?}",
+                    dir.path()
+                );
+                tokio::fs::remove_dir_all(dir.path()).await?;
+                continue;
+            }
+            let mut items = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(item) = items.next_entry().await? {
+                if item.file_name().to_string_lossy().starts_with(".tmp-") {
+                    tokio::fs::remove_file(item.path()).await?;
+                }
+            }
+            uploads += 1;
+            upload_size += self.parts_size(&dir.file_name().to_string_lossy()).await;
+        }
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.uploads = uploads;
+            stats.upload_size = upload_size;
+        }
+        self.sweep_uploads().await
+    }
+
+    /// sweep_uploads removes the uploads that are older than `MAX_UPLOAD_AGE`,
+    /// which clients abandoned without aborting them, to release their room.
+    pub async fn sweep_uploads(&self) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut dirs = tokio::fs::read_dir(self.uploads_dir()).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            let upload_id = dir.file_name().to_string_lossy().to_string();
+            if let Ok(md) = ObjectMd::read(&md_path(&dir.path().join(UPLOAD_MD_NAME))).await {
+                if now - md.last_modified > MAX_UPLOAD_AGE.as_millis() as i64 {
+                    warn!(
+                        "Write queue sweep: remove abandoned upload {}/{} {}",
+                        md.bucket, md.key, upload_id
+                    );
+                    self.remove_upload(&upload_id).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_part_number(part_number: i32) -> Result<(), S3Error> {
+    if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+        return Err(S3Error::new(
+            "InvalidArgument",
+            format!(
+                "Part number must be an integer between 1 and {}",
+                MAX_PART_NUMBER
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// UploadsQuery has the parameters of a ListMultipartUploads page.
+pub struct UploadsQuery<'a> {
+    pub prefix: &'a str,
+    pub delimiter: Option<&'a str>,
+    pub key_marker: &'a str,
+    pub upload_id_marker: Option<&'a str>,
+    pub max_uploads: usize,
+}
+
+/// UploadsPage is a merged page of ListMultipartUploads.
+#[derive(Debug, Default)]
+pub struct UploadsPage {
+    pub uploads: Vec<MultipartUpload>,
+    pub common_prefixes: Vec<String>,
+    /// The key marker and upload id marker of the next page, if truncated
+    pub next: Option<(String, Option<String>)>,
+}
+
+/// merge_uploads_page merges the local uploads (sorted by key) into a remote page.
+/// The remote uploads of a key are listed before the local ones, so a page that
+/// ends with a local upload continues on the remote after its key. Local uploads
+/// are merged only before the last key of a truncated remote page, since the next
+/// remote page may have more uploads of that key, so paging returns every upload once.
+pub fn merge_uploads_page(
+    query: &UploadsQuery,
+    local: &[(String, ObjectMd)],
+    remote: &ListMultipartUploadsOutput,
+) -> UploadsPage {
+    // items are (name, is_local, upload), where common prefixes have no upload
+    let mut items = vec![];
+    for upload in remote.uploads().unwrap_or_default() {
+        if let Some(key) = upload.key() {
+            items.push((key.to_string(), false, Some(upload.clone())));
+        }
+    }
+    for cp in remote.common_prefixes().unwrap_or_default() {
+        if let Some(prefix) = cp.prefix() {
+            items.push((prefix.to_string(), false, None));
+        }
+    }
+    let bound = match remote.is_truncated() {
+        true => remote.next_key_marker(),
+        false => None,
+    };
+    let marker_pos = query
+        .upload_id_marker
+        .and_then(|marker| local.iter().position(|(id, _)| id == marker));
+    for (index, (upload_id, md)) in local.iter().enumerate() {
+        if !md.key.starts_with(query.prefix) {
+            continue;
+        }
+        let common_prefix = common_prefix(&md.key, query.prefix, query.delimiter);
+        let name = common_prefix.as_deref().unwrap_or(&md.key);
+        let after_marker = match (name.cmp(query.key_marker), query.upload_id_marker) {
+            (Ordering::Greater, _) => true,
+            // an upload id marker of the remote comes before the local uploads of its key
+            (Ordering::Equal, Some(_)) if common_prefix.is_none() => {
+                marker_pos.map_or(true, |pos| index > pos)
+            }
+            _ => false,
+        };
+        if !after_marker || bound.map_or(false, |bound| name >= bound) {
+            continue;
+        }
+        let upload = match common_prefix {
+            Some(_) => None,
+            None => Some(
+                MultipartUpload::builder()
+                    .upload_id(upload_id)
+                    .key(&md.key)
+                    .initiated(md.last_modified_time())
+                    .set_storage_class(md.storage_class.as_deref().map(StorageClass::from))
+                    .build(),
+            ),
+        };
+        items.push((name.to_string(), true, upload));
+    }
+    // the sort is stable, which keeps the order of the uploads of a key on each side
+    items.sort_by(|(a_name, a_local, _), (b_name, b_local, _)| {
+        (a_name, a_local).cmp(&(b_name, b_local))
+    });
+    items.dedup_by(|(name, _, upload), (prev_name, _, prev_upload)| {
+        upload.is_none() && prev_upload.is_none() && name == prev_name
+    });
+
+    let mut page = UploadsPage::default();
+    for (name, _, upload) in items {
+        if page.uploads.len() + page.common_prefixes.len() >= query.max_uploads {
+            return page;
+        }
+        page.next = Some((
+            name.clone(),
+            upload
+                .as_ref()
+                .and_then(|u| u.upload_id())
+                .map(String::from),
+        ));
+        match upload {
+            Some(upload) => page.uploads.push(upload),
+            None => page.common_prefixes.push(name),
+        }
+    }
+    page.next = match remote.is_truncated() {
+        true => Some((
+            remote.next_key_marker().unwrap_or_default().to_string(),
+            remote.next_upload_id_marker().map(String::from),
+        )),
+        false => None,
+    };
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_queue::tests::test_queue;
+
+    #[tokio::test]
+    async fn upload_paths() {
+        let queue = test_queue().await;
+        let id = uuid::Uuid::new_v4().to_string();
+        let dir = queue.uploads_dir().join(&id);
+        assert_eq!(queue.upload_dir(&id), Some(dir.clone()));
+        assert_eq!(queue.part_path(&id, 7), Some(dir.join("00007")));
+        for bad in ["", "upload", "..", "../.journal"] {
+            assert_eq!(queue.upload_dir(bad), None, "{:?}", bad);
+        }
+        assert!(!queue.has_upload(&id).await);
+    }
+
+    #[test]
+    fn part_numbers() {
+        for part_number in [1, 10000] {
+            assert!(check_part_number(part_number).is_ok(), "{}", part_number);
+        }
+        for part_number in [-1, 0, 10001] {
+            let err = check_part_number(part_number).unwrap_err();
+            assert_eq!(err.code, "InvalidArgument");
+        }
+    }
+
+    #[tokio::test]
+    async fn recover_uploads_removes_leftovers() {
+        let queue = test_queue().await;
+        let id = uuid::Uuid::new_v4().to_string();
+        let dir = queue.upload_dir(&id).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        ObjectMd::default()
+            .write(&md_path(&dir.join(UPLOAD_MD_NAME)))
+            .await
+            .unwrap();
+        std::fs::write(dir.join("00001"), b"part").unwrap();
+        ObjectMd {
+            content_length: 4,
+            ..ObjectMd::default()
+        }
+        .write(&md_path(&dir.join("00001")))
+        .await
+        .unwrap();
+        std::fs::write(dir.join(".tmp-00002"), b"cut").unwrap();
+        // an upload that was being removed has no upload meta-data
+        let removed = queue.upload_dir(&uuid::Uuid::new_v4().to_string()).unwrap();
+        std::fs::create_dir_all(&removed).unwrap();
+        std::fs::write(removed.join("00001"), b"part").unwrap();
+
+        queue.recover_uploads().await.unwrap();
+        assert!(queue.has_upload(&id).await);
+        assert!(dir.join("00001").exists());
+        assert!(!dir.join(".tmp-00002").exists());
+        assert!(!removed.exists());
+        let stats = queue.stats.lock().unwrap();
+        assert_eq!((stats.uploads, stats.upload_size), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn sweep_uploads_removes_abandoned() {
+        let queue = test_queue().await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let max_age = MAX_UPLOAD_AGE.as_millis() as i64;
+        let mut ids = vec![];
+        for last_modified in [now - max_age - 1000, now - max_age + 60_000] {
+            let id = uuid::Uuid::new_v4().to_string();
+            let dir = queue.upload_dir(&id).unwrap();
+            std::fs::create_dir_all(&dir).unwrap();
+            ObjectMd {
+                last_modified,
+                ..ObjectMd::default()
+            }
+            .write(&md_path(&dir.join(UPLOAD_MD_NAME)))
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        queue.stats.lock().unwrap().uploads = 2;
+
+        queue.sweep_uploads().await.unwrap();
+        assert!(!queue.has_upload(&ids[0]).await);
+        assert!(!queue.upload_dir(&ids[0]).unwrap().exists());
+        assert!(queue.has_upload(&ids[1]).await);
+        assert_eq!(queue.stats.lock().unwrap().uploads, 1);
+    }
+
+    fn upload(key: &str, upload_id: &str) -> MultipartUpload {
+        MultipartUpload::builder()
+            .key(key)
+            .upload_id(upload_id)
+            .build()
+    }
+
+    fn query<'a>(key_marker: &'a str, upload_id_marker: Option<&'a str>) -> UploadsQuery<'a> {
+        UploadsQuery {
+            prefix: "",
+            delimiter: None,
+            key_marker,
+            upload_id_marker,
+            max_uploads: 3,
+        }
+    }
+
+    fn listed(page: &UploadsPage) -> Vec<(&str, &str)> {
+        page.uploads
+            .iter()
+            .map(|u| (u.key().unwrap(), u.upload_id().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn merged_uploads_pages() {
+        let local: Vec<(String, ObjectMd)> = [("b", "l1"), ("b", "l2"), ("d", "l3")]
+            .iter()
+            .map(|(key, id)| {
+                let md = ObjectMd {
+                    key: key.to_string(),
+                    ..Default::default()
+                };
+                (id.to_string(), md)
+            })
+            .collect();
+        let remote = ListMultipartUploadsOutput::builder()
+            .uploads(upload("a", "r1"))
+            .uploads(upload("b", "r2"))
+            .uploads(upload("c", "r3"))
+            .build();
+
+        // the remote uploads of a key come before the local ones
+        let page = merge_uploads_page(&query("", None), &local, &remote);
+        assert_eq!(listed(&page), [("a", "r1"), ("b", "r2"), ("b", "l1")]);
+        assert_eq!(page.next, Some(("b".to_string(), Some("l1".to_string()))));
+
+        // a local marker continues after it, and the remote after its key
+        let remote_after_b = ListMultipartUploadsOutput::builder()
+            .uploads(upload("c", "r3"))
+            .build();
+        let page = merge_uploads_page(&query("b", Some("l1")), &local, &remote_after_b);
+        assert_eq!(listed(&page), [("b", "l2"), ("c", "r3"), ("d", "l3")]);
+        assert_eq!(page.next, None);
+
+        // a remote marker continues with every local upload of its key
+        let remote_after_r1 = ListMultipartUploadsOutput::builder()
+            .uploads(upload("b", "r2"))
+            .uploads(upload("c", "r3"))
+            .build();
+        let page = merge_uploads_page(&query("a", Some("r1")), &local, &remote_after_r1);
+        assert_eq!(listed(&page), [("b", "r2"), ("b", "l1"), ("b", "l2")]);
+        assert_eq!(page.next, Some(("b".to_string(), Some("l2".to_string()))));
+
+        // local uploads at or after the next key of a truncated remote wait for its next page
+        let truncated = ListMultipartUploadsOutput::builder()
+            .uploads(upload("a", "r1"))
+            .uploads(upload("b", "r2"))
+            .is_truncated(true)
+            .next_key_marker("b")
+            .next_upload_id_marker("r2")
+            .build();
+        let page = merge_uploads_page(&query("", None), &local, &truncated);
+        assert_eq!(listed(&page), [("a", "r1"), ("b", "r2")]);
+        assert_eq!(page.next, Some(("b".to_string(), Some("r2".to_string()))));
+    }
+}